@@ -0,0 +1,100 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+// A scan can be stopped part way through by cancelling the token in its
+// config (`Config::cancel`), from any thread.  Every scanner finishes the
+// chunk it is reading and then stops rather than start another, so that the
+// checkpoint, if any, records exactly what was scanned and the scan can be
+// resumed from there.  The shards that were cut short are reported with a
+// status of `ShardStatus::Cancelled`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::SharkspotterError;
+
+// How often a sleeping scanner checks whether it has been cancelled.
+const SLEEP_STEP: Duration = Duration::from_millis(100);
+
+/// Shared by every clone of a config, so cancelling any one of them cancels
+/// the scan.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// `SharkspotterError::Cancelled` once the token has been cancelled.
+    pub fn check(&self) -> Result<(), SharkspotterError> {
+        if self.is_cancelled() {
+            return Err(SharkspotterError::Cancelled);
+        }
+
+        Ok(())
+    }
+
+    /// Sleep for `duration`, or until the token is cancelled if that is
+    /// sooner.
+    pub fn sleep(&self, duration: Duration) {
+        let end = Instant::now() + duration;
+
+        loop {
+            let now = Instant::now();
+            if now >= end || self.is_cancelled() {
+                return;
+            }
+
+            thread::sleep((end - now).min(SLEEP_STEP));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel_token_test() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(token.check().is_ok());
+
+        let start = Instant::now();
+        token.sleep(Duration::from_millis(10));
+        assert!(start.elapsed() >= Duration::from_millis(10));
+
+        clone.cancel();
+        assert!(token.is_cancelled());
+
+        // A cancelled token doesn't keep anyone waiting.
+        let start = Instant::now();
+        token.sleep(Duration::from_secs(60));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        match token.check() {
+            Err(SharkspotterError::Cancelled) => (),
+            ret => panic!("unexpected result {:?}", ret),
+        }
+    }
+}
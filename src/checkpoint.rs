@@ -0,0 +1,445 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+// A checkpoint records, for each shard and each id column, the last id that
+// has been completely scanned and handed to the caller.  It is persisted to a
+// state file as json of the form:
+// {
+//   "shards": {
+//     "1": { "_id": 1000999, "_idx": 42999 },
+//     "2": { "_id": 87999 }
+//   }
+// }
+//
+// When a shard's id columns are split into separately scanned parts (see
+// `Config::shard_splits`) each part is recorded under "<index>#<part>", and
+// the first id of each part is kept under "splits" so that a resumed scan
+// splits the range in the same places:
+// {
+//   "shards": { "1": { "_id#0": 24999, "_id#1": 51999 } },
+//   "splits": { "1": { "_id": [0, 50000] } }
+// }
+//
+// A multithreaded scan breaks each index into units of work (see
+// `scheduler`), which are recorded under "<index>@<first id of the unit>".
+//
+// When resuming, each shard/index pair restarts at the id following the one
+// recorded.  Anything scanned after the last persisted checkpoint is scanned
+// again, so a resumed scan may emit some objects a second time, but it will
+// never miss one.
+//
+// That only holds if every match handed to the caller before a checkpoint is
+// saved has been written out by then.  A caller that reads matches from a
+// channel, or writes them through a buffer, enables the `OutputAck` in its
+// config and tells it how many it has written.  Each update is then held
+// back until the caller has written every match handed over before it.
+
+use serde::{Deserialize, Serialize};
+use slog::{debug, Logger};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::error::SharkspotterError;
+use crate::source::IdRange;
+
+// Minimum time between writes of the state file.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct CheckpointState {
+    pub shards: BTreeMap<u32, BTreeMap<String, u64>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub splits: BTreeMap<u32, BTreeMap<String, Vec<u64>>>,
+}
+
+/// Counts the matches that have been handed to the caller, and how many of
+/// them the caller has written out.  Shared by every clone of a config
+/// (see `Config::output_ack`).
+#[derive(Debug, Clone, Default)]
+pub struct OutputAck {
+    enabled: Arc<AtomicBool>,
+    handed_over: Arc<AtomicU64>,
+    written: Arc<AtomicU64>,
+}
+
+impl OutputAck {
+    /// Only move the checkpoint past a match once `written` has been called
+    /// for it.  This must be done before the scan starts.
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Called for every match as it is handed to the caller.  The count goes
+    /// up before the match is sent, so a match is never sent ahead of its
+    /// count.
+    pub(crate) fn handed_over(&self) {
+        self.handed_over.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Called by the caller once the first `count` matches it was given have
+    /// been written out, in the order they were received.
+    pub fn written(&self, count: u64) {
+        self.written.store(count, Ordering::SeqCst);
+    }
+}
+
+/// An update that is waiting for the caller to write out the matches that
+/// were handed over before it.
+struct PendingUpdate {
+    handed_over: u64,
+    shard: u32,
+    id_name: String,
+    last_id: u64,
+}
+
+struct CheckpointInner {
+    path: PathBuf,
+    state: CheckpointState,
+    last_write: Instant,
+    ack: OutputAck,
+    pending: Vec<PendingUpdate>,
+}
+
+impl CheckpointInner {
+    fn record(&mut self, shard: u32, id_name: String, last_id: u64) {
+        self.state
+            .shards
+            .entry(shard)
+            .or_default()
+            .insert(id_name, last_id);
+    }
+
+    /// Record the pending updates whose matches have all been written out.
+    fn record_written(&mut self) {
+        let written = self.ack.written.load(Ordering::SeqCst);
+        let done = self
+            .pending
+            .iter()
+            .take_while(|update| update.handed_over <= written)
+            .count();
+
+        for update in self.pending.drain(..done).collect::<Vec<_>>() {
+            self.record(update.shard, update.id_name, update.last_id);
+        }
+    }
+}
+
+/// A handle to the scan checkpoint shared by all of the shard scanners of a
+/// single run.  Cloning the handle is cheap.  If checkpointing is not enabled
+/// in the config then every method is a no-op.
+#[derive(Clone, Default)]
+pub struct Checkpoint {
+    inner: Option<Arc<Mutex<CheckpointInner>>>,
+}
+
+impl CheckpointState {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(|e| {
+            let msg = format!("Could not parse checkpoint '{}': {}", path, e);
+            Error::new(ErrorKind::InvalidData, msg)
+        })
+    }
+
+    /// Write the state to a temporary file and then rename it into place so
+    /// that a crash mid-write does not leave behind a truncated checkpoint.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let contents = serde_json::to_vec(self)?;
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+impl Checkpoint {
+    /// Create a checkpoint for this run.  If the config specifies a resume
+    /// then the existing state file is loaded, otherwise we start from an
+    /// empty state.
+    pub fn from_config(conf: &Config) -> Result<Self, Error> {
+        let path = match &conf.checkpoint_file {
+            Some(p) => p,
+            None => return Ok(Checkpoint::default()),
+        };
+
+        let state = if conf.resume {
+            CheckpointState::load(path)?
+        } else {
+            CheckpointState::default()
+        };
+
+        let inner = CheckpointInner {
+            path: PathBuf::from(path),
+            state,
+            last_write: Instant::now(),
+            ack: conf.output_ack.clone(),
+            pending: vec![],
+        };
+
+        Ok(Checkpoint {
+            inner: Some(Arc::new(Mutex::new(inner))),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    /// The last id that was completely scanned for this shard and id column,
+    /// if any.
+    pub fn resume_point(&self, shard: u32, id_name: &str) -> Option<u64> {
+        let inner = self.inner.as_ref()?;
+        let inner = inner.lock().expect("checkpoint lock");
+
+        inner
+            .state
+            .shards
+            .get(&shard)
+            .and_then(|ids| ids.get(id_name))
+            .copied()
+    }
+
+    /// The first id of each split of this shard's id column, if the column
+    /// was split in an earlier run.
+    pub fn split_points(&self, shard: u32, id_name: &str) -> Option<Vec<u64>> {
+        let inner = self.inner.as_ref()?;
+        let inner = inner.lock().expect("checkpoint lock");
+
+        inner
+            .state
+            .splits
+            .get(&shard)
+            .and_then(|ids| ids.get(id_name))
+            .cloned()
+    }
+
+    /// Record where this shard's id column has been split.  These are saved
+    /// along with the next update.
+    pub fn set_split_points(&self, shard: u32, id_name: &str, points: &[u64]) {
+        let inner = match &self.inner {
+            Some(i) => i,
+            None => return,
+        };
+        let mut inner = inner.lock().expect("checkpoint lock");
+
+        inner
+            .state
+            .splits
+            .entry(shard)
+            .or_insert_with(BTreeMap::new)
+            .entry(id_name.to_string())
+            .or_insert_with(|| points.to_vec());
+    }
+
+    /// The first id of each of `parts` splits of this shard's id column
+    /// `range`.  A resumed scan uses the split points of the original run,
+    /// which are recorded the first time the column is split.
+    pub fn split_range(
+        &self,
+        shard: u32,
+        id_name: &str,
+        range: IdRange,
+        parts: u32,
+    ) -> Result<Vec<u64>, SharkspotterError> {
+        let points = match self.split_points(shard, id_name) {
+            Some(points) => points,
+            None => {
+                let points = range.split_points(parts);
+                self.set_split_points(shard, id_name, &points);
+                points
+            }
+        };
+
+        if points.len() != parts as usize {
+            return Err(SharkspotterError::InvalidConfig(format!(
+                "The checkpoint for shard {} {} has {} splits, not {}",
+                shard,
+                id_name,
+                points.len(),
+                parts
+            )));
+        }
+
+        Ok(points)
+    }
+
+    /// Record that every id up to and including `last_id` has been scanned,
+    /// and its matches handed to the caller.  If the `OutputAck` is enabled
+    /// this isn't saved until the caller has written those matches out.  The
+    /// state file is only rewritten if CHECKPOINT_INTERVAL has passed since
+    /// the last time it was written.
+    pub fn update(
+        &self,
+        shard: u32,
+        id_name: &str,
+        last_id: u64,
+        log: &Logger,
+    ) -> Result<(), Error> {
+        let inner = match &self.inner {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        let mut inner = inner.lock().expect("checkpoint lock");
+
+        // The count is read under the lock, so the pending updates are in
+        // the order of their counts.
+        if inner.ack.is_enabled() {
+            let handed_over = inner.ack.handed_over.load(Ordering::SeqCst);
+            inner.pending.push(PendingUpdate {
+                handed_over,
+                shard,
+                id_name: id_name.to_string(),
+                last_id,
+            });
+        } else {
+            inner.record(shard, id_name.to_string(), last_id);
+        }
+
+        if inner.last_write.elapsed() < CHECKPOINT_INTERVAL {
+            return Ok(());
+        }
+
+        debug!(log, "writing checkpoint";
+            "path" => inner.path.display().to_string(),
+            "shard" => shard,
+            "index" => id_name,
+            "last_id" => last_id
+        );
+
+        inner.record_written();
+        inner.state.save(&inner.path)?;
+        inner.last_write = Instant::now();
+
+        Ok(())
+    }
+
+    /// Unconditionally write the current state to the state file.
+    pub fn flush(&self) -> Result<(), Error> {
+        let inner = match &self.inner {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        let mut inner = inner.lock().expect("checkpoint lock");
+
+        inner.record_written();
+        inner.state.save(&inner.path)?;
+        inner.last_write = Instant::now();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_roundtrip() {
+        let _guard = crate::util::init_global_logger(None);
+        let log = slog_scope::logger();
+
+        let path = std::env::temp_dir().join(format!(
+            "sharkspotter_checkpoint_{}.json",
+            std::process::id()
+        ));
+        let path_str = path.to_str().expect("path").to_string();
+
+        let mut conf = Config {
+            checkpoint_file: Some(path_str),
+            ..Default::default()
+        };
+
+        let checkpoint = Checkpoint::from_config(&conf).expect("checkpoint");
+        assert!(checkpoint.is_enabled());
+        assert_eq!(checkpoint.resume_point(1, "_id"), None);
+
+        checkpoint.update(1, "_id", 999, &log).expect("update");
+        checkpoint.update(1, "_idx", 42, &log).expect("update");
+        checkpoint.update(2, "_id", 1999, &log).expect("update");
+        checkpoint.update(1, "_id", 1999, &log).expect("update");
+        checkpoint.set_split_points(3, "_idx", &[0, 500]);
+        checkpoint.update(3, "_idx#1", 700, &log).expect("update");
+        checkpoint.flush().expect("flush");
+
+        conf.resume = true;
+        let resumed = Checkpoint::from_config(&conf).expect("resume");
+        assert_eq!(resumed.resume_point(1, "_id"), Some(1999));
+        assert_eq!(resumed.resume_point(1, "_idx"), Some(42));
+        assert_eq!(resumed.resume_point(2, "_id"), Some(1999));
+        assert_eq!(resumed.resume_point(2, "_idx"), None);
+        assert_eq!(resumed.split_points(3, "_idx"), Some(vec![0, 500]));
+        assert_eq!(resumed.resume_point(3, "_idx#1"), Some(700));
+        assert_eq!(resumed.resume_point(3, "_idx#0"), None);
+
+        fs::remove_file(&path).expect("remove checkpoint");
+
+        // No checkpoint file configured means no checkpointing.
+        let disabled =
+            Checkpoint::from_config(&Config::default()).expect("disabled");
+        assert!(!disabled.is_enabled());
+        disabled.update(1, "_id", 1, &log).expect("noop update");
+        assert_eq!(disabled.resume_point(1, "_id"), None);
+    }
+
+    #[test]
+    fn checkpoint_ack_test() {
+        let _guard = crate::util::init_global_logger(None);
+        let log = slog_scope::logger();
+
+        let path = std::env::temp_dir().join(format!(
+            "sharkspotter_checkpoint_ack_{}.json",
+            std::process::id()
+        ));
+        let conf = Config {
+            checkpoint_file: Some(path.to_str().expect("path").to_string()),
+            ..Default::default()
+        };
+        conf.output_ack.enable();
+        let checkpoint = Checkpoint::from_config(&conf).expect("checkpoint");
+        let saved = || {
+            let state = CheckpointState::load(path.to_str().unwrap())
+                .expect("load checkpoint");
+            state.shards.get(&1).and_then(|ids| ids.get("_id")).copied()
+        };
+
+        // Two chunks, each with two matches, handed to the caller.
+        for last_id in [99, 199].iter() {
+            conf.output_ack.handed_over();
+            conf.output_ack.handed_over();
+            checkpoint.update(1, "_id", *last_id, &log).expect("update");
+        }
+
+        checkpoint.flush().expect("flush");
+        assert_eq!(saved(), None);
+
+        // The first chunk's matches are written, but only one of the
+        // second's.
+        conf.output_ack.written(3);
+        checkpoint.flush().expect("flush");
+        assert_eq!(saved(), Some(99));
+
+        conf.output_ack.written(4);
+        checkpoint.flush().expect("flush");
+        assert_eq!(saved(), Some(199));
+
+        fs::remove_file(&path).expect("remove checkpoint");
+    }
+}
@@ -0,0 +1,149 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+// A moray scan reads an id column a range of ids at a time.  How many rows a
+// range of a given width holds depends on how densely packed the ids are,
+// which varies a lot from shard to shard and from one part of a shard to the
+// next.  When the chunk size is allowed to vary (`min_chunk_size` is less
+// than `max_chunk_size`) the width of each chunk is picked so that it should
+// hold around `chunk_size` rows, based on how many rows the last chunk had.
+// It never more than doubles or halves from one chunk to the next, and is
+// halved whenever a chunk query takes more than a quarter of the sql
+// timeout.  A chunk whose query times out altogether is read again at half
+// the width.  Only the time moray takes to answer is counted, not the time
+// it takes the caller to deal with the rows.
+
+use std::time::Duration;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone)]
+pub struct ChunkSizer {
+    size: u64,
+    min: u64,
+    max: u64,
+    target_rows: u64,
+    max_latency: Duration,
+}
+
+impl ChunkSizer {
+    pub fn new(conf: &Config) -> Self {
+        // A bound of 0 means the chunk size can't vary in that direction.
+        let bound = |b| if b == 0 { conf.chunk_size } else { b };
+        let min = bound(conf.min_chunk_size);
+        let max = bound(conf.max_chunk_size);
+
+        ChunkSizer {
+            size: conf.chunk_size.max(min).min(max),
+            min,
+            max,
+            target_rows: conf.chunk_size,
+            max_latency: Duration::from_millis(conf.sql_timeout_ms / 4),
+        }
+    }
+
+    /// The number of ids the next chunk should cover.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Work out the size of the next chunk after one that covered `width`
+    /// ids and returned `rows` rows in `latency`.
+    pub fn update(&mut self, width: u64, rows: u64, latency: Duration) {
+        if self.min >= self.max || width == 0 {
+            return;
+        }
+
+        let mut size = if rows == 0 {
+            width.saturating_mul(2)
+        } else {
+            (width as f64 * self.target_rows as f64 / rows as f64) as u64
+        };
+
+        size = size.min(width.saturating_mul(2)).max(width / 2);
+
+        if latency > self.max_latency {
+            size = size.min(width / 2);
+        }
+
+        self.size = size.max(self.min).min(self.max);
+    }
+
+    /// Halve the chunk size after a query over `width` ids timed out.
+    /// Returns false, and leaves the size as it is, if the chunk size is
+    /// fixed or can't get any smaller than `width`.
+    pub fn timed_out(&mut self, width: u64) -> bool {
+        let size = (width / 2).max(self.min);
+        if self.min >= self.max || size >= width {
+            return false;
+        }
+
+        self.size = size;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_sizer_test() {
+        let fast = Duration::from_millis(10);
+        let conf = Config {
+            chunk_size: 100,
+            min_chunk_size: 50,
+            max_chunk_size: 1000,
+            sql_timeout_ms: 4000,
+            ..Default::default()
+        };
+        let mut sizer = ChunkSizer::new(&conf);
+        assert_eq!(sizer.size(), 100);
+
+        // Sparse ids widen the chunks, but only by double at a time.
+        sizer.update(100, 10, fast);
+        assert_eq!(sizer.size(), 200);
+        sizer.update(200, 40, fast);
+        assert_eq!(sizer.size(), 400);
+        sizer.update(400, 0, fast);
+        assert_eq!(sizer.size(), 800);
+        sizer.update(800, 0, fast);
+        assert_eq!(sizer.size(), 1000);
+
+        // Dense ids narrow them.
+        sizer.update(1000, 125, fast);
+        assert_eq!(sizer.size(), 800);
+
+        // As does a slow query, even if the chunk was sparse.
+        sizer.update(800, 10, Duration::from_secs(2));
+        assert_eq!(sizer.size(), 400);
+        sizer.update(400, 10, Duration::from_secs(2));
+        sizer.update(200, 10, Duration::from_secs(2));
+        assert_eq!(sizer.size(), 100);
+        sizer.update(100, 10, Duration::from_secs(2));
+        assert_eq!(sizer.size(), 50);
+
+        // A query that times out halves whatever part of the chunk it was
+        // over, but only down to the minimum.
+        sizer.update(50, 0, fast);
+        assert!(sizer.timed_out(80));
+        assert_eq!(sizer.size(), 50);
+        assert!(!sizer.timed_out(50));
+        assert!(sizer.timed_out(300));
+        assert_eq!(sizer.size(), 150);
+
+        // A fixed chunk size by default.
+        let conf = Config::default();
+        let mut sizer = ChunkSizer::new(&conf);
+        sizer.update(conf.chunk_size, 0, fast);
+        assert_eq!(sizer.size(), conf.chunk_size);
+        assert!(!sizer.timed_out(conf.chunk_size));
+    }
+}
@@ -9,12 +9,329 @@
  */
 
 use clap::{value_t, App, AppSettings, Arg, ArgMatches};
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
 use slog::Level;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
 use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 
+use crate::cancel::CancelToken;
+use crate::checkpoint::OutputAck;
+
 const MAX_THREADS: usize = 100;
 
+/// Fill in the "{shard}" and "{domain}" placeholders of a host template.
+pub fn expand_host_template(
+    template: &str,
+    shard: u32,
+    domain: &str,
+) -> String {
+    template
+        .replace("{shard}", &shard.to_string())
+        .replace("{domain}", domain)
+}
+
+/// Where to find each shard's moray.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MorayConfig {
+    /// Host name template, see `expand_host_template`.
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for MorayConfig {
+    fn default() -> Self {
+        Self {
+            host: String::from("{shard}.moray.{domain}"),
+            port: 2020,
+        }
+    }
+}
+
+impl MorayConfig {
+    pub fn host(&self, shard: u32, domain: &str) -> String {
+        expand_host_template(&self.host, shard, domain)
+    }
+}
+
+/// Whether and how to use TLS for direct-DB connections.  These are the same
+/// as libpq's `sslmode`s, apart from "allow".
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    Disable,
+
+    /// Use TLS if the server supports it, without checking its certificate.
+    Prefer,
+
+    /// Always use TLS, without checking the server's certificate.
+    Require,
+
+    /// Always use TLS, and check that the server's certificate is signed by
+    /// a trusted certificate authority.
+    VerifyCa,
+
+    /// Same as `VerifyCa`, and also check that the certificate is for the
+    /// shard's host name.
+    VerifyFull,
+}
+
+impl FromStr for SslMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disable" => Ok(SslMode::Disable),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-ca" => Ok(SslMode::VerifyCa),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            _ => Err(format!("unknown sslmode '{}'", s)),
+        }
+    }
+}
+
+/// Where to find each shard's database for direct-DB scans, and how to log
+/// in to it.
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostgresConfig {
+    /// A libpq-style connection string or postgresql:// URL, in which
+    /// "{shard}" and "{domain}" are filled in as for `host`.  Any connection
+    /// settings it has, including libpq's `sslmode`, `sslrootcert` and
+    /// `passfile`, are used instead of the ones below.
+    pub connection_string: Option<String>,
+
+    /// Host name template, see `expand_host_template`.  A path (starting
+    /// with "/") is taken as the directory of the server's Unix socket
+    /// instead.
+    pub host: String,
+    pub port: u16,
+    pub dbname: String,
+    pub user: String,
+
+    /// The password to log in with.  If there isn't one it is looked up in
+    /// `passfile`.
+    pub password: Option<String>,
+
+    /// The libpq password file to use instead of $PGPASSFILE or ~/.pgpass
+    /// (see `pgpass`).
+    pub passfile: Option<String>,
+
+    pub ssl_mode: SslMode,
+
+    /// A file of certificate authorities to trust instead of the system's.
+    pub ssl_root_cert: Option<String>,
+
+    /// Limits on how long the scan of a shard, which is a single query, and
+    /// the wait for the table's lock can take.  0 means no limit.
+    pub statement_timeout_ms: u64,
+    pub lock_timeout_ms: u64,
+
+    /// The name that the scan's connections show up as in pg_stat_activity.
+    pub application_name: String,
+}
+
+impl Default for PostgresConfig {
+    fn default() -> Self {
+        Self {
+            connection_string: None,
+            host: String::from("{shard}.rebalancer-postgres.{domain}"),
+            port: 5432,
+            dbname: String::from("moray"),
+            user: String::from("postgres"),
+            password: None,
+            passfile: None,
+            ssl_mode: SslMode::Disable,
+            ssl_root_cert: None,
+            statement_timeout_ms: 0,
+            lock_timeout_ms: 0,
+            application_name: String::from("sharkspotter"),
+        }
+    }
+}
+
+// Leave the password out of logs and error messages.
+impl fmt::Debug for PostgresConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PostgresConfig")
+            .field("connection_string", &self.connection_string)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("dbname", &self.dbname)
+            .field("user", &self.user)
+            .field("password", &self.password.as_ref().map(|_| "<hidden>"))
+            .field("passfile", &self.passfile)
+            .field("ssl_mode", &self.ssl_mode)
+            .field("ssl_root_cert", &self.ssl_root_cert)
+            .field("statement_timeout_ms", &self.statement_timeout_ms)
+            .field("lock_timeout_ms", &self.lock_timeout_ms)
+            .field("application_name", &self.application_name)
+            .finish()
+    }
+}
+
+impl PostgresConfig {
+    pub fn host(&self, shard: u32, domain: &str) -> String {
+        expand_host_template(&self.host, shard, domain)
+    }
+}
+
+/// Limits on how hard a scan works each shard (see `throttle`).  A limit of
+/// 0 means no limit.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// Rows read per second, across all shards.
+    pub rows_per_sec: u64,
+
+    /// Chunks read per second, across all shards.
+    pub chunks_per_sec: u64,
+
+    /// Rows read per second from each shard.
+    pub shard_rows_per_sec: u64,
+
+    /// Chunks read per second from each shard.
+    pub shard_chunks_per_sec: u64,
+
+    /// Time to wait after each chunk.
+    pub chunk_delay_ms: u64,
+
+    /// Chunk queries that take longer than this are taken as a sign that the
+    /// shard is busy, and the scan of it backs off.
+    pub slow_query_ms: u64,
+}
+
+/// The port that nameservers listen on unless another one is given.
+pub const DNS_PORT: u16 = 53;
+
+/// How host names are turned into addresses.  Hosts in the static map are
+/// never looked up.  Everything else is looked up using the given
+/// nameservers, or the system's resolv.conf if there are none.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    pub hosts: BTreeMap<String, IpAddr>,
+
+    /// See `parse_nameserver`.
+    #[serde(deserialize_with = "deserialize_nameservers")]
+    pub nameservers: Vec<SocketAddr>,
+}
+
+impl DnsConfig {
+    /// True if lookups are left entirely to the system resolver.
+    pub fn is_system(&self) -> bool {
+        self.hosts.is_empty() && self.nameservers.is_empty()
+    }
+}
+
+/// Parse the address of a nameserver, which is an IP address with an optional
+/// port, e.g. "10.0.0.53", "10.0.0.53:5353" or "[::1]:5353".  The port
+/// defaults to `DNS_PORT`.
+pub fn parse_nameserver(addr: &str) -> Result<SocketAddr, String> {
+    if let Ok(socket) = addr.parse::<SocketAddr>() {
+        return Ok(socket);
+    }
+
+    addr.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .map_err(|_| format!("could not parse '{}' as an address", addr))
+}
+
+fn deserialize_nameservers<'de, D>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|addr| parse_nameserver(addr).map_err(de::Error::custom))
+        .collect()
+}
+
+/// Parse a host map in the same format as /etc/hosts: an address followed
+/// by one or more host names on each line, with '#' starting a comment.
+pub fn parse_host_map(
+    contents: &str,
+) -> Result<BTreeMap<String, IpAddr>, String> {
+    let mut hosts = BTreeMap::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut fields = line.split_whitespace();
+
+        let addr = match fields.next() {
+            Some(a) => a,
+            None => continue,
+        };
+        let ip: IpAddr = addr.parse().map_err(|e| {
+            format!(
+                "line {}: could not parse '{}' as an address: {}",
+                i + 1,
+                addr,
+                e
+            )
+        })?;
+
+        let mut names = fields.peekable();
+        if names.peek().is_none() {
+            return Err(format!("line {}: no host names for {}", i + 1, addr));
+        }
+
+        for name in names {
+            hosts.insert(name.to_string(), ip);
+        }
+    }
+
+    Ok(hosts)
+}
+
+fn load_host_map(path: &str) -> Result<BTreeMap<String, IpAddr>, Error> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        let msg = format!("Could not read host map '{}': {}", path, e);
+        Error::new(e.kind(), msg)
+    })?;
+
+    parse_host_map(&contents).map_err(|e| {
+        let msg = format!("Could not parse host map '{}': {}", path, e);
+        Error::new(ErrorKind::InvalidData, msg)
+    })
+}
+
+/// The settings that can be given in a config file (see `--config`).  Any
+/// that are also given on the command line are overridden by it.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub moray: MorayConfig,
+    pub postgres: PostgresConfig,
+    pub dns: DnsConfig,
+    pub throttle: ThrottleConfig,
+}
+
+impl ConfigFile {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            let msg = format!("Could not read config file '{}': {}", path, e);
+            Error::new(e.kind(), msg)
+        })?;
+
+        serde_json::from_str(&contents).map_err(|e| {
+            let msg = format!("Could not parse config file '{}': {}", path, e);
+            Error::new(ErrorKind::InvalidData, msg)
+        })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub min_shard: u32,
@@ -22,6 +339,9 @@ pub struct Config {
     pub domain: String,
     pub sharks: Vec<String>,
     pub chunk_size: u64,
+    pub min_chunk_size: u64,
+    pub max_chunk_size: u64,
+    pub sql_timeout_ms: u64,
     pub begin: u64,
     pub end: u64,
     pub skip_validate_sharks: bool,
@@ -31,6 +351,24 @@ pub struct Config {
     pub max_threads: usize,
     pub direct_db: bool,
     pub log_level: Level,
+    pub checkpoint_file: Option<String>,
+    pub resume: bool,
+    pub dump_files: Vec<String>,
+    pub report_overlap: bool,
+    pub per_object: bool,
+    pub db_shark_filter: bool,
+    pub shard_splits: u32,
+    pub work_unit_size: u64,
+    pub max_retries: u32,
+    pub moray: MorayConfig,
+    pub postgres: PostgresConfig,
+    pub dns: DnsConfig,
+    pub throttle: ThrottleConfig,
+    pub cancel: CancelToken,
+
+    /// Lets a caller hold the checkpoint back until it has written out what
+    /// it was given (see `checkpoint`).
+    pub output_ack: OutputAck,
 }
 
 impl Default for Config {
@@ -43,6 +381,9 @@ impl Default for Config {
             begin: 0,
             end: 0,
             chunk_size: 1000,
+            min_chunk_size: 0,
+            max_chunk_size: 0,
+            sql_timeout_ms: 10000,
             skip_validate_sharks: false,
             output_file: None,
             obj_id_only: false,
@@ -50,6 +391,21 @@ impl Default for Config {
             max_threads: 50,
             direct_db: false,
             log_level: Level::Debug,
+            checkpoint_file: None,
+            resume: false,
+            dump_files: vec![],
+            report_overlap: false,
+            per_object: false,
+            db_shark_filter: false,
+            shard_splits: 1,
+            work_unit_size: 1_000_000,
+            max_retries: 5,
+            moray: MorayConfig::default(),
+            postgres: PostgresConfig::default(),
+            dns: DnsConfig::default(),
+            throttle: ThrottleConfig::default(),
+            cancel: CancelToken::default(),
+            output_ack: OutputAck::default(),
         }
     }
 }
@@ -114,6 +470,24 @@ impl<'a, 'b> Config {
                 .help("number of records to scan per call to moray (default: \
                 100)")
                 .takes_value(true))
+            .arg(Arg::with_name("min_chunk_size")
+                .long("min_chunk_size")
+                .value_name("NUM_IDS")
+                .help("Let the number of ids scanned per call to moray shrink \
+                to this if the ids are densely packed or moray is slow")
+                .takes_value(true))
+            .arg(Arg::with_name("max_chunk_size")
+                .long("max_chunk_size")
+                .value_name("NUM_IDS")
+                .help("Let the number of ids scanned per call to moray grow \
+                to this if the ids are sparse")
+                .takes_value(true))
+            .arg(Arg::with_name("sql_timeout")
+                .long("sql_timeout")
+                .value_name("MILLISECONDS")
+                .help("Timeout for each query made through moray (default: \
+                10000)")
+                .takes_value(true))
             .arg(Arg::with_name("begin-index")
                 .short("b")
                 .long("begin")
@@ -134,7 +508,7 @@ impl<'a, 'b> Config {
                 .takes_value(true))
             .arg(Arg::with_name("multithreaded")
                 .short("T")
-                .help("Run with multiple threads, one per shard")
+                .help("Run with multiple threads that share out the shards")
                 .long("multithreaded")
                 .takes_value(false))
             .arg(Arg::with_name("max_threads")
@@ -163,6 +537,199 @@ impl<'a, 'b> Config {
                 .long("log_level")
                 .help("Set log level")
                 .takes_value(true))
+            .arg(Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .value_name("STATE_FILE")
+                .help("Periodically save scan progress to this file")
+                .takes_value(true))
+            .arg(Arg::with_name("resume")
+                .long("resume")
+                .value_name("STATE_FILE")
+                .help("Resume an interrupted scan from this checkpoint file")
+                .conflicts_with("checkpoint")
+                .takes_value(true))
+            .arg(Arg::with_name("dump")
+                .long("dump")
+                .value_name("DUMP_FILE")
+                .help("Scan a local manatee backup of the manta table instead \
+                of moray.  The shard is taken from the \
+                '<shard>.moray.<domain>' component of the path, and the \
+                shard range defaults to the shards of the dump files.")
+                .number_of_values(1)
+                .multiple(true)
+                .conflicts_with_all(&["direct_db", "checkpoint", "resume"])
+                .takes_value(true))
+            .arg(Arg::with_name("shard_splits")
+                .long("shard_splits")
+                .value_name("NUM_SPLITS")
+                .help("Split the id range of each shard into this many parts \
+                that are scanned concurrently, with -T or --direct_db \
+                (default: 1)")
+                .conflicts_with("dump")
+                .takes_value(true))
+            .arg(Arg::with_name("work_unit_size")
+                .long("work_unit_size")
+                .value_name("NUM_IDS")
+                .help("Number of ids in each unit of work shared out between \
+                the threads of a multithreaded scan (default: 1000000)")
+                .takes_value(true))
+            .arg(Arg::with_name("max_retries")
+                .long("max_retries")
+                .value_name("NUM_RETRIES")
+                .help("Number of times to retry a failed moray request, \
+                waiting twice as long after each one (default: 5)")
+                .takes_value(true))
+            .arg(Arg::with_name("config")
+                .long("config")
+                .value_name("CONFIG_FILE")
+                .help("Read the moray, postgres, dns and throttle settings \
+                from this json file")
+                .takes_value(true))
+            .arg(Arg::with_name("moray_host")
+                .long("moray_host")
+                .value_name("HOST_TEMPLATE")
+                .help("Host name of each shard's moray, where {shard} and \
+                {domain} are filled in (default: {shard}.moray.{domain})")
+                .takes_value(true))
+            .arg(Arg::with_name("moray_port")
+                .long("moray_port")
+                .value_name("PORT")
+                .help("Port that moray listens on (default: 2020)")
+                .takes_value(true))
+            .arg(Arg::with_name("postgres_host")
+                .long("postgres_host")
+                .value_name("HOST_TEMPLATE")
+                .help("Host name of each shard's database for --direct_db, \
+                where {shard} and {domain} are filled in, or the directory of \
+                its Unix socket (default: \
+                {shard}.rebalancer-postgres.{domain})")
+                .takes_value(true))
+            .arg(Arg::with_name("postgres_port")
+                .long("postgres_port")
+                .value_name("PORT")
+                .help("Port that postgres listens on (default: 5432)")
+                .takes_value(true))
+            .arg(Arg::with_name("postgres_db")
+                .long("postgres_db")
+                .value_name("DB_NAME")
+                .help("Database to connect to for --direct_db (default: \
+                moray)")
+                .takes_value(true))
+            .arg(Arg::with_name("postgres_user")
+                .long("postgres_user")
+                .value_name("USER")
+                .help("User to connect as for --direct_db (default: \
+                postgres)")
+                .takes_value(true))
+            .arg(Arg::with_name("postgres_url")
+                .long("postgres_url")
+                .value_name("CONNECTION_STRING")
+                .help("libpq connection string or postgresql:// URL of each \
+                shard's database for --direct_db, where {shard} and {domain} \
+                are filled in.  Takes the place of the other --postgres_* \
+                connection options.")
+                .takes_value(true))
+            .arg(Arg::with_name("postgres_passfile")
+                .long("postgres_passfile")
+                .value_name("PASSFILE")
+                .help("Look up the --direct_db password in this file instead \
+                of $PGPASSFILE or ~/.pgpass")
+                .takes_value(true))
+            .arg(Arg::with_name("postgres_sslmode")
+                .long("postgres_sslmode")
+                .value_name("SSL_MODE")
+                .help("Whether to use TLS for --direct_db, and how to check \
+                the server's certificate (default: disable)")
+                .possible_values(&["disable", "prefer", "require",
+                    "verify-ca", "verify-full"])
+                .takes_value(true))
+            .arg(Arg::with_name("postgres_sslrootcert")
+                .long("postgres_sslrootcert")
+                .value_name("CA_FILE")
+                .help("Trust the certificate authorities in this file instead \
+                of the system's for --direct_db")
+                .takes_value(true))
+            .arg(Arg::with_name("postgres_statement_timeout")
+                .long("postgres_statement_timeout")
+                .value_name("MILLISECONDS")
+                .help("Give up on the --direct_db scan of a shard after this \
+                long (default: 0, no limit)")
+                .takes_value(true))
+            .arg(Arg::with_name("postgres_lock_timeout")
+                .long("postgres_lock_timeout")
+                .value_name("MILLISECONDS")
+                .help("Give up on the --direct_db scan of a shard if it has \
+                to wait this long for a lock (default: 0, no limit)")
+                .takes_value(true))
+            .arg(Arg::with_name("postgres_application_name")
+                .long("postgres_application_name")
+                .value_name("NAME")
+                .help("Name the --direct_db connections show up as in \
+                pg_stat_activity (default: sharkspotter)")
+                .takes_value(true))
+            .arg(Arg::with_name("host_map")
+                .long("host_map")
+                .value_name("HOSTS_FILE")
+                .help("Use the addresses in this file, which is in the same \
+                format as /etc/hosts, instead of looking the hosts up")
+                .takes_value(true))
+            .arg(Arg::with_name("nameserver")
+                .long("nameserver")
+                .value_name("IP_ADDR[:PORT]")
+                .help("Look up hosts using this nameserver instead of the \
+                ones in resolv.conf (default port: 53)")
+                .number_of_values(1)
+                .multiple(true)
+                .takes_value(true))
+            .arg(Arg::with_name("max_rows_per_sec")
+                .long("max_rows_per_sec")
+                .value_name("NUM_ROWS")
+                .help("Most rows to read per second across all shards")
+                .takes_value(true))
+            .arg(Arg::with_name("max_chunks_per_sec")
+                .long("max_chunks_per_sec")
+                .value_name("NUM_CHUNKS")
+                .help("Most chunks to read per second across all shards")
+                .takes_value(true))
+            .arg(Arg::with_name("shard_max_rows_per_sec")
+                .long("shard_max_rows_per_sec")
+                .value_name("NUM_ROWS")
+                .help("Most rows to read per second from each shard")
+                .takes_value(true))
+            .arg(Arg::with_name("shard_max_chunks_per_sec")
+                .long("shard_max_chunks_per_sec")
+                .value_name("NUM_CHUNKS")
+                .help("Most chunks to read per second from each shard")
+                .takes_value(true))
+            .arg(Arg::with_name("chunk_delay")
+                .long("chunk_delay")
+                .value_name("MILLISECONDS")
+                .help("Time to wait after reading each chunk")
+                .takes_value(true))
+            .arg(Arg::with_name("slow_query")
+                .long("slow_query")
+                .value_name("MILLISECONDS")
+                .help("Back off from a shard while its chunk queries take \
+                longer than this")
+                .takes_value(true))
+            .arg(Arg::with_name("report_overlap")
+                .long("report_overlap")
+                .help("Count the objects that are in both the _id and _idx \
+                columns of a shard (slow)")
+                .conflicts_with_all(&["direct_db", "dump"])
+                .takes_value(false))
+            .arg(Arg::with_name("per_object")
+                .long("per_object")
+                .help("Write each object to the output file once, even if it \
+                is on more than one of the sharks")
+                .requires("output_file")
+                .takes_value(false))
+            .arg(Arg::with_name("db_shark_filter")
+                .long("db_shark_filter")
+                .help("Have the database skip the rows that are not on the \
+                sharks (--direct_db only)")
+                .requires("direct_db")
+                .takes_value(false))
     }
 
     // TODO: This has grown over time and is now causing a clippy warning.
@@ -171,6 +738,92 @@ impl<'a, 'b> Config {
     fn config_from_matches(matches: ArgMatches) -> Result<Config, Error> {
         let mut config = Config::default();
 
+        // Anything given on the command line overrides the config file.
+        if let Ok(path) = value_t!(matches, "config", String) {
+            let file = ConfigFile::load(&path).map_err(|e| {
+                eprintln!("{}", e);
+                e
+            })?;
+            config.moray = file.moray;
+            config.postgres = file.postgres;
+            config.dns = file.dns;
+            config.throttle = file.throttle;
+        }
+
+        if let Ok(path) = value_t!(matches, "host_map", String) {
+            let hosts = load_host_map(&path).map_err(|e| {
+                eprintln!("{}", e);
+                e
+            })?;
+            config.dns.hosts.extend(hosts);
+        }
+
+        if let Some(nameservers) = matches.values_of("nameserver") {
+            config.dns.nameservers = nameservers
+                .map(parse_nameserver)
+                .collect::<Result<_, _>>()
+                .map_err(|e| {
+                    let msg = format!("Could not parse 'nameserver': {}", e);
+                    eprintln!("{}", msg);
+                    Error::new(ErrorKind::Other, msg)
+                })?;
+        }
+
+        if let Ok(host) = value_t!(matches, "moray_host", String) {
+            config.moray.host = host;
+        }
+
+        if let Ok(port) = value_t!(matches, "moray_port", u16) {
+            config.moray.port = port;
+        }
+
+        if let Ok(host) = value_t!(matches, "postgres_host", String) {
+            config.postgres.host = host;
+        }
+
+        if let Ok(port) = value_t!(matches, "postgres_port", u16) {
+            config.postgres.port = port;
+        }
+
+        if let Ok(dbname) = value_t!(matches, "postgres_db", String) {
+            config.postgres.dbname = dbname;
+        }
+
+        if let Ok(user) = value_t!(matches, "postgres_user", String) {
+            config.postgres.user = user;
+        }
+
+        if let Ok(url) = value_t!(matches, "postgres_url", String) {
+            config.postgres.connection_string = Some(url);
+        }
+
+        if let Ok(path) = value_t!(matches, "postgres_passfile", String) {
+            config.postgres.passfile = Some(path);
+        }
+
+        if let Ok(mode) = value_t!(matches, "postgres_sslmode", SslMode) {
+            config.postgres.ssl_mode = mode;
+        }
+
+        if let Ok(path) = value_t!(matches, "postgres_sslrootcert", String) {
+            config.postgres.ssl_root_cert = Some(path);
+        }
+
+        if let Ok(timeout) =
+            value_t!(matches, "postgres_statement_timeout", u64)
+        {
+            config.postgres.statement_timeout_ms = timeout;
+        }
+
+        if let Ok(timeout) = value_t!(matches, "postgres_lock_timeout", u64) {
+            config.postgres.lock_timeout_ms = timeout;
+        }
+
+        if let Ok(name) = value_t!(matches, "postgres_application_name", String)
+        {
+            config.postgres.application_name = name;
+        }
+
         if let Ok(max_shard) = value_t!(matches, "max_shard", u32) {
             config.max_shard = max_shard;
         }
@@ -191,6 +844,18 @@ impl<'a, 'b> Config {
             config.chunk_size = chunk_size;
         }
 
+        if let Ok(min) = value_t!(matches, "min_chunk_size", u64) {
+            config.min_chunk_size = min;
+        }
+
+        if let Ok(max) = value_t!(matches, "max_chunk_size", u64) {
+            config.max_chunk_size = max;
+        }
+
+        if let Ok(timeout) = value_t!(matches, "sql_timeout", u64) {
+            config.sql_timeout_ms = timeout;
+        }
+
         if let Ok(output_file) = value_t!(matches, "output_file", String) {
             config.output_file = Some(output_file);
         }
@@ -219,6 +884,94 @@ impl<'a, 'b> Config {
             config.log_level = parse_log_level(&matches)?;
         }
 
+        if let Ok(checkpoint) = value_t!(matches, "checkpoint", String) {
+            config.checkpoint_file = Some(checkpoint);
+        }
+
+        if let Ok(resume) = value_t!(matches, "resume", String) {
+            config.checkpoint_file = Some(resume);
+            config.resume = true;
+        }
+
+        if let Ok(shard_splits) = value_t!(matches, "shard_splits", u32) {
+            // Without either of these the splits of a shard would be
+            // scanned one after another.
+            if shard_splits > 1 && !config.multithreaded && !config.direct_db {
+                let msg = "'shard_splits' requires -T or --direct_db";
+                eprintln!("{}", msg);
+                return Err(Error::new(ErrorKind::Other, msg));
+            }
+            config.shard_splits = shard_splits;
+        }
+
+        if let Ok(work_unit_size) = value_t!(matches, "work_unit_size", u64) {
+            config.work_unit_size = work_unit_size;
+        }
+
+        if let Ok(max_retries) = value_t!(matches, "max_retries", u32) {
+            config.max_retries = max_retries;
+        }
+
+        let throttle = &mut config.throttle;
+        if let Ok(rate) = value_t!(matches, "max_rows_per_sec", u64) {
+            throttle.rows_per_sec = rate;
+        }
+
+        if let Ok(rate) = value_t!(matches, "max_chunks_per_sec", u64) {
+            throttle.chunks_per_sec = rate;
+        }
+
+        if let Ok(rate) = value_t!(matches, "shard_max_rows_per_sec", u64) {
+            throttle.shard_rows_per_sec = rate;
+        }
+
+        if let Ok(rate) = value_t!(matches, "shard_max_chunks_per_sec", u64) {
+            throttle.shard_chunks_per_sec = rate;
+        }
+
+        if let Ok(delay) = value_t!(matches, "chunk_delay", u64) {
+            throttle.chunk_delay_ms = delay;
+        }
+
+        if let Ok(threshold) = value_t!(matches, "slow_query", u64) {
+            throttle.slow_query_ms = threshold;
+        }
+
+        if matches.is_present("report_overlap") {
+            config.report_overlap = true;
+        }
+
+        if matches.is_present("per_object") {
+            config.per_object = true;
+        }
+
+        if matches.is_present("db_shark_filter") {
+            config.db_shark_filter = true;
+        }
+
+        if let Some(dump_files) = matches.values_of("dump") {
+            config.dump_files = dump_files.map(String::from).collect();
+        }
+
+        // Each dump file says which shard it is for, so unless a shard range
+        // was given the range is whatever the dump files cover.
+        if !config.dump_files.is_empty()
+            && !matches.is_present("min_shard")
+            && !matches.is_present("max_shard")
+        {
+            let shards: Vec<u32> = config
+                .dump_files
+                .iter()
+                .filter_map(|path| crate::dump::shard_from_path(path))
+                .collect();
+            if let (Some(min), Some(max)) =
+                (shards.iter().min(), shards.iter().max())
+            {
+                config.min_shard = *min;
+                config.max_shard = *max;
+            }
+        }
+
         config.domain = matches.value_of("domain").unwrap().to_string();
         config.sharks = matches
             .values_of("shark")
@@ -246,6 +999,20 @@ pub fn normalize_config(conf: &mut Config) {
         conf.max_threads = MAX_THREADS;
     }
 
+    if conf.shard_splits == 0 {
+        eprintln!("Shard splits must be at least 1.  Setting to 1.");
+        conf.shard_splits = 1;
+    }
+
+    if conf.work_unit_size == 0 {
+        let default = Config::default().work_unit_size;
+        eprintln!(
+            "Work unit size must be at least 1.  Setting to {}.",
+            default
+        );
+        conf.work_unit_size = default;
+    }
+
     if conf.begin > 0 && conf.end > 0 && conf.end < conf.begin {
         eprintln!("'end' is smaller than 'begin', discard 'end' value given");
         conf.end = 0;
@@ -261,6 +1028,7 @@ mod test {
         let args = vec![
             "target/debug/sharkspotter",
             "-x",
+            "-T",
             "--domain",
             "east.joyent.us",
             "--shark",
@@ -277,8 +1045,19 @@ mod test {
             "3",
             "-c",
             "20",
+            "--max_chunk_size",
+            "500",
+            "--sql_timeout",
+            "30000",
             "-f",
             "foo.txt",
+            "--shard_splits",
+            "4",
+            "--work_unit_size",
+            "5000",
+            "--max_retries",
+            "0",
+            "--per_object",
         ];
 
         let matches = Config::get_app().get_matches_from(args);
@@ -291,6 +1070,9 @@ mod test {
         assert_eq!(config.begin, 3);
         assert_eq!(config.end, 10);
         assert_eq!(config.chunk_size, 20);
+        assert_eq!(config.min_chunk_size, 0);
+        assert_eq!(config.max_chunk_size, 500);
+        assert_eq!(config.sql_timeout_ms, 30000);
 
         assert_eq!(config.output_file, Some(String::from("foo.txt")));
         assert_eq!(config.domain, String::from("east.joyent.us"));
@@ -299,5 +1081,236 @@ mod test {
             config.sharks,
             vec![String::from("1.stor"), String::from("2.stor")]
         );
+
+        assert_eq!(config.checkpoint_file, None);
+        assert!(!config.resume);
+        assert!(config.dump_files.is_empty());
+        assert!(!config.report_overlap);
+        assert!(config.per_object);
+        assert!(!config.db_shark_filter);
+        assert_eq!(config.shard_splits, 4);
+        assert!(config.multithreaded);
+        assert_eq!(config.work_unit_size, 5000);
+        assert_eq!(config.max_retries, 0);
+        assert_eq!(config.moray, MorayConfig::default());
+        assert_eq!(config.postgres, PostgresConfig::default());
+        assert!(config.dns.is_system());
+        assert_eq!(
+            config.moray.host(2, &config.domain),
+            "2.moray.east.joyent.us"
+        );
+
+        // Splits are only scanned concurrently by a multithreaded or direct
+        // DB scan.
+        let args = vec![
+            "target/debug/sharkspotter",
+            "--domain",
+            "east.joyent.us",
+            "--shark",
+            "1.stor",
+            "--shard_splits",
+            "4",
+        ];
+        let matches = Config::get_app().get_matches_from(args);
+        assert!(Config::config_from_matches(matches).is_err());
+
+        // The shard range of a dump scan comes from the dump files, unless
+        // one is given.
+        let mut args = vec![
+            "target/debug/sharkspotter",
+            "--domain",
+            "east.joyent.us",
+            "--shark",
+            "1.stor",
+            "--dump",
+            "/var/tmp/12.moray.east.joyent.us/manta.gz",
+            "--dump",
+            "/var/tmp/3.moray.east.joyent.us/manta.gz",
+        ];
+        let matches = Config::get_app().get_matches_from(args.clone());
+        let config = Config::config_from_matches(matches).expect("config");
+        assert_eq!((config.min_shard, config.max_shard), (3, 12));
+
+        args.extend(&["-M", "5"]);
+        let matches = Config::get_app().get_matches_from(args);
+        let config = Config::config_from_matches(matches).expect("config");
+        assert_eq!((config.min_shard, config.max_shard), (1, 5));
+    }
+
+    #[test]
+    fn parse_config_file() {
+        let path = std::env::temp_dir()
+            .join(format!("sharkspotter_config_{}.json", std::process::id()));
+        let path_str = path.to_str().expect("path").to_string();
+        let contents = serde_json::json!({
+            "moray": {"host": "moray-{shard}.{domain}", "port": 2021},
+            "postgres": {
+                "host": "localhost",
+                "port": 15432,
+                "user": "test",
+                "password": "hunter2",
+            },
+            "dns": {"hosts": {"localhost": "127.0.0.1"}},
+            "throttle": {"shard_rows_per_sec": 5000, "slow_query_ms": 500},
+        });
+        fs::write(&path, contents.to_string()).expect("write config");
+
+        let args = vec![
+            "target/debug/sharkspotter",
+            "--domain",
+            "staging.joyent.us",
+            "--shark",
+            "1.stor",
+            "--config",
+            &path_str,
+            "--postgres_port",
+            "25432",
+            "--nameserver",
+            "10.0.0.53",
+            "--slow_query",
+            "250",
+            "--chunk_delay",
+            "10",
+            "--postgres_sslmode",
+            "verify-full",
+            "--postgres_lock_timeout",
+            "5000",
+        ];
+
+        let matches = Config::get_app().get_matches_from(args);
+        let config = Config::config_from_matches(matches).expect("config");
+        fs::remove_file(&path).expect("remove config");
+
+        assert_eq!(
+            config.moray.host(3, &config.domain),
+            "moray-3.staging.joyent.us"
+        );
+        assert_eq!(config.moray.port, 2021);
+        assert_eq!(config.postgres.host(3, &config.domain), "localhost");
+        assert_eq!(config.postgres.port, 25432);
+        assert_eq!(config.postgres.dbname, "moray");
+        assert_eq!(config.postgres.user, "test");
+        assert_eq!(config.postgres.ssl_mode, SslMode::VerifyFull);
+        assert_eq!(config.postgres.lock_timeout_ms, 5000);
+        assert_eq!(config.postgres.statement_timeout_ms, 0);
+        assert_eq!(config.postgres.application_name, "sharkspotter");
+        assert_eq!(config.postgres.password, Some(String::from("hunter2")));
+        assert!(!format!("{:?}", config.postgres).contains("hunter2"));
+        assert_eq!(
+            config.dns.hosts.get("localhost"),
+            Some(&IpAddr::from([127, 0, 0, 1]))
+        );
+        assert_eq!(
+            config.dns.nameservers,
+            vec![SocketAddr::from(([10, 0, 0, 53], 53))]
+        );
+        assert_eq!(
+            config.throttle,
+            ThrottleConfig {
+                shard_rows_per_sec: 5000,
+                chunk_delay_ms: 10,
+                slow_query_ms: 250,
+                ..Default::default()
+            }
+        );
+
+        let bad =
+            serde_json::from_str::<ConfigFile>(r#"{"moray": {"hots": ""}}"#);
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn parse_nameserver_test() {
+        assert_eq!(
+            parse_nameserver("10.0.0.53"),
+            Ok(SocketAddr::from(([10, 0, 0, 53], DNS_PORT)))
+        );
+        assert_eq!(
+            parse_nameserver("10.0.0.53:5353"),
+            Ok(SocketAddr::from(([10, 0, 0, 53], 5353)))
+        );
+        assert_eq!(
+            parse_nameserver("::1"),
+            Ok(SocketAddr::new("::1".parse().unwrap(), DNS_PORT))
+        );
+        assert_eq!(
+            parse_nameserver("[::1]:5353"),
+            Ok(SocketAddr::new("::1".parse().unwrap(), 5353))
+        );
+        assert!(parse_nameserver("ns.joyent.us").is_err());
+        assert!(parse_nameserver("10.0.0.53:x").is_err());
+
+        let dns: DnsConfig = serde_json::from_str(
+            r#"{"nameservers": ["10.0.0.5", "10.0.0.6:5353"]}"#,
+        )
+        .expect("dns config");
+        assert_eq!(
+            dns.nameservers,
+            vec![
+                SocketAddr::from(([10, 0, 0, 5], DNS_PORT)),
+                SocketAddr::from(([10, 0, 0, 6], 5353)),
+            ]
+        );
+        assert!(serde_json::from_str::<DnsConfig>(
+            r#"{"nameservers": ["10.0.0.5:"]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_host_map_test() {
+        let contents = "# shard 1\n\
+            10.1.0.1 1.moray.east.joyent.us   1.rebalancer-postgres.east.joyent.us\n\
+            \n\
+            ::1\t2.moray.east.joyent.us # tunnel\n";
+        let hosts = parse_host_map(contents).expect("host map");
+
+        assert_eq!(hosts.len(), 3);
+        assert_eq!(
+            hosts.get("1.rebalancer-postgres.east.joyent.us"),
+            Some(&IpAddr::from([10, 1, 0, 1]))
+        );
+        assert_eq!(
+            hosts.get("2.moray.east.joyent.us"),
+            Some(&"::1".parse().unwrap())
+        );
+
+        assert!(parse_host_map("10.1.0.1\n").is_err());
+        assert!(parse_host_map("1.moray 10.1.0.1\n").is_err());
+    }
+
+    #[test]
+    fn parse_resume_args() {
+        let args = vec![
+            "target/debug/sharkspotter",
+            "--domain",
+            "east.joyent.us",
+            "--shark",
+            "1.stor",
+            "--resume",
+            "scan.state",
+        ];
+
+        let matches = Config::get_app().get_matches_from(args);
+        let config = Config::config_from_matches(matches).expect("config");
+
+        assert_eq!(config.checkpoint_file, Some(String::from("scan.state")));
+        assert!(config.resume);
+
+        let args = vec![
+            "target/debug/sharkspotter",
+            "--domain",
+            "east.joyent.us",
+            "--shark",
+            "1.stor",
+            "--checkpoint",
+            "scan.state",
+        ];
+
+        let matches = Config::get_app().get_matches_from(args);
+        let config = Config::config_from_matches(matches).expect("config");
+
+        assert_eq!(config.checkpoint_file, Some(String::from("scan.state")));
+        assert!(!config.resume);
     }
 }
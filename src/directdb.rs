@@ -8,27 +8,54 @@
  * Copyright 2020 Joyent, Inc.
  */
 
-use crossbeam_channel as crossbeam;
+use futures::future::{self, Future};
 use futures::{pin_mut, TryStreamExt};
-use serde::{Deserialize, Serialize};
-use serde_json::{self, Value};
-use slog::{debug, error, trace, warn, Logger};
-use std::io::{Error, ErrorKind};
-use tokio_postgres::{NoTls, Row};
-
-use crate::config::Config;
-use crate::{
-    get_sharks_from_manta_obj, object_id_from_manta_obj, SharkspotterMessage,
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use postgres_openssl::MakeTlsConnector;
+use serde_json::{Map, Value};
+use slog::{debug, error, info, trace, Logger};
+use std::io::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::time::delay_for;
+use tokio_postgres::config::{Host, SslMode as PgSslMode};
+use tokio_postgres::{Client, IsolationLevel, NoTls, Row, Transaction};
+
+use crate::checkpoint::Checkpoint;
+use crate::config::{expand_host_template, Config, PostgresConfig, SslMode};
+use crate::error::SharkspotterError;
+use crate::pgpass;
+use crate::probe::{
+    id_range_query, IdColumn, ShardProbe, SharedProbe, ID_COLUMNS_QUERY,
 };
+use crate::source::{IdRange, ObjectSource, SourceRecord};
+use crate::throttle::ShardThrottle;
+
+// Minimum time between progress messages for each part of a scan.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(60);
 
 // Unfortunately the Manta records in the moray database are slightly
 // different from what we get back from the moray service (both for the
 // `findobjects` and `sql` endpoints.  So if we are going direct to the database
-// we need to use a different struct to represent the record (DB schema).
-// Fortunately we don't need every field, only _value and _etag.  Note that
-// there are some differences in production manta schema versus the latest
-// manta schema.  Specifically production has a 4 byte int for _id and it
-// also includes the _idx column.
+// we need to build the moray object from the columns ourselves.  Only _value
+// and _etag are required, the id columns, _key, _mtime and _vnode are passed
+// on if they are there.  Note that there are some differences in production
+// manta schema versus the latest manta schema.  Specifically production has a
+// 4 byte int for _id and it also includes the _idx column.
+//
+// Nearly every row in a shard is for an object on some other shark, so the
+// _value of each row is checked for the requested shark names before it is
+// parsed, and only parsed once (see `record_from_row()`).
+//
+// A single query can only keep one of the database's backend processes busy,
+// so with `Config::shard_splits` the shard's primary id column is cut into
+// ranges that are each read over a connection of their own, concurrently
+// (see `scan_parts()`).  Otherwise a multithreaded run can cut the column into
+// units of work instead, like it does for a moray scan (see `scheduler`).
 //
 // moray=> SELECT table_name, column_name, data_type FROM information_schema.columns WHERE table_name = 'manta';
 // table_name | column_name | data_type
@@ -45,135 +72,1811 @@ use crate::{
 //  manta      | owner       | text
 //  manta      | objectid    | text
 //  manta      | type        | text
-#[derive(Deserialize, Serialize)]
-struct MorayMantaBucketObjectEssential {
-    _value: String,
-    _etag: String,
+
+// Production has a 4 byte int for _id while the latest schema uses a bigint,
+// so accept either.
+fn row_id(
+    row: &Row,
+    id_name: &str,
+    shard: u32,
+) -> Result<u64, SharkspotterError> {
+    let id = match row.try_get::<_, i64>(id_name) {
+        Ok(id) => id,
+        Err(_) => {
+            row.try_get::<_, i32>(id_name).map(i64::from).map_err(|e| {
+                SharkspotterError::MalformedRecord {
+                    shard,
+                    id: None,
+                    cause: e.to_string(),
+                }
+            })?
+        }
+    };
+
+    Ok(id as u64)
+}
+
+// A condition that only rows whose _value has one of the sharks' names in
+// it, as a json string, pass.  Like `may_be_on_sharks()` this lets through
+// some rows that aren't on the sharks, which are weeded out once the _value
+// is parsed.
+fn shark_filter(sharks: &[String]) -> String {
+    let likes: Vec<String> = sharks
+        .iter()
+        .map(|shark| {
+            let pattern = shark
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+                .replace('\'', "''");
+            format!("_value LIKE '%\"{}\"%'", pattern)
+        })
+        .collect();
+
+    likes.join(" OR ")
+}
+
+// The rows of a shard that a single query reads: either the whole table, or
+// the ids of its id column from `begin` up to but not including `end` (the end
+// of the table if None).  When the shard is split each part is read over a
+// connection of its own.
+#[derive(Debug, Clone, PartialEq)]
+struct ScanPart {
+    id_name: Option<&'static str>,
+
+    /// Which split of the id column this is, if the shard is split (see
+    /// `Config::shard_splits`).
+    split: Option<u32>,
+
+    /// The first id of the unit of work this is, if it is one (see
+    /// `DirectDbObjectSource::split_off`).
+    unit: Option<u64>,
+    begin: u64,
+    end: Option<u64>,
+
+    /// Without a checkpoint we let postgres return the rows in whatever order
+    /// is cheapest.  With one we need to walk the table in id order so that
+    /// the last id we have sent is a valid point to resume from.
+    ordered: bool,
 }
 
-pub async fn get_objects_from_shard(
+impl ScanPart {
+    fn whole_table() -> Self {
+        ScanPart {
+            id_name: None,
+            split: None,
+            unit: None,
+            begin: 0,
+            end: None,
+            ordered: false,
+        }
+    }
+
+    // Each split and each unit of work is checkpointed separately, as it is
+    // for a moray scan.
+    fn checkpoint_key(&self) -> Option<String> {
+        let id_name = self.id_name?;
+
+        Some(match (self.split, self.unit) {
+            (_, Some(unit)) => format!("{}@{}", id_name, unit),
+            (Some(split), None) => format!("{}#{}", id_name, split),
+            (None, None) => id_name.to_string(),
+        })
+    }
+
+    fn name(&self, shard: u32, splits: u32) -> String {
+        match (self.id_name, self.split, self.unit, self.end) {
+            (Some(id_name), _, Some(unit), Some(end)) => {
+                format!("shard {} {} ids {}-{}", shard, id_name, unit, end - 1)
+            }
+            (Some(id_name), Some(split), _, _) => format!(
+                "shard {} {} split {}/{}",
+                shard,
+                id_name,
+                split + 1,
+                splits
+            ),
+            _ => format!("shard {}", shard),
+        }
+    }
+}
+
+// Work out which parts to read a shard in.  A split shard is cut into parts
+// at the same ids as in the original run if it is being resumed, and the
+// last part is left open ended so that it picks up any rows added since the
+// shard was probed.  A unit of work is read as a single part.  Parts that an
+// earlier run finished are left out.
+fn scan_parts(
     shard: u32,
-    conf: Config,
-    log: Logger,
-    obj_tx: crossbeam::Sender<SharkspotterMessage>,
-) -> Result<(), Error> {
-    let shard_host_name =
-        format!("{}.rebalancer-postgres.{}", shard, conf.domain);
+    conf: &Config,
+    checkpoint: &Checkpoint,
+    probe: &ShardProbe,
+    unit: Option<IdRange>,
+) -> Result<Vec<ScanPart>, SharkspotterError> {
+    let id_name =
+        probe
+            .primary_column()
+            .ok_or_else(|| SharkspotterError::Query {
+                shard,
+                cause: String::from("manta table has no _id or _idx column"),
+            })?;
+    let ordered = checkpoint.is_enabled();
+    let range = probe.column(id_name).and_then(|column| column.range);
 
-    debug!(log, "Connecting to {}", shard_host_name);
-    // Connect to this shard's reblancer-postgres moray database.
-    let (client, connection) = tokio_postgres::Config::new()
-        .host(shard_host_name.as_str())
-        .user("postgres")
-        .dbname("moray")
-        .keepalives_idle(std::time::Duration::from_secs(30))
-        .connect(NoTls)
-        .await
-        .map_err(|e| {
-            error!(log, "failed to connect to {}: {}", &shard_host_name, e);
-            Error::new(ErrorKind::Other, e)
-        })?;
+    let mut parts = match (unit, range) {
+        (Some(unit), _) => vec![ScanPart {
+            id_name: Some(id_name),
+            split: None,
+            unit: Some(unit.begin),
+            begin: unit.begin,
+            end: Some(unit.end + 1),
+            ordered,
+        }],
+        (None, Some(range)) if conf.shard_splits > 1 => {
+            let points = checkpoint.split_range(
+                shard,
+                id_name,
+                range,
+                conf.shard_splits,
+            )?;
 
-    let task_host_name = shard_host_name.clone();
-    let task_log = log.clone();
+            points
+                .iter()
+                .enumerate()
+                .map(|(i, begin)| ScanPart {
+                    id_name: Some(id_name),
+                    split: Some(i as u32),
+                    unit: None,
+                    begin: *begin,
+                    end: points.get(i + 1).copied(),
+                    ordered,
+                })
+                .collect()
+        }
+        _ => vec![ScanPart {
+            id_name: Some(id_name),
+            split: None,
+            unit: None,
+            begin: 0,
+            end: None,
+            ordered,
+        }],
+    };
 
-    tokio::spawn(async move {
-        connection.await.map_err(|e| {
-            error!(
-                task_log,
-                "could not communicate with {}: {}", task_host_name, e
-            );
-            Error::new(ErrorKind::Other, e)
-        })?;
-        Ok::<(), Error>(())
+    for part in parts.iter_mut() {
+        let key = part.checkpoint_key().expect("part of an id column");
+        if let Some(id) = checkpoint.resume_point(shard, &key) {
+            part.begin = part.begin.max(id + 1);
+        }
+    }
+    parts.retain(|part| match part.end {
+        Some(end) => part.begin < end,
+        None => true,
     });
 
-    let rows = client
-        .query_raw("SELECT * from manta where type='object'", vec![])
-        .await
-        .map_err(|e| {
-            error!(log, "query error for {}: {}", &shard_host_name, e);
-            Error::new(ErrorKind::Other, e)
-        })?;
-
-    pin_mut!(rows);
-    // Iterate over the rows in the stream.  For each one determine if it
-    // matches the shark we are looking for.
-    while let Some(row) = rows
-        .try_next()
-        .await
-        .map_err(|e| Error::new(ErrorKind::Other, e))?
+    Ok(parts)
+}
+
+// If `sharks` is not empty only the rows that may be on one of them are
+// returned.
+fn objects_query(part: &ScanPart, sharks: &[String]) -> String {
+    let mut query = String::from("SELECT * FROM manta WHERE type = 'object'");
+
+    if !sharks.is_empty() {
+        query.push_str(&format!(" AND ({})", shark_filter(sharks)));
+    }
+
+    if let Some(id_name) = part.id_name {
+        query.push_str(&format!(" AND {} >= {}", id_name, part.begin));
+        if let Some(end) = part.end {
+            query.push_str(&format!(" AND {} < {}", id_name, end));
+        }
+        if part.ordered {
+            query.push_str(&format!(" ORDER BY {}", id_name));
+        }
+    }
+
+    query
+}
+
+// The settings to connect to a shard's database with.
+struct ShardDb {
+    /// The host name (before any lookup of our own) or socket directory.
+    host: Host,
+    port: u16,
+    dbname: String,
+    user: String,
+    password: Option<Vec<u8>>,
+    passfile: Option<String>,
+    ssl_mode: SslMode,
+    ssl_root_cert: Option<String>,
+    application_name: String,
+    options: Option<String>,
+    connect_timeout: Option<Duration>,
+}
+
+impl ShardDb {
+    fn endpoint(&self) -> String {
+        match &self.host {
+            Host::Tcp(host) => format!("{}:{}", host, self.port),
+            Host::Unix(dir) => {
+                format!("{}/.s.PGSQL.{}", dir.display(), self.port)
+            }
+        }
+    }
+}
+
+// The connection string settings that tokio-postgres either doesn't know
+// about, or only knows some of the values of, and that are handled here
+// instead.
+const LIBPQ_ONLY_KEYS: [&str; 3] = ["sslmode", "sslrootcert", "passfile"];
+
+fn percent_decode(s: &str) -> Result<String, String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = s
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("invalid percent escape in '{}'", s))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|e| e.to_string())
+}
+
+// Split a "key = value ..." connection string into its settings, undoing
+// the quoting of the values.
+fn split_key_values(conn_str: &str) -> Result<Vec<(String, String)>, String> {
+    let mut settings = vec![];
+    let mut chars = conn_str.chars().peekable();
+    let skip_spaces = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.peek().map(|c| c.is_whitespace()) == Some(true) {
+            chars.next();
+        }
+    };
+
+    loop {
+        skip_spaces(&mut chars);
+        if chars.peek().is_none() {
+            return Ok(settings);
+        }
+
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c.is_whitespace() {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+
+        skip_spaces(&mut chars);
+        if chars.next() != Some('=') {
+            return Err(format!("missing \"=\" after \"{}\"", key));
+        }
+        skip_spaces(&mut chars);
+
+        let mut value = String::new();
+        let quoted = chars.peek() == Some(&'\'');
+        if quoted {
+            chars.next();
+        }
+        loop {
+            match chars.next() {
+                Some('\\') => match chars.next() {
+                    Some(c) => value.push(c),
+                    None => break,
+                },
+                Some('\'') if quoted => break,
+                Some(c) if c.is_whitespace() && !quoted => break,
+                Some(c) => value.push(c),
+                None if quoted => {
+                    return Err(format!("unterminated quote in \"{}\"", key))
+                }
+                None => break,
+            }
+        }
+
+        settings.push((key, value));
+    }
+}
+
+// Take the LIBPQ_ONLY_KEYS out of a connection string, which is either a
+// "key = value ..." string or a postgresql:// URL with the settings in its
+// query string.  Returns the rest of the connection string, for
+// tokio-postgres to parse, and the settings that were taken out of it.
+fn take_libpq_settings(
+    conn_str: &str,
+) -> Result<(String, Vec<(String, String)>), String> {
+    let mut taken = vec![];
+
+    if conn_str.starts_with("postgresql://")
+        || conn_str.starts_with("postgres://")
     {
-        let val_str: &str = row.get("_value");
-        let value: Value = serde_json::from_str(val_str)
-            .map_err(|e| Error::new(ErrorKind::Other, e))?;
-        if let Err(e) = check_value_for_match(
-            &value,
-            &row,
-            &conf.sharks,
-            shard,
-            &obj_tx,
-            &log,
-        ) {
-            return Err(e);
+        let mut parts = conn_str.splitn(2, '?');
+        let base = parts.next().unwrap_or("");
+        let query = match parts.next() {
+            Some(query) => query,
+            None => return Ok((conn_str.to_string(), taken)),
+        };
+
+        let mut kept = vec![];
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let mut kv = param.splitn(2, '=');
+            let key = kv.next().unwrap_or("");
+            match (LIBPQ_ONLY_KEYS.contains(&key), kv.next()) {
+                (true, Some(value)) => {
+                    taken.push((key.to_string(), percent_decode(value)?))
+                }
+                _ => kept.push(param),
+            }
+        }
+
+        if kept.is_empty() {
+            return Ok((base.to_string(), taken));
         }
+        return Ok((format!("{}?{}", base, kept.join("&")), taken));
     }
 
-    Ok(())
+    let mut kept = vec![];
+    for (key, value) in split_key_values(conn_str)? {
+        if LIBPQ_ONLY_KEYS.contains(&key.as_str()) {
+            taken.push((key, value));
+        } else {
+            let value = value.replace('\\', "\\\\").replace('\'', "\\'");
+            kept.push(format!("{}='{}'", key, value));
+        }
+    }
+
+    Ok((kept.join(" "), taken))
+}
+
+// Work out how to connect to a shard's database.  Anything that is in the
+// connection string takes the place of the matching postgres config field.
+fn shard_db(shard: u32, conf: &Config) -> Result<ShardDb, SharkspotterError> {
+    let pg = &conf.postgres;
+    let host_name = pg.host(shard, &conf.domain);
+    let mut db = ShardDb {
+        host: if host_name.starts_with('/') {
+            Host::Unix(PathBuf::from(host_name))
+        } else {
+            Host::Tcp(host_name)
+        },
+        port: pg.port,
+        dbname: pg.dbname.clone(),
+        user: pg.user.clone(),
+        password: pg.password.as_ref().map(|p| p.as_bytes().to_vec()),
+        passfile: pg.passfile.clone(),
+        ssl_mode: pg.ssl_mode,
+        ssl_root_cert: pg.ssl_root_cert.clone(),
+        application_name: pg.application_name.clone(),
+        options: None,
+        connect_timeout: None,
+    };
+
+    let conn_str = match &pg.connection_string {
+        Some(conn_str) => expand_host_template(conn_str, shard, &conf.domain),
+        None => return Ok(db),
+    };
+    let invalid = |cause: String| SharkspotterError::Connect {
+        endpoint: format!("shard {}", shard),
+        cause: format!("invalid connection string: {}", cause),
+    };
+
+    let (conn_str, libpq_settings) =
+        take_libpq_settings(&conn_str).map_err(invalid)?;
+    let parsed = tokio_postgres::Config::from_str(&conn_str)
+        .map_err(|e| invalid(e.to_string()))?;
+
+    if let Some(host) = parsed.get_hosts().first() {
+        db.host = host.clone();
+    }
+    if let Some(port) = parsed.get_ports().first() {
+        db.port = *port;
+    }
+    if let Some(dbname) = parsed.get_dbname() {
+        db.dbname = dbname.to_string();
+    }
+    if let Some(user) = parsed.get_user() {
+        db.user = user.to_string();
+    }
+    if let Some(password) = parsed.get_password() {
+        db.password = Some(password.to_vec());
+    }
+    if let Some(application_name) = parsed.get_application_name() {
+        db.application_name = application_name.to_string();
+    }
+    if let Some(options) = parsed.get_options() {
+        db.options = Some(options.to_string());
+    }
+    if let Some(timeout) = parsed.get_connect_timeout() {
+        db.connect_timeout = Some(*timeout);
+    }
+
+    for (key, value) in libpq_settings {
+        match key.as_str() {
+            "sslmode" => {
+                db.ssl_mode = SslMode::from_str(&value).map_err(invalid)?
+            }
+            "sslrootcert" => db.ssl_root_cert = Some(value),
+            "passfile" => db.passfile = Some(value),
+            _ => (),
+        }
+    }
+
+    Ok(db)
 }
 
-fn check_value_for_match(
-    value: &Value,
+// A TLS connector for the configured sslmode, or None if TLS is disabled.
+// The certificate is checked against `host_name` rather than the host that
+// is connected to, which is an address when we look the host up ourselves.
+fn tls_connector(
+    db: &ShardDb,
+    host_name: &str,
+) -> Result<Option<MakeTlsConnector>, ErrorStack> {
+    let verify = match db.ssl_mode {
+        SslMode::Disable => return Ok(None),
+        SslMode::Prefer | SslMode::Require => false,
+        SslMode::VerifyCa | SslMode::VerifyFull => true,
+    };
+
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    if let Some(ca_file) = &db.ssl_root_cert {
+        builder.set_ca_file(ca_file)?;
+    }
+    if !verify {
+        builder.set_verify(SslVerifyMode::NONE);
+    }
+
+    let verify_host = match db.ssl_mode {
+        SslMode::VerifyFull => Some(host_name.to_string()),
+        _ => None,
+    };
+    let mut connector = MakeTlsConnector::new(builder.build());
+    connector.set_callback(move |config, _| {
+        config.set_verify_hostname(false);
+        if let Some(host) = &verify_host {
+            config.param_mut().set_host(host)?;
+        }
+        Ok(())
+    });
+
+    Ok(Some(connector))
+}
+
+fn spawn_connection<C>(connection: C, endpoint: String, log: Logger)
+where
+    C: Future<Output = Result<(), tokio_postgres::Error>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!(log, "could not communicate with {}: {}", endpoint, e);
+        }
+    });
+}
+
+async fn connect_to_shard(
+    shard: u32,
+    conf: &Config,
+    log: &Logger,
+) -> Result<Client, SharkspotterError> {
+    let db = shard_db(shard, conf)?;
+    let endpoint = db.endpoint();
+    let connect_error = |cause: String| {
+        error!(log, "failed to connect to {}: {}", endpoint, cause);
+        SharkspotterError::Connect {
+            endpoint: endpoint.clone(),
+            cause,
+        }
+    };
+
+    let mut pg_config = tokio_postgres::Config::new();
+    pg_config
+        .port(db.port)
+        .user(db.user.as_str())
+        .dbname(db.dbname.as_str())
+        .keepalives_idle(Duration::from_secs(30));
+
+    // So that the scan's queries can be told apart in pg_stat_activity.
+    if !db.application_name.is_empty() {
+        pg_config.application_name(db.application_name.as_str());
+    }
+    if let Some(options) = &db.options {
+        pg_config.options(options.as_str());
+    }
+    if let Some(timeout) = db.connect_timeout {
+        pg_config.connect_timeout(timeout);
+    }
+
+    // Connections over a Unix socket count as "localhost" in the password
+    // file, as they do for libpq.
+    let (host_name, pgpass_host) = match &db.host {
+        Host::Unix(dir) => {
+            pg_config.host_path(dir);
+            ("localhost", "localhost")
+        }
+        Host::Tcp(host_name) => {
+            // tokio-postgres looks the host up with the system resolver, so
+            // only look it up ourselves if that has been overridden.
+            let connect_host = if conf.dns.is_system() {
+                host_name.clone()
+            } else {
+                let host = host_name.clone();
+                let dns = conf.dns.clone();
+                tokio::task::spawn_blocking(move || {
+                    crate::lookup_ip_str(&host, &dns)
+                })
+                .await
+                .map_err(|e| SharkspotterError::Dns {
+                    host: host_name.clone(),
+                    cause: e.to_string(),
+                })??
+            };
+
+            debug!(log, "Connecting to {} ({})", endpoint, connect_host);
+            pg_config.host(connect_host.as_str());
+            (host_name.as_str(), host_name.as_str())
+        }
+    };
+
+    let password = match &db.password {
+        Some(password) => Some(password.clone()),
+        None => pgpass::lookup_password(
+            db.passfile.as_ref().map(String::as_str),
+            pgpass_host,
+            db.port,
+            &db.dbname,
+            &db.user,
+            log,
+        )
+        .map(String::into_bytes),
+    };
+    if let Some(password) = password {
+        pg_config.password(password);
+    }
+
+    pg_config.ssl_mode(match db.ssl_mode {
+        SslMode::Disable => PgSslMode::Disable,
+        SslMode::Prefer => PgSslMode::Prefer,
+        _ => PgSslMode::Require,
+    });
+
+    // Connect to this shard's reblancer-postgres moray database.
+    let tls = tls_connector(&db, host_name)
+        .map_err(|e| connect_error(format!("could not set up TLS: {}", e)))?;
+    let client = match tls {
+        None => {
+            let (client, connection) = pg_config
+                .connect(NoTls)
+                .await
+                .map_err(|e| connect_error(e.to_string()))?;
+            spawn_connection(connection, endpoint.clone(), log.clone());
+            client
+        }
+        Some(tls) => {
+            let (client, connection) = pg_config
+                .connect(tls)
+                .await
+                .map_err(|e| connect_error(e.to_string()))?;
+            spawn_connection(connection, endpoint.clone(), log.clone());
+            client
+        }
+    };
+
+    Ok(client)
+}
+
+fn row_error(
     row: &Row,
-    filter_sharks: &[String],
     shard: u32,
-    obj_tx: &crossbeam_channel::Sender<SharkspotterMessage>,
     log: &Logger,
-) -> Result<(), Error> {
-    let obj_id = object_id_from_manta_obj(value)
-        .map_err(|e| Error::new(ErrorKind::Other, e))?;
-    let sharks = get_sharks_from_manta_obj(value, log)?;
+    e: tokio_postgres::Error,
+) -> SharkspotterError {
+    error!(log, "Error deserializing record as manta object: {}", e);
+    SharkspotterError::MalformedRecord {
+        shard,
+        id: row_id(row, "_id", shard)
+            .or_else(|_| row_id(row, "_idx", shard))
+            .ok(),
+        cause: e.to_string(),
+    }
+}
 
-    trace!(log, "sharkspotter checking {}", obj_id);
-    sharks
-        .iter()
-        .filter(|s| filter_sharks.contains(&s.manta_storage_id))
-        .try_for_each(|s| {
-            send_matching_object(row, &s.manta_storage_id, shard, &obj_tx, log)
-        })
+// Everything but the _value of the moray object that the moray `sql` endpoint
+// would return for a row of the manta table.  The _id is included so that
+// errors further along can say which record they came from.
+fn moray_fields_from_row(
+    row: &Row,
+    shard: u32,
+    log: &Logger,
+) -> Result<Map<String, Value>, SharkspotterError> {
+    let etag: String = row
+        .try_get("_etag")
+        .map_err(|e| row_error(row, shard, log, e))?;
+
+    let mut fields = Map::new();
+    fields.insert("_id".into(), row_id(row, "_id", shard).ok().into());
+    fields.insert("_idx".into(), row_id(row, "_idx", shard).ok().into());
+    fields.insert("_key".into(), row.try_get::<_, String>("_key").ok().into());
+    fields.insert("_mtime".into(), row_id(row, "_mtime", shard).ok().into());
+    fields.insert("_vnode".into(), row_id(row, "_vnode", shard).ok().into());
+    fields.insert("_etag".into(), etag.into());
+
+    Ok(fields)
 }
 
-fn send_matching_object(
+// Build a moray object of the same form the moray `sql` endpoint returns from
+// a row of the manta table.
+fn moray_obj_from_row(
     row: &Row,
-    shark_name: &str,
     shard: u32,
-    obj_tx: &crossbeam_channel::Sender<SharkspotterMessage>,
     log: &Logger,
-) -> Result<(), Error> {
-    trace!(log, "Found matching record: {:#?}", &row);
-    let moray_object: MorayMantaBucketObjectEssential =
-        serde_postgres::from_row(&row).map_err(|e| {
-            error!(log, "Error deserializing record as manta object: {}", e);
-            Error::new(ErrorKind::Other, e)
-        })?;
-
-    let etag = moray_object._etag.clone();
-    let manta_value_str = moray_object._value.as_str();
-    let manta_value: Value = serde_json::from_str(manta_value_str)
-        .map_err(|e| Error::new(ErrorKind::Other, e))?;
-
-    trace!(log, "Sending value: {:#?}", manta_value);
-
-    let msg = SharkspotterMessage {
+) -> Result<Value, SharkspotterError> {
+    let value: String = row
+        .try_get("_value")
+        .map_err(|e| row_error(row, shard, log, e))?;
+    let mut fields = moray_fields_from_row(row, shard, log)?;
+    fields.insert("_value".into(), value.into());
+
+    Ok(Value::Object(fields))
+}
+
+// A manta_storage_id is a host name, which json never needs to escape, so
+// the _value of an object that is on one of the sharks must have its name in
+// it somewhere.
+fn may_be_on_sharks(value: &str, sharks: &[String]) -> bool {
+    sharks.iter().any(|shark| value.contains(shark.as_str()))
+}
+
+// Parse the _value of a row straight from the text that postgres returned.
+fn parsed_record(
+    moray_fields: Map<String, Value>,
+    value: &str,
+    shard: u32,
+) -> SourceRecord<'static> {
+    let moray_value = Value::Object(moray_fields);
+    let manta_value = serde_json::from_str(value).map_err(|e| {
+        SharkspotterError::MalformedRecord {
+            shard,
+            id: crate::moray_obj_id(&moray_value),
+            cause: format!("Could not parse _value ({})", e),
+        }
+    });
+
+    SourceRecord::Parsed {
+        moray_value,
         manta_value,
-        etag,
-        shark: shark_name.to_string(),
+    }
+}
+
+// Turn a row into a record without copying its _value, skipping it if it
+// can't be on any of the requested sharks.
+fn record_from_row(
+    row: &Row,
+    shard: u32,
+    sharks: &[String],
+    log: &Logger,
+) -> Result<SourceRecord<'static>, SharkspotterError> {
+    let value: &str = row
+        .try_get("_value")
+        .map_err(|e| row_error(row, shard, log, e))?;
+
+    record_from_value(value, shard, sharks, || {
+        moray_fields_from_row(row, shard, log)
+    })
+}
+
+// The part of `record_from_row()` that only needs the row's _value.  The
+// rest of the row's fields are only read if it may be on one of the sharks.
+fn record_from_value<M>(
+    value: &str,
+    shard: u32,
+    sharks: &[String],
+    moray_fields: M,
+) -> Result<SourceRecord<'static>, SharkspotterError>
+where
+    M: FnOnce() -> Result<Map<String, Value>, SharkspotterError>,
+{
+    if !may_be_on_sharks(value, sharks) {
+        return Ok(SourceRecord::Skipped);
+    }
+
+    Ok(parsed_record(moray_fields()?, value, shard))
+}
+
+async fn probe_with_client(
+    client: &Client,
+    shard: u32,
+    log: &Logger,
+) -> Result<ShardProbe, SharkspotterError> {
+    let query_error = |e: tokio_postgres::Error| {
+        error!(log, "probe query error for shard {}: {}", shard, e);
+        SharkspotterError::Query {
+            shard,
+            cause: e.to_string(),
+        }
+    };
+
+    let mut id_columns = vec![];
+    for row in client
+        .query(ID_COLUMNS_QUERY, &[])
+        .await
+        .map_err(query_error)?
+    {
+        let name: String = row.try_get("column_name").map_err(query_error)?;
+        let data_type: String =
+            row.try_get("data_type").map_err(query_error)?;
+
+        let range_row = client
+            .query_one(id_range_query(&name).as_str(), &[])
+            .await
+            .map_err(query_error)?;
+        let min: Option<i64> = range_row.try_get("min").map_err(query_error)?;
+        let max: Option<i64> = range_row.try_get("max").map_err(query_error)?;
+
+        let range = match (min, max) {
+            (Some(begin), Some(end)) => Some(IdRange {
+                begin: begin as u64,
+                end: end as u64,
+            }),
+            _ => None,
+        };
+
+        id_columns.push(IdColumn {
+            name,
+            data_type,
+            range,
+        });
+    }
+
+    // Keep the columns in the same order as ID_COLUMNS.
+    id_columns.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let probe = ShardProbe { shard, id_columns };
+    info!(log, "shard probe"; "shard" => shard, "id_columns" => probe.summary());
+
+    Ok(probe)
+}
+
+/// Find which id columns this shard's manta table has, along with their
+/// types and the range of values in them.
+pub async fn probe_shard(
+    shard: u32,
+    conf: &Config,
+    log: &Logger,
+) -> Result<ShardProbe, SharkspotterError> {
+    let client = connect_to_shard(shard, conf, log).await?;
+    probe_with_client(&client, shard, log).await
+}
+
+/// Stream every object in this shard's manta table, calling the handler with
+/// each one as a moray object.
+pub async fn get_objects_from_shard<F>(
+    shard: u32,
+    conf: Config,
+    log: Logger,
+    checkpoint: Checkpoint,
+    throttle: ShardThrottle,
+    mut handler: F,
+) -> Result<(), SharkspotterError>
+where
+    F: FnMut(&Value) -> Result<(), SharkspotterError>,
+{
+    scan_rows(
+        shard,
+        &conf,
+        &log,
+        checkpoint,
+        throttle,
+        None,
+        |row| moray_obj_from_row(row, shard, &log),
+        |obj| future::ready(handler(&obj)),
+    )
+    .await
+}
+
+/// Stream every object in this shard's manta table, calling the handler with
+/// each one as a record whose _value has already been parsed, or as a
+/// skipped record if it can't be on any of `conf.sharks`.  The handler
+/// returns a future, so that it can e.g. wait for room in a channel before
+/// the next row is read.
+pub async fn visit_objects_in_shard<F, Fut>(
+    shard: u32,
+    conf: Config,
+    log: Logger,
+    checkpoint: Checkpoint,
+    throttle: ShardThrottle,
+    handler: F,
+) -> Result<(), SharkspotterError>
+where
+    F: FnMut(SourceRecord<'static>) -> Fut,
+    Fut: Future<Output = Result<(), SharkspotterError>>,
+{
+    scan_rows(
         shard,
+        &conf,
+        &log,
+        checkpoint,
+        throttle,
+        None,
+        |row| record_from_row(row, shard, &conf.sharks, &log),
+        handler,
+    )
+    .await
+}
+
+// Every shard scan is read in a read only transaction, so that it can never
+// change anything, and sees the table as it was when the scan started however
+// long it takes.  The parts of a split shard are read in a snapshot exported
+// from another transaction, so that they all see the same table.
+async fn start_scan_transaction<'a>(
+    client: &'a mut Client,
+    pg: &PostgresConfig,
+    snapshot: Option<&str>,
+) -> Result<Transaction<'a>, tokio_postgres::Error> {
+    let tx = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()
+        .await?;
+
+    let mut settings = String::new();
+    if let Some(snapshot) = snapshot {
+        settings
+            .push_str(&format!("SET TRANSACTION SNAPSHOT '{}'; ", snapshot));
+    }
+    settings.push_str(&format!(
+        "SET LOCAL statement_timeout = {}; SET LOCAL lock_timeout = {};",
+        pg.statement_timeout_ms, pg.lock_timeout_ms
+    ));
+    tx.batch_execute(&settings).await?;
+
+    Ok(tx)
+}
+
+// What the parts of a shard's scan share.  The parts of a split shard are
+// read concurrently on the same task, so they take turns with the handler.
+struct ShardScan<'a, R, F> {
+    shard: u32,
+    conf: &'a Config,
+    log: &'a Logger,
+    checkpoint: &'a Checkpoint,
+    filter_sharks: &'a [String],
+    to_record: R,
+    handler: Mutex<F>,
+}
+
+impl<'a, T, R, F, Fut> ShardScan<'a, R, F>
+where
+    R: Fn(&Row) -> Result<T, SharkspotterError>,
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = Result<(), SharkspotterError>>,
+{
+    fn query_error(&self, e: tokio_postgres::Error) -> SharkspotterError {
+        SharkspotterError::Query {
+            shard: self.shard,
+            cause: e.to_string(),
+        }
+    }
+
+    /// Read each of the parts over a connection of its own, in a snapshot
+    /// exported from `client`, whose transaction has to stay open until
+    /// they have all started.  Returns whether the scan was cancelled.
+    async fn scan_split(
+        &self,
+        client: &mut Client,
+        parts: &[ScanPart],
+        throttle: &ShardThrottle,
+    ) -> Result<bool, SharkspotterError> {
+        let log = self.log;
+        let tx = start_scan_transaction(client, &self.conf.postgres, None)
+            .await
+            .map_err(|e| {
+                error!(
+                    log,
+                    "could not start transaction on shard {}: {}",
+                    self.shard,
+                    e
+                );
+                self.query_error(e)
+            })?;
+        let snapshot: String = tx
+            .query_one("SELECT pg_export_snapshot();", &[])
+            .await
+            .and_then(|row| row.try_get(0))
+            .map_err(|e| {
+                error!(
+                    log,
+                    "could not export snapshot of shard {}: {}", self.shard, e
+                );
+                self.query_error(e)
+            })?;
+
+        info!(log, "scanning shard {} in {} parts", self.shard, parts.len();
+            "snapshot" => &snapshot);
+
+        let scans = parts.iter().map(|part| {
+            let throttle = throttle.clone();
+            let snapshot = snapshot.as_str();
+
+            async move {
+                let mut client =
+                    connect_to_shard(self.shard, self.conf, log).await?;
+                self.scan_part(&mut client, part, Some(snapshot), throttle)
+                    .await
+            }
+        });
+        let cancelled = future::try_join_all(scans)
+            .await?
+            .into_iter()
+            .any(|cancelled| cancelled);
+
+        tx.commit().await.map_err(|e| self.query_error(e))?;
+        Ok(cancelled)
+    }
+
+    /// Read a part of the shard over `client`, handing each row to the
+    /// handler.  Returns whether the scan was cancelled.
+    async fn scan_part(
+        &self,
+        client: &mut Client,
+        part: &ScanPart,
+        snapshot: Option<&str>,
+        mut throttle: ShardThrottle,
+    ) -> Result<bool, SharkspotterError> {
+        let (shard, conf, log) = (self.shard, self.conf, self.log);
+        let name = part.name(shard, conf.shard_splits);
+        let checkpoint_key = if part.ordered {
+            part.checkpoint_key()
+        } else {
+            None
+        };
+        let query = objects_query(part, self.filter_sharks);
+
+        if let Some(id_name) = part.id_name {
+            debug!(log, "scanning {} from {} {}", name, id_name, part.begin);
+        }
+
+        let tx = start_scan_transaction(client, &conf.postgres, snapshot)
+            .await
+            .map_err(|e| {
+                error!(log, "could not start transaction on {}: {}", name, e);
+                self.query_error(e)
+            })?;
+
+        // A `Vec<&dyn ToSql>` of parameters would make this future !Send, and
+        // so impossible to spawn (see `stream`), where an empty iterator is
+        // not.
+        let rows = tx
+            .query_raw(query.as_str(), std::iter::empty())
+            .await
+            .map_err(|e| {
+                error!(log, "query error for {}: {}", name, e);
+                self.query_error(e)
+            })?;
+
+        pin_mut!(rows);
+        let mut rows_read: u64 = 0;
+        let mut rows_since_checkpoint = 0;
+        let mut last_id = None;
+        let mut last_progress = Instant::now();
+        let mut cancelled = false;
+
+        // Iterate over the rows in the stream, handing each one to the caller.
+        while let Some(row) =
+            rows.try_next().await.map_err(|e| self.query_error(e))?
+        {
+            trace!(log, "Read record: {:#?}", &row);
+            let record = (self.to_record)(&row)?;
+
+            // The handler is only held while it is called, not while the
+            // future it returns is waited on.
+            let handled = {
+                let mut handler = self.handler.lock().expect("handler lock");
+                (*handler)(record)
+            };
+            handled.await?;
+
+            let wait = throttle.after_row(log);
+            if wait > Duration::from_secs(0) {
+                delay_for(wait).await;
+            }
+
+            rows_read += 1;
+            if let Some(id_name) = part.id_name {
+                last_id = Some(row_id(&row, id_name, shard)?);
+            }
+
+            if let (Some(key), Some(id)) = (&checkpoint_key, last_id) {
+                rows_since_checkpoint += 1;
+                if rows_since_checkpoint >= conf.chunk_size {
+                    self.checkpoint.update(shard, key, id, log)?;
+                    rows_since_checkpoint = 0;
+                }
+            }
+
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                info!(log, "scan progress"; "scan" => &name,
+                    "rows" => rows_read, "last_id" => last_id);
+                last_progress = Instant::now();
+            }
+
+            if conf.cancel.is_cancelled() {
+                info!(log, "scan of {} cancelled", name);
+                cancelled = true;
+                break;
+            }
+        }
+
+        // Record how far we got, even if the scan was cancelled, so that it
+        // can be resumed.
+        if let (Some(key), Some(id)) = (&checkpoint_key, last_id) {
+            self.checkpoint.update(shard, key, id, log)?;
+        }
+
+        if cancelled {
+            return Ok(true);
+        }
+
+        tx.commit().await.map_err(|e| self.query_error(e))?;
+        info!(log, "finished scan of {}", name; "rows" => rows_read);
+
+        Ok(false)
+    }
+}
+
+// Read the shard, or only `unit` of it if it is given along with the probe it
+// was split off with.
+#[allow(clippy::too_many_arguments)]
+async fn scan_rows<T, R, F, Fut>(
+    shard: u32,
+    conf: &Config,
+    log: &Logger,
+    checkpoint: Checkpoint,
+    throttle: ShardThrottle,
+    unit: Option<(&ShardProbe, IdRange)>,
+    to_record: R,
+    handler: F,
+) -> Result<(), SharkspotterError>
+where
+    R: Fn(&Row) -> Result<T, SharkspotterError>,
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = Result<(), SharkspotterError>>,
+{
+    let mut client = connect_to_shard(shard, conf, log).await?;
+
+    // Only a checkpointed or split scan needs to know the shard's id column.
+    let parts = match unit {
+        Some((probe, unit)) => {
+            scan_parts(shard, conf, &checkpoint, probe, Some(unit))?
+        }
+        None if checkpoint.is_enabled() || conf.shard_splits > 1 => {
+            let probe = probe_with_client(&client, shard, log).await?;
+            scan_parts(shard, conf, &checkpoint, &probe, None)?
+        }
+        None => vec![ScanPart::whole_table()],
+    };
+
+    let scan = ShardScan {
+        shard,
+        conf,
+        log,
+        checkpoint: &checkpoint,
+        filter_sharks: if conf.db_shark_filter {
+            &conf.sharks
+        } else {
+            &[]
+        },
+        to_record,
+        handler: Mutex::new(handler),
+    };
+
+    let cancelled = match parts.as_slice() {
+        [] => false,
+        [part] => scan.scan_part(&mut client, part, None, throttle).await?,
+        parts => scan.scan_split(&mut client, parts, &throttle).await?,
     };
 
-    if let Err(e) = obj_tx.send(msg) {
-        warn!(log, "Tx channel disconnected: {}", e);
-        return Err(Error::new(ErrorKind::BrokenPipe, e));
+    if cancelled {
+        return Err(SharkspotterError::Cancelled);
     }
+
     Ok(())
 }
+
+// In test we noticed that the basic scheduler outperformed both the
+// `threaded_scheduler()` with tuned thread counts and the default thread
+// counts provided by `Runtime::new()` by 33%.  It also does not create any
+// additional LWPs.
+fn new_runtime() -> Result<Runtime, Error> {
+    tokio::runtime::Builder::new()
+        .enable_all()
+        .basic_scheduler()
+        .build()
+}
+
+/// Reads every object in a shard's manta table directly from its
+/// rebalancer-postgres database rather than through moray.
+pub struct DirectDbObjectSource {
+    shard: u32,
+    conf: Config,
+    checkpoint: Checkpoint,
+    throttle: ShardThrottle,
+
+    /// Shared with the units of work split off from this source.
+    probe: SharedProbe,
+    unit: Option<IdRange>,
+}
+
+impl DirectDbObjectSource {
+    pub fn new(
+        shard: u32,
+        conf: &Config,
+        checkpoint: &Checkpoint,
+        throttle: &ShardThrottle,
+    ) -> Self {
+        DirectDbObjectSource {
+            shard,
+            conf: conf.clone(),
+            checkpoint: checkpoint.clone(),
+            throttle: throttle.clone(),
+            probe: SharedProbe::default(),
+            unit: None,
+        }
+    }
+
+    /// A source for one unit of work of the shard.
+    fn with_unit(&self, unit: IdRange) -> Self {
+        DirectDbObjectSource {
+            shard: self.shard,
+            conf: self.conf.clone(),
+            checkpoint: self.checkpoint.clone(),
+            throttle: self.throttle.clone(),
+            probe: self.probe.clone(),
+            unit: Some(unit),
+        }
+    }
+
+    /// Probe the shard if none of its units has done so yet.
+    fn probe(&self, log: &Logger) -> Result<ShardProbe, SharkspotterError> {
+        self.probe.get_or_probe(|| {
+            let mut rt = new_runtime()?;
+            rt.block_on(probe_shard(self.shard, &self.conf, log))
+        })
+    }
+
+    /// The part of `range` in this source's unit of work, if it has one.
+    fn unit_range(&self, range: Option<IdRange>) -> Option<IdRange> {
+        let range = match (range?, self.unit) {
+            (range, Some(unit)) => IdRange {
+                begin: range.begin.max(unit.begin),
+                end: range.end.min(unit.end),
+            },
+            (range, None) => range,
+        };
+
+        if range.begin > range.end {
+            return None;
+        }
+
+        Some(range)
+    }
+
+    // The unit of work to read, if this source is one, along with the probe
+    // it was split off with.
+    fn scan_unit(&self) -> Option<(ShardProbe, IdRange)> {
+        let unit = self.unit?;
+        self.probe.get().map(|probe| (probe, unit))
+    }
+}
+
+impl ObjectSource for DirectDbObjectSource {
+    fn shard(&self) -> u32 {
+        self.shard
+    }
+
+    fn name(&self) -> String {
+        let unit = match self.unit {
+            Some(unit) => format!(" ids {}-{}", unit.begin, unit.end),
+            None => String::new(),
+        };
+
+        match shard_db(self.shard, &self.conf) {
+            Ok(db) => {
+                format!("shard {}{} via {}", self.shard, unit, db.endpoint())
+            }
+            Err(_) => format!("shard {}{} via postgres", self.shard, unit),
+        }
+    }
+
+    fn id_range(
+        &mut self,
+        log: &Logger,
+    ) -> Result<Option<IdRange>, SharkspotterError> {
+        let probe = self.probe(log)?;
+
+        Ok(self.unit_range(
+            probe
+                .primary_column()
+                .and_then(|id_name| probe.scan_range(id_name, &self.conf)),
+        ))
+    }
+
+    /// A shard split with `Config::shard_splits` is already read in parts
+    /// over connections of their own, and is left whole.  Otherwise the
+    /// primary id column is cut into units the same way as for a moray scan.
+    /// Each unit is read in a transaction of its own, so unlike the parts of
+    /// a split shard the units don't see the same snapshot of the table.
+    fn split_off(
+        &mut self,
+        log: &Logger,
+        max_ids: u64,
+    ) -> Result<Option<Box<dyn ObjectSource>>, SharkspotterError> {
+        if self.conf.shard_splits > 1 {
+            return Ok(None);
+        }
+
+        let probe = self.probe(log)?;
+        let id_name = match probe.primary_column() {
+            Some(id_name) => id_name,
+            None => return Ok(None),
+        };
+        let column_range =
+            probe.column(id_name).and_then(|column| column.range);
+        let mut range = match self.unit_range(column_range) {
+            Some(range) => range,
+            None => return Ok(None),
+        };
+
+        // Pick up where an earlier run over the whole shard left off before
+        // splitting it, as the moray source does.
+        if self.unit.is_none() {
+            if let Some(last_id) =
+                self.checkpoint.resume_point(self.shard, id_name)
+            {
+                range.begin = range.begin.max(last_id + 1);
+            }
+        }
+
+        let (unit, rest) = match range.split_unit(max_ids) {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+
+        let rest = self.with_unit(rest);
+        self.unit = Some(unit);
+
+        Ok(Some(Box::new(rest)))
+    }
+
+    fn scan(
+        &mut self,
+        log: &Logger,
+        handler: &mut dyn FnMut(&Value) -> Result<(), SharkspotterError>,
+    ) -> Result<(), SharkspotterError> {
+        let (shard, unit) = (self.shard, self.scan_unit());
+        let mut rt = new_runtime()?;
+        rt.block_on(scan_rows(
+            shard,
+            &self.conf,
+            log,
+            self.checkpoint.clone(),
+            self.throttle.clone(),
+            unit.as_ref().map(|(probe, unit)| (probe, *unit)),
+            |row| moray_obj_from_row(row, shard, log),
+            |obj| future::ready(handler(&obj)),
+        ))
+    }
+
+    fn scan_records(
+        &mut self,
+        log: &Logger,
+        handler: &mut dyn FnMut(SourceRecord) -> Result<(), SharkspotterError>,
+    ) -> Result<(), SharkspotterError> {
+        let (shard, unit) = (self.shard, self.scan_unit());
+        let sharks = &self.conf.sharks;
+        let mut rt = new_runtime()?;
+        rt.block_on(scan_rows(
+            shard,
+            &self.conf,
+            log,
+            self.checkpoint.clone(),
+            self.throttle.clone(),
+            unit.as_ref().map(|(probe, unit)| (probe, *unit)),
+            |row| record_from_row(row, shard, sharks, log),
+            |record| future::ready(handler(record)),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libmanta::moray::MantaObjectShark;
+    use serde_json::json;
+
+    // The _value of an object, about the size of one in production.
+    fn manta_value(i: u64, sharks: &[&str]) -> String {
+        let sharks: Vec<Value> = sharks
+            .iter()
+            .map(|s| json!({"datacenter": "dc0", "manta_storage_id": s}))
+            .collect();
+
+        json!({
+            "name": format!("obj{}", i),
+            "key": format!("/2e2d6d1c-7d8d-11ea-a2a1-d3bc7ec2bb8e/stor/obj{}", i),
+            "headers": {"m-custom": "value"},
+            "mtime": 1_586_900_000_000u64 + i,
+            "owner": "2e2d6d1c-7d8d-11ea-a2a1-d3bc7ec2bb8e",
+            "type": "object",
+            "objectId": format!("4ab3e1f0-7d8d-11ea-a2a1-{:012}", i),
+            "contentLength": 1024,
+            "contentMD5": "1B2M2Y8AsgTpgAmY7PhCfg==",
+            "contentType": "application/octet-stream",
+            "etag": format!("4ab3e1f0-7d8d-11ea-a2a1-{:012}", i),
+            "sharks": sharks,
+            "vnode": i % 1024,
+            "creator": "2e2d6d1c-7d8d-11ea-a2a1-d3bc7ec2bb8e",
+            "dirname": "/2e2d6d1c-7d8d-11ea-a2a1-d3bc7ec2bb8e/stor",
+        })
+        .to_string()
+    }
+
+    fn moray_fields(id: u64) -> Map<String, Value> {
+        let mut fields = Map::new();
+        fields.insert("_id".into(), id.into());
+        fields.insert("_etag".into(), "E".into());
+        fields
+    }
+
+    #[test]
+    fn shard_db_test() {
+        let mut conf = Config {
+            domain: String::from("test.joyent.us"),
+            ..Default::default()
+        };
+        conf.postgres.host = String::from("/var/run/postgresql");
+        conf.postgres.port = 5433;
+
+        let db = shard_db(2, &conf).expect("shard db");
+        assert_eq!(db.endpoint(), "/var/run/postgresql/.s.PGSQL.5433");
+        assert_eq!(db.user, "postgres");
+        assert_eq!(db.password, None);
+
+        // The connection string takes the place of the settings it has.
+        conf.postgres.connection_string = Some(String::from(
+            "host={shard}.pg.{domain} user=reader password=secret",
+        ));
+        let db = shard_db(2, &conf).expect("shard db");
+        assert_eq!(db.endpoint(), "2.pg.test.joyent.us:5433");
+        assert_eq!(db.user, "reader");
+        assert_eq!(db.dbname, "moray");
+        assert_eq!(db.password, Some(b"secret".to_vec()));
+
+        conf.postgres.connection_string = Some(String::from("port=x"));
+        assert!(shard_db(2, &conf).is_err());
+
+        // So does the sslmode, whichever form the connection string is in.
+        conf.postgres.connection_string = Some(String::from(
+            "postgresql://reader@{shard}.pg.{domain}/moray?sslmode=require",
+        ));
+        let db = shard_db(2, &conf).expect("shard db");
+        assert_eq!(db.endpoint(), "2.pg.test.joyent.us:5433");
+        assert_eq!(db.ssl_mode, SslMode::Require);
+
+        // Including the settings only libpq understands.
+        conf.postgres.connection_string = Some(String::from(
+            "host={shard}.pg.{domain} sslmode=verify-full \
+             sslrootcert='/opt/my certs/ca.pem' passfile=/tmp/pgpass \
+             application_name=scan connect_timeout=5",
+        ));
+        let db = shard_db(2, &conf).expect("shard db");
+        assert_eq!(db.ssl_mode, SslMode::VerifyFull);
+        assert_eq!(
+            db.ssl_root_cert.as_ref().map(String::as_str),
+            Some("/opt/my certs/ca.pem")
+        );
+        assert_eq!(
+            db.passfile.as_ref().map(String::as_str),
+            Some("/tmp/pgpass")
+        );
+        assert_eq!(db.application_name, "scan");
+        assert_eq!(db.connect_timeout, Some(Duration::from_secs(5)));
+
+        conf.postgres.connection_string =
+            Some(String::from("host=db sslmode=bogus"));
+        assert!(shard_db(2, &conf).is_err());
+    }
+
+    #[test]
+    fn take_libpq_settings_test() {
+        let (rest, taken) = take_libpq_settings(
+            "postgresql://reader@1.pg/moray?sslmode=verify-ca\
+             &sslrootcert=%2Fopt%2Fca.pem&application_name=scan",
+        )
+        .expect("url");
+        assert_eq!(
+            rest,
+            "postgresql://reader@1.pg/moray?application_name=scan"
+        );
+        assert_eq!(
+            taken,
+            vec![
+                (String::from("sslmode"), String::from("verify-ca")),
+                (String::from("sslrootcert"), String::from("/opt/ca.pem")),
+            ]
+        );
+
+        let (rest, taken) = take_libpq_settings(
+            "host=db passfile=/tmp/pgpass password='it\\'s  a secret'",
+        )
+        .expect("key/value");
+        assert_eq!(rest, "host='db' password='it\\'s  a secret'");
+        assert_eq!(
+            split_key_values(&rest).expect("split"),
+            vec![
+                (String::from("host"), String::from("db")),
+                (String::from("password"), String::from("it's  a secret")),
+            ]
+        );
+        assert_eq!(
+            taken,
+            vec![(String::from("passfile"), String::from("/tmp/pgpass"))]
+        );
+
+        assert!(take_libpq_settings("host='db").is_err());
+        assert!(take_libpq_settings("host").is_err());
+    }
+
+    #[test]
+    fn scan_parts_test() {
+        let _guard = crate::util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let path = std::env::temp_dir().join(format!(
+            "sharkspotter_scan_parts_{}.json",
+            std::process::id()
+        ));
+        let mut conf = Config {
+            checkpoint_file: Some(path.to_str().expect("path").to_string()),
+            ..Default::default()
+        };
+        let probe = ShardProbe {
+            shard: 1,
+            id_columns: vec![IdColumn {
+                name: String::from("_id"),
+                data_type: String::from("integer"),
+                range: Some(IdRange { begin: 1, end: 100 }),
+            }],
+        };
+        let part = |split, begin, end| ScanPart {
+            id_name: Some("_id"),
+            split,
+            unit: None,
+            begin,
+            end,
+            ordered: true,
+        };
+
+        // Without a checkpoint or splits the whole table is read at once.
+        let parts = scan_parts(1, &conf, &Checkpoint::default(), &probe, None)
+            .expect("parts");
+        assert_eq!(
+            parts,
+            vec![ScanPart {
+                ordered: false,
+                ..part(None, 0, None)
+            }]
+        );
+
+        let checkpoint = Checkpoint::from_config(&conf).expect("checkpoint");
+        checkpoint.update(1, "_id", 10, &log).expect("update");
+        let parts =
+            scan_parts(1, &conf, &checkpoint, &probe, None).expect("parts");
+        assert_eq!(parts, vec![part(None, 11, None)]);
+
+        // A resumed split scan skips the ids and parts that are done.
+        conf.shard_splits = 4;
+        let parts =
+            scan_parts(1, &conf, &checkpoint, &probe, None).expect("parts");
+        assert_eq!(
+            parts,
+            vec![
+                part(Some(0), 1, Some(26)),
+                part(Some(1), 26, Some(51)),
+                part(Some(2), 51, Some(76)),
+                part(Some(3), 76, None),
+            ]
+        );
+        checkpoint.update(1, "_id#1", 50, &log).expect("update");
+        checkpoint.update(1, "_id#2", 60, &log).expect("update");
+        let parts =
+            scan_parts(1, &conf, &checkpoint, &probe, None).expect("parts");
+        assert_eq!(
+            parts,
+            vec![
+                part(Some(0), 1, Some(26)),
+                part(Some(2), 61, Some(76)),
+                part(Some(3), 76, None),
+            ]
+        );
+
+        conf.shard_splits = 3;
+        assert!(scan_parts(1, &conf, &checkpoint, &probe, None).is_err());
+
+        // A unit of work is a single part, checkpointed on its own.
+        let unit = IdRange { begin: 20, end: 29 };
+        checkpoint.update(1, "_id@20", 24, &log).expect("update");
+        let parts = scan_parts(1, &conf, &checkpoint, &probe, Some(unit))
+            .expect("parts");
+        let unit_part = ScanPart {
+            unit: Some(20),
+            ..part(None, 25, Some(30))
+        };
+        assert_eq!(parts, vec![unit_part.clone()]);
+        assert_eq!(unit_part.checkpoint_key(), Some(String::from("_id@20")));
+        assert_eq!(unit_part.name(1, 3), "shard 1 _id ids 20-29");
+        checkpoint.update(1, "_id@20", 29, &log).expect("update");
+        assert!(scan_parts(1, &conf, &checkpoint, &probe, Some(unit))
+            .expect("parts")
+            .is_empty());
+
+        let empty = ShardProbe {
+            shard: 1,
+            id_columns: vec![],
+        };
+        assert!(scan_parts(1, &conf, &checkpoint, &empty, None).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn objects_query_test() {
+        assert_eq!(
+            objects_query(&ScanPart::whole_table(), &[]),
+            "SELECT * FROM manta WHERE type = 'object'"
+        );
+
+        let mut part = ScanPart {
+            id_name: Some("_id"),
+            split: None,
+            unit: None,
+            begin: 11,
+            end: None,
+            ordered: true,
+        };
+        assert_eq!(
+            objects_query(&part, &[]),
+            "SELECT * FROM manta WHERE type = 'object' AND _id >= 11 \
+             ORDER BY _id"
+        );
+
+        part.split = Some(1);
+        part.end = Some(20);
+        part.ordered = false;
+        assert_eq!(
+            objects_query(&part, &[]),
+            "SELECT * FROM manta WHERE type = 'object' AND _id >= 11 \
+             AND _id < 20"
+        );
+
+        let sharks = vec![String::from("1.stor"), String::from("a_b%'.stor")];
+        part.id_name = Some("_idx");
+        part.begin = 0;
+        part.end = None;
+        part.ordered = true;
+        assert_eq!(
+            objects_query(&part, &sharks),
+            "SELECT * FROM manta WHERE type = 'object' AND \
+             (_value LIKE '%\"1.stor\"%' OR _value LIKE '%\"a\\_b\\%''.stor\"%') \
+             AND _idx >= 0 ORDER BY _idx"
+        );
+    }
+
+    #[test]
+    fn split_off_test() {
+        let _guard = crate::util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let conf = Config::default();
+        let checkpoint = Checkpoint::default();
+        let throttle = crate::throttle::Throttle::new(&conf).shard(1);
+        let mut source =
+            DirectDbObjectSource::new(1, &conf, &checkpoint, &throttle);
+
+        // There's no database to probe, so the source has to use the probe
+        // that is already there.
+        source
+            .probe
+            .get_or_probe(|| {
+                Ok(ShardProbe {
+                    shard: 1,
+                    id_columns: vec![IdColumn {
+                        name: String::from("_id"),
+                        data_type: String::from("integer"),
+                        range: Some(IdRange { begin: 5, end: 25 }),
+                    }],
+                })
+            })
+            .expect("probe");
+
+        let mut rest = source.split_off(&log, 10).unwrap().expect("split");
+        assert_eq!(
+            source.id_range(&log).unwrap(),
+            Some(IdRange { begin: 5, end: 9 })
+        );
+        assert_eq!(
+            rest.id_range(&log).unwrap(),
+            Some(IdRange { begin: 10, end: 25 })
+        );
+        assert!(rest.name().starts_with("shard 1 ids 10-25 via "));
+        assert!(rest.split_off(&log, 20).unwrap().is_some());
+        assert!(rest.split_off(&log, 20).unwrap().is_none());
+
+        // A split shard is already read in parts of its own, and isn't even
+        // probed.
+        let conf = Config {
+            shard_splits: 2,
+            ..Default::default()
+        };
+        let mut source =
+            DirectDbObjectSource::new(1, &conf, &checkpoint, &throttle);
+        assert!(source.split_off(&log, 10).unwrap().is_none());
+    }
+
+    #[test]
+    fn record_from_value_test() {
+        let _guard = crate::util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let conf = Config {
+            sharks: vec![String::from("1.stor"), String::from("3.stor")],
+            ..Default::default()
+        };
+
+        let value = manta_value(1, &["2.stor", "3.stor"]);
+        assert!(may_be_on_sharks(&value, &conf.sharks));
+        assert!(!may_be_on_sharks(
+            &manta_value(2, &["2.stor", "4.stor"]),
+            &conf.sharks
+        ));
+
+        let mut found = vec![];
+        let mut handle = |record| {
+            crate::handle_record(
+                &log,
+                record,
+                1,
+                None,
+                &conf,
+                &mut |record| {
+                    found.push((record.shark, record.id));
+                    Ok(())
+                },
+                &mut Err,
+            )
+        };
+
+        handle(parsed_record(moray_fields(5), &value, 1)).expect("record");
+
+        match handle(parsed_record(moray_fields(6), "{not json", 1)) {
+            Err(SharkspotterError::MalformedRecord { shard, id, .. }) => {
+                assert_eq!((shard, id), (1, Some(6)));
+            }
+            ret => panic!("unexpected result {:?}", ret),
+        }
+        assert_eq!(found, vec![(String::from("3.stor"), Some(5))]);
+    }
+
+    // Compare the rate at which rows are checked by the direct-DB path
+    // (`record_from_value()`, which is `record_from_row()` once the _value
+    // has been borrowed from the row) with handing each row over as a moray
+    // object, and with the direct-DB pipeline it replaced.  Run with:
+    //
+    //     cargo test --release --lib directdb_bench -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn directdb_bench() {
+        let _guard = crate::util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let conf = Config {
+            sharks: vec![String::from("7.stor")],
+            ..Default::default()
+        };
+        let rows = 500_000;
+
+        // A shard where one in every 50 objects is on the requested shark.
+        let values: Vec<String> = (0..rows)
+            .map(|i| {
+                let first = format!("{}.stor", i % 50 + 1);
+                let second = format!("{}.stor", (i + 25) % 50 + 51);
+                manta_value(i, &[first.as_str(), second.as_str()])
+            })
+            .collect();
+
+        let run = |to_record: &dyn Fn(u64, &str) -> u64| {
+            let start = Instant::now();
+            let matched: u64 = values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| to_record(i as u64, value))
+                .sum();
+            let secs = start.elapsed().as_secs_f64();
+            (matched, rows as f64 / secs)
+        };
+        let count = |record: SourceRecord| {
+            let mut matched = 0;
+            crate::handle_record(
+                &log,
+                record,
+                1,
+                None,
+                &conf,
+                &mut |_| {
+                    matched += 1;
+                    Ok(())
+                },
+                &mut Err,
+            )
+            .expect("record");
+            matched
+        };
+
+        // The old pipeline: serde_postgres copied the _value and _etag out
+        // of each row into a moray object, the _value was copied again to
+        // parse it, the sharks were copied to deserialize them, and the
+        // objects were copied once for each matching shark.
+        let (old_matched, old_rate) = run(&|id, value| {
+            let moray_value = json!({
+                "_id": id,
+                "_etag": String::from("E"),
+                "_value": value.to_string(),
+            });
+            let value = moray_value["_value"].clone();
+            let manta_value: Value =
+                serde_json::from_str(value.as_str().expect("_value"))
+                    .expect("manta value");
+            let sharks: Vec<MantaObjectShark> =
+                serde_json::from_value(manta_value["sharks"].clone())
+                    .expect("sharks");
+
+            let mut records = vec![];
+            for shark in sharks.iter() {
+                if conf.sharks.contains(&shark.manta_storage_id) {
+                    records.push((moray_value.clone(), manta_value.clone()));
+                }
+            }
+            records.len() as u64
+        });
+        let (moray_matched, moray_rate) = run(&|id, value| {
+            let mut fields = moray_fields(id);
+            fields.insert("_value".into(), value.to_string().into());
+            count(SourceRecord::Moray(&Value::Object(fields)))
+        });
+        let (fast_matched, fast_rate) = run(&|id, value| {
+            let record = record_from_value(value, 1, &conf.sharks, || {
+                Ok(moray_fields(id))
+            })
+            .expect("record");
+            count(record)
+        });
+
+        assert_eq!(old_matched, rows / 50);
+        assert_eq!(moray_matched, old_matched);
+        assert_eq!(fast_matched, old_matched);
+        println!(
+            "old direct-DB: {:.0} rows/s, moray objects: {:.0} rows/s, \
+             direct-DB: {:.0} rows/s ({:.1}x the old direct-DB)",
+            old_rate,
+            moray_rate,
+            fast_rate,
+            fast_rate / old_rate
+        );
+    }
+}
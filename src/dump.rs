@@ -0,0 +1,366 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+// Manatee backups of the moray `manta` table are uploaded to
+// /poseidon/stor/manatee_backups/<shard>.moray.<domain>/... as gzipped files
+// of newline separated json.  The first line describes the columns of the
+// table, and each line after that is a single row with every column rendered
+// as a string (or null):
+//
+// {"name":"manta","keys":["_id","_txn_snap","_key","_value","_etag",...]}
+// {"entry":["114590",null,"/6136.../07e023da.log","{\"contentLength\":...}",
+//     "7712D647",...]}
+//
+// Each entry is zipped up with the keys to recreate the same moray object we
+// get back from the `sql` endpoint, so that the rows can be handed to the same
+// filtering as the moray and direct-DB sources.  Files that already contain one
+// moray object per line (e.g. saved `sql` output) are accepted as well.
+
+use flate2::read::GzDecoder;
+use serde_json::{self, Map, Value};
+use slog::{debug, Logger};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error};
+use std::path::Path;
+
+use crate::cancel::CancelToken;
+use crate::config::Config;
+use crate::error::SharkspotterError;
+use crate::source::{ObjectSource, SourceRecord};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Find the shard number in a manatee backup path, which contains a
+/// component of the form "<shard>.moray.<domain>".
+pub fn shard_from_path(path: &str) -> Option<u32> {
+    Path::new(path).components().find_map(|c| {
+        let comp = c.as_os_str().to_str()?;
+        let dot = comp.find(".moray.")?;
+        comp[..dot].parse().ok()
+    })
+}
+
+/// Pair each configured dump file with the shard it was taken from.  Every
+/// shard must fall within the configured shard range, which on the command
+/// line defaults to the shards of the dump files.
+pub fn dump_files_by_shard(
+    conf: &Config,
+) -> Result<Vec<(u32, String)>, SharkspotterError> {
+    conf.dump_files
+        .iter()
+        .map(|path| {
+            let shard = shard_from_path(path).ok_or_else(|| {
+                let msg = format!(
+                    "Could not find '<shard>.moray.<domain>' in dump file \
+                     path '{}'",
+                    path
+                );
+                SharkspotterError::InvalidConfig(msg)
+            })?;
+
+            if shard < conf.min_shard || shard > conf.max_shard {
+                let msg = format!(
+                    "Dump file '{}' is for shard {} which is outside of the \
+                     shard range {}-{}",
+                    path, shard, conf.min_shard, conf.max_shard
+                );
+                return Err(SharkspotterError::InvalidConfig(msg));
+            }
+
+            Ok((shard, path.to_string()))
+        })
+        .collect()
+}
+
+fn open_dump(path: &str) -> Result<Box<dyn BufRead>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(GzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Turn a single line of a dump file into a moray object.  Returns None for
+/// the header line, which instead updates the keys used for the lines that
+/// follow it.
+fn moray_obj_from_line(
+    line: &str,
+    keys: &mut Option<Vec<String>>,
+) -> Result<Option<Value>, String> {
+    let mut val: Value = serde_json::from_str(line)
+        .map_err(|e| format!("Could not parse line as json ({})", e))?;
+
+    if let Some(k) = val.get("keys") {
+        let new_keys: Vec<String> = serde_json::from_value(k.clone())
+            .map_err(|e| format!("Could not parse dump header ({})", e))?;
+        *keys = Some(new_keys);
+        return Ok(None);
+    }
+
+    let entry = match val.get_mut("entry") {
+        Some(Value::Array(entry)) => entry,
+        Some(e) => return Err(format!("Entry is not an array {:#?}", e)),
+        None => {
+            // Already in the form of a moray object.
+            return Ok(Some(val));
+        }
+    };
+
+    let keys = keys
+        .as_ref()
+        .ok_or_else(|| String::from("Found entry before dump header"))?;
+
+    if keys.len() != entry.len() {
+        return Err(format!(
+            "Entry has {} columns, header has {}",
+            entry.len(),
+            keys.len()
+        ));
+    }
+
+    let moray_obj: Map<String, Value> =
+        keys.iter().cloned().zip(entry.drain(..)).collect();
+
+    Ok(Some(Value::Object(moray_obj)))
+}
+
+/// Reads every row of a dump file taken from a single shard.
+pub struct DumpObjectSource {
+    shard: u32,
+    path: String,
+    cancel: CancelToken,
+}
+
+impl DumpObjectSource {
+    pub fn new(shard: u32, path: String, cancel: &CancelToken) -> Self {
+        DumpObjectSource {
+            shard,
+            path,
+            cancel: cancel.clone(),
+        }
+    }
+
+    /// Hand each line of the dump to the handler as a moray object, or as
+    /// the error turning it into one, so that the caller can decide whether
+    /// a malformed line ends the scan.
+    fn scan_lines(
+        &mut self,
+        log: &Logger,
+        handler: &mut dyn FnMut(
+            Result<Value, SharkspotterError>,
+        ) -> Result<(), SharkspotterError>,
+    ) -> Result<(), SharkspotterError> {
+        debug!(
+            log,
+            "Scanning dump file {} for shard {}", self.path, self.shard
+        );
+
+        let reader = open_dump(&self.path)?;
+        let mut keys = None;
+
+        for (i, line) in reader.lines().enumerate() {
+            self.cancel.check()?;
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match moray_obj_from_line(&line, &mut keys) {
+                Ok(Some(moray_obj)) => handler(Ok(moray_obj))?,
+                Ok(None) => continue,
+                Err(e) => handler(Err(SharkspotterError::MalformedRecord {
+                    shard: self.shard,
+                    id: None,
+                    cause: format!("{}:{}: {}", self.path, i + 1, e),
+                }))?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ObjectSource for DumpObjectSource {
+    fn shard(&self) -> u32 {
+        self.shard
+    }
+
+    fn name(&self) -> String {
+        format!("shard {} dump {}", self.shard, self.path)
+    }
+
+    fn scan(
+        &mut self,
+        log: &Logger,
+        handler: &mut dyn FnMut(&Value) -> Result<(), SharkspotterError>,
+    ) -> Result<(), SharkspotterError> {
+        self.scan_lines(log, &mut |moray_obj| handler(&moray_obj?))
+    }
+
+    /// A malformed line is handed over as a record error, which the caller's
+    /// record error handler decides what to do with.
+    fn scan_records(
+        &mut self,
+        log: &Logger,
+        handler: &mut dyn FnMut(SourceRecord) -> Result<(), SharkspotterError>,
+    ) -> Result<(), SharkspotterError> {
+        self.scan_lines(log, &mut |moray_obj| match moray_obj {
+            Ok(moray_obj) => handler(SourceRecord::Moray(&moray_obj)),
+            Err(e) => handler(SourceRecord::Parsed {
+                moray_value: Value::Object(Map::new()),
+                manta_value: Err(e),
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use serde_json::json;
+    use std::io::Write;
+
+    #[test]
+    fn shard_from_path_test() {
+        assert_eq!(
+            shard_from_path(
+                "/poseidon/stor/manatee_backups/12.moray.east.joyent.us/\
+                 2019/10/09/00/manta-2019-10-09-00-00-00.gz"
+            ),
+            Some(12)
+        );
+        assert_eq!(shard_from_path("./1.moray.east.joyent.us.gz"), Some(1));
+        assert_eq!(shard_from_path("/var/tmp/manta.gz"), None);
+        assert_eq!(shard_from_path("/var/tmp/x.moray.east/manta.gz"), None);
+    }
+
+    #[test]
+    fn dump_object_source_test() {
+        let _guard = crate::util::init_global_logger(None);
+        let log = slog_scope::logger();
+
+        let object = |id: &str, sharks: &[&str]| {
+            let sharks: Vec<Value> = sharks
+                .iter()
+                .map(|s| {
+                    json!({
+                        "datacenter": "dc0",
+                        "manta_storage_id": s,
+                    })
+                })
+                .collect();
+            json!({
+                "objectId": id,
+                "type": "object",
+                "sharks": sharks,
+            })
+            .to_string()
+        };
+        let directory = json!({"type": "directory"}).to_string();
+
+        let rows = [
+            json!({"name": "manta", "keys": ["_id", "_value", "_etag"]}),
+            json!({"entry": ["1", object("a", &["1.st", "2.st"]), "A"]}),
+            json!({"entry": ["2", directory, "B"]}),
+            json!({"entry": ["3", object("c", &["3.st"]), "C"]}),
+            json!({"entry": ["4", object("d", &["2.st"]), "D"]}),
+        ];
+
+        let path = std::env::temp_dir()
+            .join(format!("sharkspotter_dump_{}.gz", std::process::id()));
+        let path_str = path.to_str().expect("path").to_string();
+
+        let mut encoder = GzEncoder::new(
+            File::create(&path).expect("create dump"),
+            Compression::default(),
+        );
+        for row in rows.iter() {
+            writeln!(encoder, "{}", row).expect("write dump");
+        }
+        encoder.finish().expect("finish dump");
+
+        let conf = Config {
+            sharks: vec![String::from("2.st")],
+            ..Default::default()
+        };
+        let sources: Vec<Box<dyn ObjectSource>> = vec![Box::new(
+            DumpObjectSource::new(7, path_str.clone(), &conf.cancel),
+        )];
+        let mut found = vec![];
+        let report = crate::run_sources(
+            sources,
+            &conf,
+            log.clone(),
+            |record| {
+                assert_eq!(record.shark, "2.st");
+                assert_eq!(record.shard, 7);
+                found.push((
+                    record.manta_value["objectId"]
+                        .as_str()
+                        .unwrap()
+                        .to_string(),
+                    record.etag,
+                ));
+                Ok(())
+            },
+            Err,
+        );
+        assert!(report.is_complete());
+        assert_eq!(report.scanned(), 4);
+
+        assert_eq!(
+            found,
+            vec![
+                (String::from("a"), String::from("A")),
+                (String::from("d"), String::from("D")),
+            ]
+        );
+
+        // A malformed line goes to the record error handler, and the scan
+        // carries on past it if that lets it.
+        let mut file = File::create(&path).expect("create dump");
+        for (i, row) in rows.iter().enumerate() {
+            if i == 2 {
+                writeln!(file, "{{\"entry\": [").expect("write dump");
+            }
+            writeln!(file, "{}", row).expect("write dump");
+        }
+        drop(file);
+
+        let sources: Vec<Box<dyn ObjectSource>> =
+            vec![Box::new(DumpObjectSource::new(7, path_str, &conf.cancel))];
+        let mut matches = 0;
+        let mut errors = vec![];
+        let report = crate::run_sources(
+            sources,
+            &conf,
+            log,
+            |_| {
+                matches += 1;
+                Ok(())
+            },
+            |e| {
+                errors.push(e.to_string());
+                Ok(())
+            },
+        );
+        assert!(report.is_complete());
+        assert_eq!(matches, 2);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains(":3: Could not parse line as json"));
+
+        std::fs::remove_file(&path).expect("remove dump");
+    }
+}
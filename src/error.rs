@@ -0,0 +1,228 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+#[derive(Debug)]
+pub enum SharkspotterError {
+    /// A moray or postgres host name could not be resolved.
+    Dns { host: String, cause: String },
+
+    /// A connection to moray or postgres could not be established.
+    Connect { endpoint: String, cause: String },
+
+    /// A query against a shard's moray or postgres failed.
+    Query { shard: u32, cause: String },
+
+    /// The connection to a shard's moray, or moray's connection to its
+    /// database, was lost part way through a query.
+    Disconnected { shard: u32, cause: String },
+
+    /// A query took longer than the timeout it was given.
+    QueryTimeout { shard: u32, cause: String },
+
+    /// A record was read but could not be parsed.
+    MalformedRecord {
+        shard: u32,
+        id: Option<u64>,
+        cause: String,
+    },
+
+    /// A record's manta object metadata has no usable "sharks" array.
+    MissingSharks {
+        shard: u32,
+        id: Option<u64>,
+        cause: String,
+    },
+
+    /// One of the requested sharks could not be validated.
+    InvalidShark(String),
+
+    /// The configuration can not be used as given.
+    InvalidConfig(String),
+
+    /// The receiving end of the channel has been dropped.
+    ChannelClosed,
+
+    /// The scan was cancelled (see `cancel::CancelToken`).
+    Cancelled,
+
+    /// One or more sources failed during a multithreaded run.
+    Scan(Vec<SharkspotterError>),
+
+    /// Any other I/O error, including those returned by the caller's
+    /// handler.
+    Io(Error),
+}
+
+impl SharkspotterError {
+    /// True for errors that may go away if the operation is tried again,
+    /// such as a moray that is restarting.  Any other query error, e.g. bad
+    /// SQL or a missing permission, would only fail again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SharkspotterError::Connect { .. }
+            | SharkspotterError::Disconnected { .. }
+            | SharkspotterError::QueryTimeout { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Errors that only affect a single record.  These are passed to the
+    /// caller's error handler, which decides whether or not the scan should
+    /// continue.
+    pub fn is_record_error(&self) -> bool {
+        match self {
+            SharkspotterError::MalformedRecord { .. }
+            | SharkspotterError::MissingSharks { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for SharkspotterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let id_str = |id: &Option<u64>| match id {
+            Some(id) => format!(" _id {}", id),
+            None => String::new(),
+        };
+
+        match self {
+            SharkspotterError::Dns { host, cause } => {
+                write!(f, "could not resolve {}: {}", host, cause)
+            }
+            SharkspotterError::Connect { endpoint, cause } => {
+                write!(f, "could not connect to {}: {}", endpoint, cause)
+            }
+            SharkspotterError::Query { shard, cause } => {
+                write!(f, "query error on shard {}: {}", shard, cause)
+            }
+            SharkspotterError::Disconnected { shard, cause } => {
+                write!(f, "lost connection to shard {}: {}", shard, cause)
+            }
+            SharkspotterError::QueryTimeout { shard, cause } => {
+                write!(f, "query timed out on shard {}: {}", shard, cause)
+            }
+            SharkspotterError::MalformedRecord { shard, id, cause } => write!(
+                f,
+                "malformed record on shard {}{}: {}",
+                shard,
+                id_str(id),
+                cause
+            ),
+            SharkspotterError::MissingSharks { shard, id, cause } => write!(
+                f,
+                "missing sharks on shard {}{}: {}",
+                shard,
+                id_str(id),
+                cause
+            ),
+            SharkspotterError::InvalidShark(msg) => write!(f, "{}", msg),
+            SharkspotterError::InvalidConfig(msg) => write!(f, "{}", msg),
+            SharkspotterError::ChannelClosed => write!(f, "channel closed"),
+            SharkspotterError::Cancelled => write!(f, "scan cancelled"),
+            SharkspotterError::Scan(errors) => {
+                writeln!(f, "Sharkspotter encountered the following errors:")?;
+                for error in errors.iter() {
+                    writeln!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+            SharkspotterError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SharkspotterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SharkspotterError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// An io::Error that was created from a SharkspotterError (see below) is
+/// unwrapped back into the original error.
+impl From<Error> for SharkspotterError {
+    fn from(error: Error) -> Self {
+        let is_wrapped = error
+            .get_ref()
+            .map(|inner| inner.is::<SharkspotterError>())
+            .unwrap_or(false);
+
+        if is_wrapped {
+            let inner = error.into_inner().expect("wrapped error");
+            return *inner
+                .downcast::<SharkspotterError>()
+                .expect("wrapped SharkspotterError");
+        }
+
+        SharkspotterError::Io(error)
+    }
+}
+
+/// For callers that still deal in io::Errors.  The original error can be
+/// recovered with `SharkspotterError::from()`.
+impl From<SharkspotterError> for Error {
+    fn from(error: SharkspotterError) -> Self {
+        match error {
+            SharkspotterError::Io(e) => e,
+            SharkspotterError::ChannelClosed => {
+                Error::new(ErrorKind::BrokenPipe, error)
+            }
+            SharkspotterError::Cancelled => {
+                Error::new(ErrorKind::Interrupted, error)
+            }
+            _ => Error::new(ErrorKind::Other, error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_error_roundtrip() {
+        let err = SharkspotterError::MissingSharks {
+            shard: 2,
+            id: Some(1234),
+            cause: String::from("no sharks"),
+        };
+        assert!(err.is_record_error());
+        assert_eq!(
+            err.to_string(),
+            "missing sharks on shard 2 _id 1234: no sharks"
+        );
+
+        let io_err = Error::from(err);
+        assert_eq!(io_err.kind(), ErrorKind::Other);
+        match SharkspotterError::from(io_err) {
+            SharkspotterError::MissingSharks { shard, id, .. } => {
+                assert_eq!(shard, 2);
+                assert_eq!(id, Some(1234));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let io_err = Error::from(SharkspotterError::ChannelClosed);
+        assert_eq!(io_err.kind(), ErrorKind::BrokenPipe);
+
+        let io_err = Error::new(ErrorKind::NotFound, "no such file");
+        match SharkspotterError::from(io_err) {
+            SharkspotterError::Io(e) => {
+                assert_eq!(e.kind(), ErrorKind::NotFound)
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
@@ -46,41 +46,73 @@
 //   }
 // }
 
+pub mod cancel;
+pub mod checkpoint;
+pub mod chunk;
 pub mod config;
 pub mod directdb;
+pub mod dump;
+pub mod error;
+pub mod pgpass;
+pub mod probe;
+pub mod record;
+pub mod report;
+pub mod retry;
+pub mod scanner;
+pub mod scheduler;
+pub mod source;
+pub mod stream;
+pub mod throttle;
 pub mod util;
 
-use lazy_static::lazy_static;
+use checkpoint::Checkpoint;
+use chunk::ChunkSizer;
+use error::SharkspotterError;
 use libmanta::moray::MantaObjectShark;
 use moray::client::MorayClient;
 use moray::objects as moray_objects;
+use probe::{
+    id_range_from_values, id_range_query, IdColumn, ShardProbe, SharedProbe,
+    ID_COLUMNS, ID_COLUMNS_QUERY,
+};
+use record::{MantaObject, MatchRecord};
+use report::{RunReport, ShardReport, ShardStatus};
+use retry::Backoff;
+use scheduler::WorkQueue;
 use serde::Deserialize;
 use serde_json::{self, Value};
-use slog::{debug, error, warn, Logger};
+use slog::{debug, error, info, warn, Logger};
+use source::{IdRange, ObjectSource, SourceRecord};
+use std::collections::HashSet;
 use std::io::{Error, ErrorKind};
-use std::net::IpAddr;
-use std::sync::Mutex;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use threadpool::ThreadPool;
+use throttle::{ShardThrottle, Throttle};
+use trust_dns_resolver::config::{
+    NameServerConfigGroup, ResolverConfig, ResolverOpts,
+};
 use trust_dns_resolver::Resolver;
 
-lazy_static! {
-    static ref ERROR_LIST: Mutex<Vec<std::io::Error>> = Mutex::new(vec![]);
-}
-
 #[derive(Deserialize, Debug, Clone)]
 struct IdRet {
     max: String,
 }
 
-#[derive(Debug)]
-pub struct SharkspotterMessage {
-    pub manta_value: Value,
-    pub etag: String,
-    pub shark: String,
-    pub shard: u32,
-}
+/// What the multithreaded runs send back for each match.  A `MatchRecord`
+/// has the same `manta_value`, `etag`, `shark` and `shard` fields that this
+/// used to have on its own.
+pub type SharkspotterMessage = MatchRecord;
 
 fn _parse_max_id_value(val: Value, log: &Logger) -> Result<u64, Error> {
+    parse_sql_u64(val, "max", log)
+}
+
+/// Parse the single numeric column of a one row moray `sql` response, such
+/// as that of a MAX() or count().
+fn parse_sql_u64(val: Value, key: &str, log: &Logger) -> Result<u64, Error> {
     if val.is_array() {
         let val_arr = val.as_array().unwrap();
 
@@ -94,19 +126,19 @@ fn _parse_max_id_value(val: Value, log: &Logger) -> Result<u64, Error> {
         return Err(Error::new(ErrorKind::Other, "Expected array"));
     }
 
-    let max = match val[0].get("max") {
+    let max = match val[0].get(key) {
         Some(m) => m.to_owned(),
         None => {
             return Err(Error::new(
                 ErrorKind::Other,
-                "Query missing 'max' value",
+                format!("Query missing '{}' value", key),
             ));
         }
     };
 
     let max_num: u64 = match max {
         Value::Number(n) => {
-            debug!(log, "Parsing largest id value as Number");
+            debug!(log, "Parsing '{}' value as Number", key);
             match n.as_u64() {
                 Some(num_64) => num_64,
                 None => {
@@ -118,21 +150,21 @@ fn _parse_max_id_value(val: Value, log: &Logger) -> Result<u64, Error> {
             }
         }
         Value::String(s) => {
-            debug!(log, "Parsing largest id value as String");
+            debug!(log, "Parsing '{}' value as String", key);
             match s.parse() {
                 Ok(snum) => snum,
                 Err(e) => {
                     let msg =
-                        format!("Error parsing max value as String: {}", e);
+                        format!("Error parsing {} value as String: {}", key, e);
                     return Err(Error::new(ErrorKind::Other, msg));
                 }
             }
         }
         _ => {
-            debug!(log, "largest id value is unknown variant {:#?}", max);
+            debug!(log, "'{}' value is unknown variant {:#?}", key, max);
             return Err(Error::new(
                 ErrorKind::Other,
-                "Error max value was not a string or a number",
+                format!("Error {} value was not a string or a number", key),
             ));
         }
     };
@@ -140,41 +172,66 @@ fn _parse_max_id_value(val: Value, log: &Logger) -> Result<u64, Error> {
     Ok(max_num)
 }
 
-/// Find the largest _id/_idx in the database.
-fn find_largest_id_value(
+/// Find which id columns a shard's manta table has, and the range of values
+/// in each.
+fn probe_moray_shard(
     log: &Logger,
     mclient: &mut MorayClient,
-    id: &str,
-) -> Result<u64, Error> {
-    let mut ret: u64 = 0;
-    debug!(log, "Finding largest ID value as '{}'", id);
-    mclient.sql(
-        format!("SELECT MAX({}) FROM manta;", id).as_str(),
-        vec![],
-        r#"{"limit": 1, "no_count": true}"#,
-        |resp| {
-            // The expected response is:
-            //  [{
-            //      "max": <value>
-            //  }]
-            //
-            //  Where <value> is either a String or a Number.
-
-            ret = match _parse_max_id_value(resp.to_owned(), log) {
-                Ok(max_num) => max_num,
-                Err(e) => {
-                    return Err(e);
+    shard: u32,
+) -> Result<ShardProbe, SharkspotterError> {
+    let query_error = |e: Error| moray_query_error(shard, e);
+    let row_error = |cause: String| Error::new(ErrorKind::Other, cause);
+
+    let mut columns: Vec<(String, String)> = vec![];
+    mclient
+        .sql(ID_COLUMNS_QUERY, vec![], r#"{"no_count": true}"#, |resp| {
+            let row = moray_obj_from_sql_resp(log, resp, shard)?;
+            match (
+                row.get("column_name").and_then(Value::as_str),
+                row.get("data_type").and_then(Value::as_str),
+            ) {
+                (Some(name), Some(data_type)) => {
+                    columns.push((name.to_string(), data_type.to_string()));
+                    Ok(())
                 }
-            };
-            Ok(())
-        },
-    )?;
-    Ok(ret)
-}
+                _ => Err(row_error(format!("Unexpected column {:#?}", row))),
+            }
+        })
+        .map_err(query_error)?;
+
+    let mut id_columns = vec![];
+    for id in ID_COLUMNS.iter() {
+        let data_type = match columns.iter().find(|(name, _)| name == id) {
+            Some((_, data_type)) => data_type.clone(),
+            None => continue,
+        };
+
+        let mut range = None;
+        mclient
+            .sql(
+                id_range_query(id).as_str(),
+                vec![],
+                r#"{"limit": 1, "no_count": true}"#,
+                |resp| {
+                    let row = moray_obj_from_sql_resp(log, resp, shard)?;
+                    range = id_range_from_values(&row["min"], &row["max"])
+                        .map_err(row_error)?;
+                    Ok(())
+                },
+            )
+            .map_err(query_error)?;
+
+        id_columns.push(IdColumn {
+            name: id.to_string(),
+            data_type,
+            range,
+        });
+    }
+
+    let probe = ShardProbe { shard, id_columns };
+    info!(log, "shard probe"; "shard" => shard, "id_columns" => probe.summary());
 
-fn _log_return_error(log: &Logger, msg: &str) -> Result<(), Error> {
-    error!(log, "{}", msg);
-    Err(Error::new(ErrorKind::Other, msg))
+    Ok(probe)
 }
 
 pub fn get_sharks_from_manta_obj(
@@ -189,16 +246,14 @@ pub fn get_sharks_from_manta_obj(
                 return Err(Error::new(ErrorKind::Other, msg));
             }
 
-            serde_json::from_value::<Vec<MantaObjectShark>>(s.clone()).map_err(
-                |e| {
-                    let msg = format!(
-                        "Could not deserialize sharks value {:#?}. ({})",
-                        s, e
-                    );
-                    error!(log, "{}", msg);
-                    Error::new(ErrorKind::Other, msg)
-                },
-            )
+            Vec::<MantaObjectShark>::deserialize(s).map_err(|e| {
+                let msg = format!(
+                    "Could not deserialize sharks value {:#?}. ({})",
+                    s, e
+                );
+                error!(log, "{}", msg);
+                Error::new(ErrorKind::Other, msg)
+            })
         }
         None => {
             let msg = format!("Missing 'sharks' field {:#?}", value);
@@ -214,8 +269,7 @@ pub fn get_sharks_from_manta_obj(
 pub fn manta_obj_from_moray_obj(moray_obj: &Value) -> Result<Value, String> {
     match moray_obj.get("_value") {
         Some(val) => {
-            let val_clone = val.clone();
-            let str_val = match val_clone.as_str() {
+            let str_val = match val.as_str() {
                 Some(s) => s,
                 None => {
                     return Err(format!(
@@ -269,181 +323,672 @@ pub fn etag_from_moray_value(moray_value: &Value) -> Result<String, Error> {
     }
 }
 
-// TODO: add tests for this function
+/// The value of an id column of a moray object, which moray may hand back as
+/// either a number or a string.
+fn moray_obj_column(moray_value: &Value, id_name: &str) -> Option<u64> {
+    match moray_value.get(id_name)? {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The _id (or _idx) of a moray object, used to give context to errors.
+fn moray_obj_id(moray_value: &Value) -> Option<u64> {
+    ["_id", "_idx"]
+        .iter()
+        .find_map(|id| moray_obj_column(moray_value, id))
+}
+
 // See block comment at top of a file for an example of the object this is
 // working with.
-/// Called for every object that is read in by the query executed in
-/// read_chunk().  For a given object:
-///     1. Validate it is of the right form.
-///     2. Get it's "_value" which is the manta object metadata(*).
+/// Called for every moray object that is produced by an ObjectSource.  For a
+/// given object:
+///     1. Get it's "_value" which is the manta object metadata(*).
+///     2. Skip it if the metadata is not for an object (e.g. a directory).
 ///     3. Check if the manta object metadata is for an object that is on the
 ///        shark that the caller is looking for.
-///     4. Return the entire moray entry as a serde Value
+///     4. Pass a MatchRecord for each of those sharks to the caller's
+///        handler, or a single MatchRecord listing all of them if
+///        `conf.per_object` is set.
 ///
 /// (*): The manta object metadata does not have a consistent schema, so the
 /// only thing we look for is the "sharks" array which should always be there
 /// regardless of the schema.  If it is not then we can't really filter on
-/// the shark so we log an error and move on, not returning the value to the
-/// caller.
+/// the shark so we log an error and return a record error, not returning the
+/// value to the caller.
 fn query_handler<F>(
     log: &Logger,
-    val: &Value,
+    moray_value: &Value,
     shard_num: u32,
-    sharks_requested: &[String],
+    id_column: Option<&str>,
+    conf: &config::Config,
     handler: &mut F,
-) -> Result<(), Error>
+) -> Result<(), SharkspotterError>
 where
-    F: FnMut(Value, &str, &str, u32) -> Result<(), Error>,
+    F: FnMut(MatchRecord) -> Result<(), SharkspotterError>,
 {
-    match val.as_array() {
-        Some(v) => {
-            if v.len() > 1 {
-                warn!(
-                    log,
-                    "Expected 1 value, got {}.  Using first entry.",
-                    v.len()
-                );
-            }
+    let manta_value = manta_obj_from_moray_obj(moray_value).map_err(|e| {
+        error!(log, "{}", e);
+        SharkspotterError::MalformedRecord {
+            shard: shard_num,
+            id: moray_obj_id(moray_value),
+            cause: e,
         }
-        None => {
-            return _log_return_error(log, "Entry is not an array");
+    })?;
+
+    match_manta_obj(
+        log,
+        moray_value,
+        manta_value,
+        shard_num,
+        id_column,
+        conf,
+        handler,
+    )
+}
+
+/// Steps 2 to 4 of the query_handler, for a moray object whose manta object
+/// metadata has already been parsed.  The metadata is moved into the last
+/// MatchRecord rather than copied.
+fn match_manta_obj<F>(
+    log: &Logger,
+    moray_value: &Value,
+    manta_value: Value,
+    shard_num: u32,
+    id_column: Option<&str>,
+    conf: &config::Config,
+    handler: &mut F,
+) -> Result<(), SharkspotterError>
+where
+    F: FnMut(MatchRecord) -> Result<(), SharkspotterError>,
+{
+    // The moray and direct-DB queries only return objects, but other
+    // sources may not be able to filter out directories up front.
+    if let Some(obj_type) = manta_value.get("type").and_then(Value::as_str) {
+        if obj_type != "object" {
+            return Ok(());
         }
     }
 
-    let moray_value = match val.get(0) {
-        Some(v) => v,
-        None => {
-            return _log_return_error(log, "Entry is empty");
+    let sharks =
+        get_sharks_from_manta_obj(&manta_value, &log).map_err(|e| {
+            SharkspotterError::MissingSharks {
+                shard: shard_num,
+                id: moray_obj_id(moray_value),
+                cause: e.to_string(),
+            }
+        })?;
+
+    // Filter on shark
+    let mut matching: Vec<String> = sharks
+        .iter()
+        .filter(|s| conf.sharks.contains(&s.manta_storage_id))
+        .map(|s| s.manta_storage_id.clone())
+        .collect();
+
+    let last = match matching.pop() {
+        Some(last) => last,
+        None => return Ok(()),
+    };
+
+    let etag = etag_from_moray_value(&moray_value).map_err(|e| {
+        SharkspotterError::MalformedRecord {
+            shard: shard_num,
+            id: moray_obj_id(moray_value),
+            cause: e.to_string(),
         }
+    })?;
+    let object = MantaObject::new(&manta_value, sharks);
+
+    if conf.per_object {
+        matching.push(last);
+        return handler(MatchRecord::new(
+            moray_value,
+            manta_value,
+            object,
+            &etag,
+            matching,
+            shard_num,
+            id_column,
+        ));
+    }
+
+    // Only the records for all but the last shark need their own copy of
+    // the object.
+    for shark in matching {
+        handler(MatchRecord::new(
+            moray_value,
+            manta_value.clone(),
+            object.clone(),
+            &etag,
+            vec![shark],
+            shard_num,
+            id_column,
+        ))?;
+    }
+
+    handler(MatchRecord::new(
+        moray_value,
+        manta_value,
+        object,
+        &etag,
+        vec![last],
+        shard_num,
+        id_column,
+    ))
+}
+
+/// Run a single record from a source through the query_handler.  Errors that
+/// only affect this record are given to the error handler, which decides
+/// whether the scan should continue.
+fn handle_record<F, E>(
+    log: &Logger,
+    record: SourceRecord,
+    shard_num: u32,
+    id_column: Option<&str>,
+    conf: &config::Config,
+    handler: &mut F,
+    error_handler: &mut E,
+) -> Result<(), SharkspotterError>
+where
+    F: FnMut(MatchRecord) -> Result<(), SharkspotterError>,
+    E: FnMut(SharkspotterError) -> Result<(), SharkspotterError>,
+{
+    // Every match is counted as it is handed over, so that the checkpoint
+    // can wait for the caller to write it out.
+    let handler = &mut |record| {
+        conf.output_ack.handed_over();
+        handler(record)
     };
 
-    let moray_object: Value = match serde_json::from_value(moray_value.clone())
-    {
-        Ok(mo) => mo,
-        Err(e) => {
-            let msg = format!(
-                "Could not deserialize moray value {:#?}. ({})",
-                moray_value, e
-            );
-            return _log_return_error(log, &msg);
+    let ret = match record {
+        SourceRecord::Moray(moray_value) => {
+            query_handler(log, moray_value, shard_num, id_column, conf, handler)
         }
+        SourceRecord::Parsed {
+            moray_value,
+            manta_value,
+        } => manta_value.and_then(|manta_value| {
+            match_manta_obj(
+                log,
+                &moray_value,
+                manta_value,
+                shard_num,
+                id_column,
+                conf,
+                handler,
+            )
+        }),
+        SourceRecord::Skipped => Ok(()),
     };
 
-    // here rui
-    let manta_value = match manta_obj_from_moray_obj(&moray_object) {
-        Ok(v) => v,
+    match ret {
+        Err(e) if e.is_record_error() => error_handler(e),
+        ret => ret,
+    }
+}
+
+/// Scan a single source, passing each of its records through the
+/// query_handler, and report how it went.  The scan is stopped before the
+/// next record once `stopped` returns true.
+fn scan_source<F, E>(
+    source: &mut dyn ObjectSource,
+    log: &Logger,
+    conf: &config::Config,
+    handler: &mut F,
+    error_handler: &mut E,
+    stopped: &dyn Fn() -> bool,
+) -> ShardReport
+where
+    F: FnMut(MatchRecord) -> Result<(), SharkspotterError>,
+    E: FnMut(SharkspotterError) -> Result<(), SharkspotterError>,
+{
+    let start = Instant::now();
+    let shard = source.shard();
+    let id_column = source.id_column();
+    let id_column = id_column.as_ref().map(String::as_str);
+    let mut report = ShardReport::new(shard);
+    let mut scanned = 0;
+    let mut matched = 0;
+    let mut skipped = 0;
+
+    let ret = source.scan_records(log, &mut |record| {
+        if stopped() {
+            return Err(SharkspotterError::ChannelClosed);
+        }
+
+        scanned += 1;
+        handle_record(
+            log,
+            record,
+            shard,
+            id_column,
+            conf,
+            &mut |record| {
+                matched += 1;
+                handler(record)
+            },
+            &mut |e| {
+                error_handler(e)?;
+                skipped += 1;
+                Ok(())
+            },
+        )
+    });
+
+    report.scanned = scanned;
+    report.matched = matched;
+    report.skipped = skipped;
+    report.overlap = source.overlap();
+    report.duration = start.elapsed();
+
+    match ret {
+        Ok(()) => (),
+        // A closed channel indicates that our receiver has shutdown.
+        // This is not an error in the context of lib sharkspotter.  The
+        // consumer of sharkspotter may encounter an error which causes
+        // it to stop receiving objects, but that error should be
+        // handled by the consumer not here.
+        Err(SharkspotterError::ChannelClosed) => {
+            report.status = ShardStatus::Stopped;
+        }
+        Err(SharkspotterError::Cancelled) => {
+            report.status = ShardStatus::Cancelled;
+        }
         Err(e) => {
-            return _log_return_error(log, &e);
+            error!(log, "Encountered error scanning {} ({})", source.name(), e);
+            report.status = ShardStatus::Failed;
+            report.errors.push(e);
+        }
+    }
+
+    report
+}
+
+/// The moray `sql` endpoint hands back each row of the result wrapped in an
+/// array.
+fn moray_obj_from_sql_resp<'a>(
+    log: &Logger,
+    val: &'a Value,
+    shard_num: u32,
+) -> Result<&'a Value, SharkspotterError> {
+    let malformed = |cause: &str| {
+        error!(log, "{}", cause);
+        SharkspotterError::MalformedRecord {
+            shard: shard_num,
+            id: None,
+            cause: cause.to_string(),
         }
     };
 
-    let sharks = get_sharks_from_manta_obj(&manta_value, &log)?;
+    match val.as_array() {
+        Some(v) => {
+            if v.len() > 1 {
+                warn!(
+                    log,
+                    "Expected 1 value, got {}.  Using first entry.",
+                    v.len()
+                );
+            }
+        }
+        None => {
+            return Err(malformed("Entry is not an array"));
+        }
+    }
+
+    val.get(0).ok_or_else(|| malformed("Entry is empty"))
+}
 
-    // Filter on shark
-    sharks
+/// A page of up to `count` rows of the given range of an id column, in id
+/// order, so that a full page can be followed by one that starts after the
+/// last id in it.
+fn chunk_query(
+    id_name: &str,
+    begin: u64,
+    end: u64,
+    count: u64,
+    covered: &[(&str, IdRange)],
+) -> String {
+    let skip_covered: String = covered
         .iter()
-        .filter(|s| sharks_requested.contains(&s.manta_storage_id))
-        .try_for_each(|s| {
-            // TODO: handle error
-            let etag = etag_from_moray_value(&moray_value)?;
-            handler(
-                manta_value.clone(),
-                etag.as_str(),
-                s.manta_storage_id.as_str(),
-                shard_num,
+        .map(|(id, range)| {
+            format!(
+                " AND ({} IS NULL OR {} < {} OR {} > {})",
+                id, id, range.begin, id, range.end
             )
-        })?;
+        })
+        .collect();
 
-    Ok(())
+    format!(
+        "SELECT * FROM manta WHERE {} >= {} AND \
+         {} <= {} AND type = 'object'{} ORDER BY {} LIMIT {};",
+        id_name, begin, id_name, end, skip_covered, id_name, count
+    )
 }
 
-fn chunk_query(id_name: &str, begin: u64, end: u64, count: u64) -> String {
+/// Count the objects in the given range of an id column that are skipped
+/// because the scan of an earlier column already covers them.
+fn overlap_query(
+    id_name: &str,
+    begin: u64,
+    end: u64,
+    covered: &[(&str, IdRange)],
+) -> String {
+    let in_covered: Vec<String> = covered
+        .iter()
+        .map(|(id, range)| {
+            format!("({} >= {} AND {} <= {})", id, range.begin, id, range.end)
+        })
+        .collect();
+
     format!(
-        "SELECT * FROM manta WHERE {} >= {} AND \
-         {} <= {} AND type = 'object' limit {};",
-        id_name, begin, id_name, end, count
+        "SELECT count(*) FROM manta WHERE {} >= {} AND {} <= {} AND \
+         type = 'object' AND ({});",
+        id_name,
+        begin,
+        id_name,
+        end,
+        in_covered.join(" OR ")
     )
 }
 
-/// Make the actual sql query and call the query_handler to handle processing
-/// every object that is returned in the chunk.
-fn read_chunk<F>(
+// The names of the moray errors that mean moray has lost its database, e.g.
+// while manatee fails over, rather than that anything is wrong with the
+// query.
+const MORAY_DISCONNECTED_ERRORS: [&str; 2] =
+    ["NoDatabasePeersError", "ConnectionClosedError"];
+
+/// Sort out the errors of a moray request that are worth retrying (see
+/// `SharkspotterError::is_transient()`) from those that aren't.  Moray's own
+/// errors only come back as a message with the name of the error in it.
+fn moray_query_error(shard: u32, e: Error) -> SharkspotterError {
+    let cause = e.to_string();
+
+    match e.kind() {
+        ErrorKind::TimedOut => {
+            return SharkspotterError::QueryTimeout { shard, cause };
+        }
+        ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected
+        | ErrorKind::BrokenPipe
+        | ErrorKind::UnexpectedEof => {
+            return SharkspotterError::Disconnected { shard, cause };
+        }
+        _ => (),
+    }
+
+    if cause.contains("QueryTimeoutError") {
+        SharkspotterError::QueryTimeout { shard, cause }
+    } else if MORAY_DISCONNECTED_ERRORS.iter().any(|n| cause.contains(n)) {
+        SharkspotterError::Disconnected { shard, cause }
+    } else {
+        SharkspotterError::Query { shard, cause }
+    }
+}
+
+/// The options for a moray `sql` request.
+fn sql_opts(conf: &config::Config) -> String {
+    format!(r#"{{"timeout": {}}}"#, conf.sql_timeout_ms)
+}
+
+/// The part of the moray client that a chunked scan uses, so that the scan
+/// can be tested against a fake moray.
+trait MoraySql {
+    fn sql(
+        &mut self,
+        stmt: &str,
+        opts: &str,
+        handler: &mut dyn FnMut(&Value) -> Result<(), Error>,
+    ) -> Result<(), Error>;
+}
+
+impl MoraySql for MorayClient {
+    fn sql(
+        &mut self,
+        stmt: &str,
+        opts: &str,
+        handler: &mut dyn FnMut(&Value) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        MorayClient::sql(self, stmt, vec![], opts, |resp| handler(resp))
+    }
+}
+
+/// Make the actual sql query and call the handler for every moray object
+/// that is returned in the chunk.  Returns how long moray took to answer the
+/// query, leaving out the time spent in the handler.
+fn read_chunk<M, F>(
     log: &Logger,
-    mclient: &mut MorayClient,
+    mclient: &mut M,
     query: &str,
+    opts: &str,
     shard_num: u32,
-    sharks: &[String],
     handler: &mut F,
-) -> Result<(), Error>
+) -> Result<Duration, SharkspotterError>
 where
-    F: FnMut(Value, &str, &str, u32) -> Result<(), Error>,
+    M: MoraySql,
+    F: FnMut(&Value) -> Result<(), SharkspotterError>,
 {
-    match mclient.sql(query, vec![], r#"{"timeout": 10000}"#, |a| {
-        query_handler(log, a, shard_num, sharks, handler)
-    }) {
-        Ok(()) => Ok(()),
-        Err(e) => {
-            eprintln!("Got error: {}", e);
-            Err(e)
-        }
+    // The moray client only passes io::Errors back from the handler, so we
+    // hold on to the handler's error ourselves to keep it apart from errors
+    // in moray itself.
+    let mut handler_error = None;
+    let mut handler_time = Duration::from_secs(0);
+    let start = Instant::now();
+
+    let ret = mclient.sql(query, opts, &mut |a| {
+        let handler_start = Instant::now();
+        let ret =
+            moray_obj_from_sql_resp(log, a, shard_num).and_then(&mut *handler);
+        handler_time += handler_start.elapsed();
+
+        ret.map_err(|e| {
+            handler_error = Some(e);
+            Error::new(ErrorKind::Other, "handler error")
+        })
+    });
+    let query_time = start.elapsed();
+
+    if let Some(e) = handler_error {
+        return Err(e);
     }
+
+    ret.map(|()| query_time.checked_sub(handler_time).unwrap_or_default())
+        .map_err(|e| {
+            eprintln!("Got error: {}", e);
+            moray_query_error(shard_num, e)
+        })
 }
 
-/// Find the maximum _id/_idx and, starting at 0 iterate over every entry up
-/// to the max.  For each chunk call read_chunk.
-fn iter_ids<F>(
-    id_name: &str,
-    moray_socket: &str,
-    conf: &config::Config,
+/// Iterate over every entry in the given range of an _id/_idx column.  For
+/// each chunk call read_chunk.  If a checkpoint exists for this shard and id
+/// column the scan starts after the last id recorded.  Rows that fall in one
+/// of the `covered` ranges are skipped.
+///
+/// A chunk can hold more rows than moray will return in one go, so each chunk
+/// is read a page of `chunk_size` rows at a time, in id order, with each page
+/// starting after the last id of the one before.  If a request fails it is
+/// retried on a client from `reconnect`.
+///
+/// The throttle and the chunk sizer are told how long the slowest page of
+/// each chunk took moray to answer, which leaves out retries and the time
+/// spent in the handler.  If a page times out the rest of the chunk is
+/// narrowed (see `ChunkSizer::timed_out`) and read again.
+fn iter_ids<M, F>(
+    source: &MorayObjectSource,
+    mclient: &mut M,
+    reconnect: &dyn Fn(u32) -> Result<M, SharkspotterError>,
     log: Logger,
-    shard_num: u32,
+    range: IdRange,
+    covered: &[(&str, IdRange)],
     mut handler: F,
-) -> Result<(), Error>
+) -> Result<(), SharkspotterError>
 where
-    F: FnMut(Value, &str, &str, u32) -> Result<(), Error>,
+    M: MoraySql,
+    F: FnMut(&Value) -> Result<(), SharkspotterError>,
 {
-    let mut mclient = MorayClient::from_str(moray_socket, log.clone(), None)?;
-
-    let mut start_id = conf.begin;
-    let mut end_id = conf.begin + conf.chunk_size - 1;
-    let mut largest_id = match find_largest_id_value(&log, &mut mclient, id_name) {
-        Ok(id) => id,
-        Err(e) => {
-            error!(&log, "Error finding largest ID: {}, using 0", e);
-            0
+    let id_name = source.id_name.as_str();
+    let conf = &source.conf;
+    let shard_num = source.shard;
+    let checkpoint = &source.checkpoint;
+    let checkpoint_key = source.checkpoint_key();
+
+    let mut begin = range.begin;
+    if let Some(last_id) = checkpoint.resume_point(shard_num, &checkpoint_key) {
+        if last_id + 1 > begin {
+            begin = last_id + 1;
         }
-    };
+        debug!(
+            &log,
+            "resuming scan from checkpoint";
+            "index" => id_name,
+            "shard" => shard_num,
+            "start_id" => begin
+        );
+    }
+
+    let mut start_id = begin;
+    let mut sizer = ChunkSizer::new(conf);
+    let mut end_id = begin + sizer.size() - 1;
+    let largest_id = range.end;
 
-    // clamp largest_id to conf.end if it is set and less than the largest found
-    if conf.end > 0 && conf.end < largest_id {
-        largest_id = conf.end
+    if begin > largest_id {
+        debug!(
+            &log,
+            "nothing to scan";
+            "index" => id_name,
+            "shard" => shard_num,
+            "start_id" => begin,
+            "largest_id" => largest_id
+        );
+        return Ok(());
     }
 
-    let mut remaining = largest_id - conf.begin + 1;
+    let mut remaining = largest_id - begin + 1;
     assert!(largest_id + 1 >= remaining);
 
-    // only clamp end value if `-e` is explicitly given
-    if conf.end > 0 && end_id > conf.end {
-        end_id = conf.end;
+    // The range is already clamped to `-e` if it was given, and the first
+    // chunk must not run past it into the range of another split.
+    if end_id > largest_id {
+        end_id = largest_id;
     }
 
+    let backoff = &source.backoff;
+    let opts = sql_opts(conf);
+    let page_size = conf.chunk_size.max(1);
+    let mut throttle = source.throttle.clone();
+
     while remaining > 0 {
-        let query = chunk_query(id_name, start_id, end_id, conf.chunk_size);
-        match read_chunk(
-            &log,
-            &mut mclient,
-            query.as_str(),
-            shard_num,
-            &conf.sharks,
-            &mut handler,
-        ) {
-            Ok(()) => (),
-            Err(e) => return Err(e),
-        };
+        // A chunk that has been started is always finished, so that the
+        // checkpoint is right, but no more are started once the scan has
+        // been cancelled.
+        if conf.cancel.is_cancelled() {
+            info!(
+                &log,
+                "scan cancelled";
+                "index" => id_name,
+                "shard" => shard_num,
+                "start_id" => start_id
+            );
+            return Err(SharkspotterError::Cancelled);
+        }
+
+        // If moray fails part way through a page the whole page is read
+        // again, possibly from another moray, but the records that were
+        // already handled are not handled a second time.
+        let mut handled = HashSet::new();
+        let mut page_start = start_id;
+        let mut query_time = Duration::from_secs(0);
+        loop {
+            let query =
+                chunk_query(id_name, page_start, end_id, page_size, covered);
+            let page_width = end_id - page_start + 1;
+            let mut page_rows = 0;
+            let mut page_last = None;
+
+            let page = backoff.retry(&log, |attempt| {
+                if attempt > 0 {
+                    *mclient = reconnect(attempt)?;
+                }
+
+                page_rows = 0;
+                page_last = None;
+                let ret = read_chunk(
+                    &log,
+                    mclient,
+                    &query,
+                    &opts,
+                    shard_num,
+                    &mut |obj| {
+                        page_rows += 1;
+                        page_last = moray_obj_column(obj, id_name);
+                        match moray_obj_id(obj) {
+                            Some(id) if !handled.insert(id) => Ok(()),
+                            _ => handler(obj),
+                        }
+                    },
+                );
+
+                // A page that times out is read again over fewer ids, rather
+                // than retried as it is, unless the chunk can't be narrowed.
+                match ret {
+                    Err(e @ SharkspotterError::QueryTimeout { .. }) => {
+                        if sizer.timed_out(page_width) {
+                            Ok(Err(e))
+                        } else {
+                            Err(e)
+                        }
+                    }
+                    ret => ret.map(Ok),
+                }
+            })?;
+
+            let page_time = match page {
+                Ok(page_time) => page_time,
+                Err(e) => {
+                    end_id = page_start + sizer.size() - 1;
+                    warn!(
+                        &log,
+                        "chunk query timed out, narrowing chunk";
+                        "index" => id_name,
+                        "shard" => shard_num,
+                        "start_id" => page_start,
+                        "end_id" => end_id,
+                        "error" => e.to_string()
+                    );
+                    continue;
+                }
+            };
+            query_time = query_time.max(page_time);
+
+            // A short page is the end of the chunk.  Otherwise there may be
+            // more rows after the last one in the page.
+            if page_rows < page_size {
+                break;
+            }
+
+            match page_last {
+                Some(last) if last < end_id => page_start = last + 1,
+                Some(_) => break,
+                None => {
+                    return Err(SharkspotterError::MalformedRecord {
+                        shard: shard_num,
+                        id: None,
+                        cause: format!("record has no {} column", id_name),
+                    });
+                }
+            }
+        }
+
+        let width = end_id - start_id + 1;
+        checkpoint.update(shard_num, &checkpoint_key, end_id, &log)?;
 
         // Find the percent value rounded to the thousand-th of a percent.
-        let percent_complete =
-            (1.0 - remaining as f64 / largest_id as f64) * 100.0;
+        let percent_complete = (end_id - range.begin + 1) as f64
+            / (largest_id - range.begin + 1) as f64
+            * 100.0;
         let percent_complete = (percent_complete * 1000.0).round() / 1000.0;
 
         debug!(
@@ -462,7 +1007,14 @@ where
             break;
         }
 
-        end_id = start_id + conf.chunk_size - 1;
+        // The wait is cut short if the scan is cancelled, and the scan then
+        // stops before the next chunk.
+        let wait =
+            throttle.after_chunk(handled.len() as u64, Some(query_time), &log);
+        conf.cancel.sleep(wait);
+
+        sizer.update(width, handled.len() as u64, query_time);
+        end_id = start_id + sizer.size() - 1;
         if end_id > largest_id {
             end_id = largest_id
         }
@@ -474,288 +1026,971 @@ where
     Ok(())
 }
 
-fn lookup_ip_str(host: &str) -> Result<String, Error> {
-    let resolver = Resolver::from_system_conf()?;
-    let response = resolver.lookup_ip(host)?;
-    let ip: Vec<IpAddr> = response.iter().collect();
-
-    Ok(ip[0].to_string())
+/// Scans a single id column (_id or _idx) of a shard's manta bucket by
+/// querying moray in chunks.  Rows that the scan of an earlier id column of
+/// the same shard covers are skipped (see `probe::ID_COLUMNS`), so the
+/// sources for a shard need to share the same `SharedProbe`.
+pub struct MorayObjectSource {
+    shard: u32,
+    id_name: String,
+    moray_sockets: Vec<String>,
+    conf: config::Config,
+    checkpoint: Checkpoint,
+    probe: SharedProbe,
+    throttle: ShardThrottle,
+
+    /// How failed moray requests are retried.
+    backoff: Backoff,
+    split: u32,
+    unit: Option<IdRange>,
+    overlap: Option<u64>,
 }
 
-fn shark_fix_common(conf: &mut config::Config, log: &Logger) {
-    let mut new_sharks = Vec::with_capacity(conf.sharks.len());
-
-    for shark in conf.sharks.iter() {
-        if !shark.contains(conf.domain.as_str()) {
-            let new_shark = format!("{}.{}", shark, conf.domain);
-            warn!(log,
-                  "Domain \"{}\" not found in storage node string:\"{}\", using \"{}\"",
-                  conf.domain,
-                  shark,
-                  new_shark
-            );
-
-            new_sharks.push(new_shark);
-        } else {
-            new_sharks.push(shark.to_owned());
+impl MorayObjectSource {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        shard: u32,
+        id_name: &str,
+        moray_sockets: Vec<String>,
+        conf: &config::Config,
+        checkpoint: &Checkpoint,
+        probe: &SharedProbe,
+        throttle: &ShardThrottle,
+        split: u32,
+    ) -> Self {
+        MorayObjectSource {
+            shard,
+            id_name: id_name.to_string(),
+            moray_sockets,
+            conf: conf.clone(),
+            checkpoint: checkpoint.clone(),
+            probe: probe.clone(),
+            throttle: throttle.clone(),
+            backoff: Backoff::from_config(conf),
+            split,
+            unit: None,
+            overlap: None,
         }
     }
-    conf.sharks = new_sharks;
-}
 
-fn validate_sharks(conf: &config::Config, log: &Logger) -> Result<(), Error> {
-    if conf.skip_validate_sharks {
-        return Ok(());
+    /// A source for one unit of work of the same id column.
+    fn with_unit(&self, unit: IdRange) -> Self {
+        MorayObjectSource {
+            shard: self.shard,
+            id_name: self.id_name.clone(),
+            moray_sockets: self.moray_sockets.clone(),
+            conf: self.conf.clone(),
+            checkpoint: self.checkpoint.clone(),
+            probe: self.probe.clone(),
+            throttle: self.throttle.clone(),
+            backoff: self.backoff.clone(),
+            split: self.split,
+            unit: Some(unit),
+            overlap: None,
+        }
     }
 
-    let sharks = &conf.sharks;
-    let domain = &conf.domain;
-    let shard1_moray = format!("1.moray.{}", domain);
-    let moray_ip = lookup_ip_str(shard1_moray.as_str())?;
-    let moray_socket = format!("{}:{}", moray_ip, 2021);
-    let opts = moray_objects::MethodOptions::default();
-    let mut mclient =
-        MorayClient::from_str(moray_socket.as_str(), log.clone(), None)?;
+    /// Connect to one of the shard's morays, starting with a different one
+    /// on each attempt.
+    fn moray_client(
+        &self,
+        log: &Logger,
+        attempt: u32,
+    ) -> Result<MorayClient, SharkspotterError> {
+        connect_to_moray(&self.moray_sockets, attempt as usize, self.shard, log)
+    }
 
-    for shark in sharks.iter() {
-        let mut count = 0;
-        let filter = format!("manta_storage_id={}", shark);
-        mclient.find_objects(
-            "manta_storage",
-            filter.as_str(),
-            &opts,
-            |_| {
-                count += 1;
-                Ok(())
-            },
-        )?;
+    /// Connect to the shard's moray and probe the shard if that hasn't been
+    /// done already, retrying if need be.
+    fn connect(
+        &self,
+        log: &Logger,
+    ) -> Result<(MorayClient, ShardProbe), SharkspotterError> {
+        self.backoff.retry(log, |attempt| {
+            let mut mclient = self.moray_client(log, attempt)?;
+            let probe = self.probe(log, &mut mclient)?;
+            Ok((mclient, probe))
+        })
+    }
 
-        if count > 1 {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("More than one shark with name \"{}\" found", shark),
-            ));
+    /// The shard's probe, connecting to probe the shard only if none of its
+    /// sources has done so yet.
+    fn cached_probe(
+        &self,
+        log: &Logger,
+    ) -> Result<ShardProbe, SharkspotterError> {
+        match self.probe.get() {
+            Some(probe) => Ok(probe),
+            None => self.connect(log).map(|(_, probe)| probe),
         }
+    }
 
-        if count == 0 {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("No shark with name \"{}\" found", shark),
-            ));
-        }
+    fn probe(
+        &self,
+        log: &Logger,
+        mclient: &mut MorayClient,
+    ) -> Result<ShardProbe, SharkspotterError> {
+        self.probe
+            .get_or_probe(|| probe_moray_shard(log, mclient, self.shard))
+    }
+
+    fn is_split(&self) -> bool {
+        self.conf.shard_splits > 1
+    }
+
+    /// The part of the id column's scan range that this source covers when
+    /// the shard is split (see `Config::shard_splits`).  A resumed scan uses
+    /// the split points of the original run, with the last split extended
+    /// to cover any ids added since.
+    fn split_range(
+        &self,
+        range: IdRange,
+    ) -> Result<Option<IdRange>, SharkspotterError> {
+        if !self.is_split() {
+            return Ok(Some(range));
+        }
+
+        let points = self.checkpoint.split_range(
+            self.shard,
+            &self.id_name,
+            range,
+            self.conf.shard_splits,
+        )?;
+
+        let i = self.split as usize;
+        let begin = points[i].max(range.begin);
+        let end = match points.get(i + 1) {
+            Some(next) => range.end.min(next - 1),
+            None => range.end,
+        };
+
+        if begin > end {
+            return Ok(None);
+        }
+
+        Ok(Some(IdRange { begin, end }))
+    }
+
+    /// The range of ids this source scans: the part of the column's scan
+    /// range in its split, and in its unit of work if it has one.
+    fn scan_range(
+        &self,
+        probe: &ShardProbe,
+    ) -> Result<Option<IdRange>, SharkspotterError> {
+        let range = match probe.scan_range(&self.id_name, &self.conf) {
+            Some(range) => self.split_range(range)?,
+            None => None,
+        };
+
+        let range = match (range, self.unit) {
+            (Some(range), Some(unit)) => IdRange {
+                begin: range.begin.max(unit.begin),
+                end: range.end.min(unit.end),
+            },
+            (Some(range), None) => range,
+            (None, _) => return Ok(None),
+        };
+
+        if range.begin > range.end {
+            return Ok(None);
+        }
+
+        Ok(Some(range))
+    }
+
+    /// Each split and each unit of work of an id column is checkpointed
+    /// separately.
+    fn checkpoint_key(&self) -> String {
+        if let Some(unit) = self.unit {
+            format!("{}@{}", self.id_name, unit.begin)
+        } else if self.is_split() {
+            format!("{}#{}", self.id_name, self.split)
+        } else {
+            self.id_name.clone()
+        }
+    }
+
+    /// Count the objects in this column's range that the scan of an earlier
+    /// column has already covered.
+    fn count_overlap(
+        &self,
+        log: &Logger,
+        mclient: &mut MorayClient,
+        range: IdRange,
+        covered: &[(&str, IdRange)],
+    ) -> Result<u64, SharkspotterError> {
+        if covered.is_empty() {
+            return Ok(0);
+        }
+
+        let query =
+            overlap_query(&self.id_name, range.begin, range.end, covered);
+        let mut count = 0;
+        mclient
+            .sql(query.as_str(), vec![], &sql_opts(&self.conf), |resp| {
+                count = parse_sql_u64(resp.to_owned(), "count", log)?;
+                Ok(())
+            })
+            .map_err(|e| moray_query_error(self.shard, e))?;
+
+        Ok(count)
+    }
+}
+
+impl ObjectSource for MorayObjectSource {
+    fn shard(&self) -> u32 {
+        self.shard
+    }
+
+    fn name(&self) -> String {
+        let mut split = if self.is_split() {
+            format!(" split {}/{}", self.split + 1, self.conf.shard_splits)
+        } else {
+            String::new()
+        };
+
+        if let Some(unit) = self.unit {
+            split.push_str(&format!(" ids {}-{}", unit.begin, unit.end));
+        }
+
+        format!(
+            "shard {} {}{} via moray {}",
+            self.shard,
+            self.id_name,
+            split,
+            self.moray_sockets.join(",")
+        )
+    }
+
+    fn id_range(
+        &mut self,
+        log: &Logger,
+    ) -> Result<Option<IdRange>, SharkspotterError> {
+        let probe = self.cached_probe(log)?;
+
+        self.scan_range(&probe)
+    }
+
+    fn split_off(
+        &mut self,
+        log: &Logger,
+        max_ids: u64,
+    ) -> Result<Option<Box<dyn ObjectSource>>, SharkspotterError> {
+        let probe = self.cached_probe(log)?;
+
+        let mut range = match self.scan_range(&probe)? {
+            Some(range) => range,
+            None => return Ok(None),
+        };
+
+        // The units are checkpointed separately from the whole column, so
+        // pick up where an earlier run over the whole column left off before
+        // splitting it.
+        if self.unit.is_none() {
+            let key = self.checkpoint_key();
+            if let Some(last_id) =
+                self.checkpoint.resume_point(self.shard, &key)
+            {
+                range.begin = range.begin.max(last_id + 1);
+            }
+        }
+
+        let (unit, rest) = match range.split_unit(max_ids) {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+
+        let rest = self.with_unit(rest);
+        self.unit = Some(unit);
+
+        Ok(Some(Box::new(rest)))
+    }
+
+    fn id_column(&self) -> Option<String> {
+        Some(self.id_name.clone())
+    }
+
+    fn overlap(&self) -> Option<u64> {
+        self.overlap
+    }
+
+    fn scan(
+        &mut self,
+        log: &Logger,
+        handler: &mut dyn FnMut(&Value) -> Result<(), SharkspotterError>,
+    ) -> Result<(), SharkspotterError> {
+        let (mut mclient, probe) = self.connect(log)?;
+
+        // A shard needs at least one of the id columns.  Only the source for
+        // the first one reports it so that the error isn't repeated.
+        if probe.id_columns.is_empty() && self.id_name == ID_COLUMNS[0] {
+            return Err(SharkspotterError::Query {
+                shard: self.shard,
+                cause: String::from("manta table has no _id or _idx column"),
+            });
+        }
+
+        let range = match self.scan_range(&probe)? {
+            Some(range) => range,
+            None => {
+                debug!(
+                    log,
+                    "nothing to scan";
+                    "index" => &self.id_name,
+                    "shard" => self.shard,
+                    "id_columns" => probe.summary()
+                );
+                return Ok(());
+            }
+        };
+        let covered = probe.covered_ranges(&self.id_name, &self.conf);
+
+        if self.conf.report_overlap {
+            let overlap = self.backoff.retry(log, |attempt| {
+                if attempt > 0 {
+                    mclient = self.moray_client(log, attempt)?;
+                }
+                self.count_overlap(log, &mut mclient, range, &covered)
+            })?;
+            info!(
+                log,
+                "id column overlap";
+                "shard" => self.shard,
+                "index" => &self.id_name,
+                "count" => overlap
+            );
+            self.overlap = Some(overlap);
+        }
+
+        iter_ids(
+            self,
+            &mut mclient,
+            &|attempt| self.moray_client(log, attempt),
+            log.clone(),
+            range,
+            &covered,
+            handler,
+        )
+    }
+}
+
+/// Connect to the first of a shard's moray sockets that will take a
+/// connection, starting from `first`.
+fn connect_to_moray(
+    sockets: &[String],
+    first: usize,
+    shard: u32,
+    log: &Logger,
+) -> Result<MorayClient, SharkspotterError> {
+    let count = sockets.len();
+    let mut ret = Err(SharkspotterError::Connect {
+        endpoint: format!("shard {} moray", shard),
+        cause: String::from("no addresses"),
+    });
+
+    for i in 0..count {
+        let socket = &sockets[(first + i) % count];
+        ret = MorayClient::from_str(socket, log.clone(), None).map_err(|e| {
+            SharkspotterError::Connect {
+                endpoint: socket.clone(),
+                cause: e.to_string(),
+            }
+        });
+
+        match &ret {
+            Ok(_) => break,
+            Err(e) => warn!(log, "{}", e),
+        }
+    }
+
+    ret
+}
+
+/// The socket of every address of a shard's moray.  `SocketAddr` takes care
+/// of putting IPv6 addresses in brackets.
+fn moray_sockets(
+    shard: u32,
+    conf: &config::Config,
+) -> Result<Vec<String>, SharkspotterError> {
+    let moray_host = conf.moray.host(shard, &conf.domain);
+
+    Ok(lookup_ips(moray_host.as_str(), &conf.dns)?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, conf.moray.port).to_string())
+        .collect())
+}
+
+/// Find the address of a host, either in the static host map or by looking
+/// it up (see `config::DnsConfig`).  If a host has more than one address the
+/// first is used.
+fn lookup_ip_str(
+    host: &str,
+    dns: &config::DnsConfig,
+) -> Result<String, SharkspotterError> {
+    Ok(lookup_ips(host, dns)?[0].to_string())
+}
+
+/// Every address of a host.  The list is never empty.
+fn lookup_ips(
+    host: &str,
+    dns: &config::DnsConfig,
+) -> Result<Vec<IpAddr>, SharkspotterError> {
+    let dns_error = |cause: String| SharkspotterError::Dns {
+        host: host.to_string(),
+        cause,
+    };
+
+    // A host template can be filled in with an address, e.g. for a tunnel.
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+
+    if let Some(ip) = dns.hosts.get(host) {
+        return Ok(vec![*ip]);
+    }
+
+    let resolver = if dns.nameservers.is_empty() {
+        Resolver::from_system_conf()
+    } else {
+        // Each nameserver can listen on a port of its own.
+        let mut nameservers = NameServerConfigGroup::new();
+        for ns in dns.nameservers.iter() {
+            let group =
+                NameServerConfigGroup::from_ips_clear(&[ns.ip()], ns.port());
+            nameservers.extend(group.iter().cloned());
+        }
+        Resolver::new(
+            ResolverConfig::from_parts(None, vec![], nameservers),
+            ResolverOpts::default(),
+        )
+    }
+    .map_err(|e| dns_error(e.to_string()))?;
+    let response = resolver
+        .lookup_ip(host)
+        .map_err(|e| dns_error(e.to_string()))?;
+    let ips: Vec<IpAddr> = response.iter().collect();
+
+    if ips.is_empty() {
+        return Err(dns_error(String::from("no addresses found")));
+    }
+
+    Ok(ips)
+}
+
+fn shark_fix_common(conf: &mut config::Config, log: &Logger) {
+    let mut new_sharks = Vec::with_capacity(conf.sharks.len());
+
+    for shark in conf.sharks.iter() {
+        if !shark.contains(conf.domain.as_str()) {
+            let new_shark = format!("{}.{}", shark, conf.domain);
+            warn!(log,
+                  "Domain \"{}\" not found in storage node string:\"{}\", using \"{}\"",
+                  conf.domain,
+                  shark,
+                  new_shark
+            );
+
+            new_sharks.push(new_shark);
+        } else {
+            new_sharks.push(shark.to_owned());
+        }
+    }
+    conf.sharks = new_sharks;
+}
+
+fn validate_sharks(
+    conf: &config::Config,
+    log: &Logger,
+) -> Result<(), SharkspotterError> {
+    // Scanning dump files should never require access to a live Manta.
+    if conf.skip_validate_sharks || !conf.dump_files.is_empty() {
+        return Ok(());
+    }
+
+    let sharks = &conf.sharks;
+    let opts = moray_objects::MethodOptions::default();
+    let mut mclient = connect_to_moray(&moray_sockets(1, conf)?, 0, 1, log)?;
+
+    for shark in sharks.iter() {
+        let mut count = 0;
+        let filter = format!("manta_storage_id={}", shark);
+        mclient
+            .find_objects("manta_storage", filter.as_str(), &opts, |_| {
+                count += 1;
+                Ok(())
+            })
+            .map_err(|e| SharkspotterError::Query {
+                shard: 1,
+                cause: e.to_string(),
+            })?;
+
+        if count > 1 {
+            return Err(SharkspotterError::InvalidShark(format!(
+                "More than one shark with name \"{}\" found",
+                shark
+            )));
+        }
+
+        if count == 0 {
+            return Err(SharkspotterError::InvalidShark(format!(
+                "No shark with name \"{}\" found",
+                shark
+            )));
+        }
     }
 
     Ok(())
 }
 
+/// Build the list of sources to scan from the config: the dump files if any
+/// were given, otherwise one direct-DB source per shard, or one moray source
+/// per shard and id column.
+fn sources_from_config(
+    conf: &config::Config,
+    checkpoint: &Checkpoint,
+) -> Result<Vec<Box<dyn ObjectSource>>, SharkspotterError> {
+    let mut sources: Vec<Box<dyn ObjectSource>> = vec![];
+    let throttle = Throttle::new(conf);
+
+    if !conf.dump_files.is_empty() {
+        for (shard, path) in dump::dump_files_by_shard(conf)? {
+            sources.push(Box::new(dump::DumpObjectSource::new(
+                shard,
+                path,
+                &conf.cancel,
+            )));
+        }
+        return Ok(sources);
+    }
+
+    for shard in conf.min_shard..=conf.max_shard {
+        if conf.direct_db {
+            sources.push(Box::new(directdb::DirectDbObjectSource::new(
+                shard,
+                conf,
+                checkpoint,
+                &throttle.shard(shard),
+            )));
+            continue;
+        }
+
+        // Every address of the shard's moray is tried in turn if one of them
+        // fails.
+        let moray_sockets = moray_sockets(shard, conf)?;
+
+        // We can have both _id and _idx, we don't have to have both, but we
+        // need at least 1.  The shard is probed by whichever of its sources
+        // runs first, and the others use the same result.
+        let probe = SharedProbe::default();
+        let shard_throttle = throttle.shard(shard);
+        for id in ID_COLUMNS.iter() {
+            for split in 0..conf.shard_splits.max(1) {
+                sources.push(Box::new(MorayObjectSource::new(
+                    shard,
+                    id,
+                    moray_sockets.clone(),
+                    conf,
+                    checkpoint,
+                    &probe,
+                    &shard_throttle,
+                    split,
+                )));
+            }
+        }
+    }
+
+    Ok(sources)
+}
+
 /// Main entry point to for the sharkspotter library.  Callers need to
-/// provide a closure that takes a serde Value and a u32 shard number as its
-/// arguments.
+/// provide a closure that is given a `MatchRecord` for every object that is
+/// on one of the requested sharks.
 /// Sharkspotter works by first getting the maximum and minimum _id and _idx
 /// for a given moray bucket (which is always "manta"), and then querying for
 /// entries in a user configurable chunk size.
+/// If the config lists dump files then those are scanned instead, and moray
+/// is never contacted.
+/// A record that can not be parsed stops the scan of the shard it came from.
+/// See `run_with_error_handler` to change that.
+/// Errors scanning a shard do not stop the other shards from being scanned,
+/// they are returned in the RunReport instead.
 pub fn run<F>(
     config: &config::Config,
     log: Logger,
-    mut handler: F,
-) -> Result<(), Error>
+    handler: F,
+) -> Result<RunReport, SharkspotterError>
 where
-    F: FnMut(Value, &str, &str, u32) -> Result<(), Error>,
+    F: FnMut(MatchRecord) -> Result<(), Error>,
+{
+    run_with_error_handler(config, log, handler, Err)
+}
+
+/// Same as `run`, but errors that only affect a single record (see
+/// `SharkspotterError::is_record_error()`) are passed to the error handler.
+/// If the error handler returns Ok the scan carries on with the next record,
+/// otherwise the scan of that shard stops.
+pub fn run_with_error_handler<F, E>(
+    config: &config::Config,
+    log: Logger,
+    handler: F,
+    error_handler: E,
+) -> Result<RunReport, SharkspotterError>
+where
+    F: FnMut(MatchRecord) -> Result<(), Error>,
+    E: FnMut(SharkspotterError) -> Result<(), SharkspotterError>,
 {
     let mut conf = config.clone();
     shark_fix_common(&mut conf, &log);
     validate_sharks(&conf, &log)?;
 
-    for i in conf.min_shard..=conf.max_shard {
-        let moray_host = format!("{}.moray.{}", i, conf.domain);
-        let moray_ip = lookup_ip_str(moray_host.as_str())?;
-        let moray_socket = format!("{}:{}", moray_ip, 2021);
+    let checkpoint = Checkpoint::from_config(&conf)?;
+    let sources = sources_from_config(&conf, &checkpoint)?;
 
-        // TODO: MANTA-4912
-        // We can have both _id and _idx, we don't have to have both, but we
-        // need at least 1.  This is an error that should be passed back to
-        // the caller via the handler as noted in MANTA-4912.
-        for id in ["_id", "_idx"].iter() {
-            if let Err(e) =
-                iter_ids(id, &moray_socket, &conf, log.clone(), i, &mut handler)
-            {
-                error!(&log, "Encountered error scanning shard {} ({})", i, e);
-            }
+    let report = run_sources(sources, &conf, log, handler, error_handler);
+    checkpoint.flush()?;
+
+    Ok(report)
+}
+
+/// Scan each of the given sources in turn, calling the handler for every
+/// object that resides on one of `conf.sharks`, and the error handler for
+/// every record that could not be parsed.  An error scanning one source is
+/// logged and recorded in the report, and the remaining sources are still
+/// scanned.
+///
+/// Unlike `run`, the shark names in the config are used exactly as given.
+pub fn run_sources<F, E>(
+    sources: Vec<Box<dyn ObjectSource>>,
+    conf: &config::Config,
+    log: Logger,
+    mut handler: F,
+    mut error_handler: E,
+) -> RunReport
+where
+    F: FnMut(MatchRecord) -> Result<(), Error>,
+    E: FnMut(SharkspotterError) -> Result<(), SharkspotterError>,
+{
+    let mut on_match =
+        |record| handler(record).map_err(SharkspotterError::from);
+
+    let start = Instant::now();
+    let mut report = RunReport::default();
+
+    for mut source in sources {
+        if conf.cancel.is_cancelled() {
+            info!(log, "scan cancelled, not scanning {}", source.name());
+            break;
         }
+
+        report.add(scan_source(
+            source.as_mut(),
+            &log,
+            conf,
+            &mut on_match,
+            &mut error_handler,
+            &|| false,
+        ));
     }
 
-    Ok(())
+    report.duration = start.elapsed();
+    report
 }
 
-fn start_iter_ids_thread(
-    id_name: &str,
-    shard_num: u32,
-    moray_ip: String,
-    obj_tx: crossbeam_channel::Sender<SharkspotterMessage>,
-    log: Logger,
-    conf: config::Config,
-) -> impl Fn() -> () {
-    let moray_socket = format!("{}:{}", moray_ip, 2020);
-    let id_string = id_name.to_string();
-
-    move || {
-        if let Err(e) = iter_ids(
-            id_string.as_str(),
-            &moray_socket,
-            &conf,
-            log.clone(),
-            shard_num,
-            |manta_value, etag, shark, shard_num| {
-                let msg = SharkspotterMessage {
-                    manta_value,
-                    etag: etag.to_string(),
-                    shark: shark.to_string(),
-                    shard: shard_num,
-                };
-                obj_tx
-                    .send(msg)
-                    .map_err(|e| Error::new(ErrorKind::Other, e))
-            },
-        ) {
-            error!(
-                &log,
-                "Encountered error scanning shard {} ({})", shard_num, e
-            );
-            // TODO: MANTA-5360
+/// Where the scanner threads of a multithreaded run send what they find.
+trait MatchSender: Clone + Send + 'static {
+    fn send_match(
+        &self,
+        msg: SharkspotterMessage,
+    ) -> Result<(), SharkspotterError>;
+
+    /// Called with every record error.  Returning an error stops the scan of
+    /// the current source.
+    fn send_record_error(
+        &self,
+        err: SharkspotterError,
+    ) -> Result<(), SharkspotterError>;
+
+    /// Whether the receiver has gone away, in which case there is no point
+    /// in scanning any further.  A crossbeam channel can't tell until a send
+    /// to it fails, which `PoolSender` remembers for all of the workers.
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+/// The sender that the workers of a run share.  Once a send fails because
+/// the receiver has gone away, every worker stops pulling sources from the
+/// queue, not just the one whose send failed.
+#[derive(Clone)]
+struct PoolSender<S> {
+    tx: S,
+    closed: Arc<AtomicBool>,
+}
+
+impl<S: MatchSender> PoolSender<S> {
+    fn new(tx: S) -> Self {
+        PoolSender {
+            tx,
+            closed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn sent(
+        &self,
+        ret: Result<(), SharkspotterError>,
+    ) -> Result<(), SharkspotterError> {
+        if let Err(SharkspotterError::ChannelClosed) = ret {
+            self.closed.store(true, Ordering::SeqCst);
         }
+        ret
     }
 }
 
-fn run_moray_shard_thread(
-    pool: &ThreadPool,
-    shard: u32,
-    obj_tx: &crossbeam_channel::Sender<SharkspotterMessage>,
-    conf: &config::Config,
-    log: &Logger,
-) -> Result<(), Error> {
-    let moray_host = format!("{}.moray.{}", shard, conf.domain);
-    let moray_ip = lookup_ip_str(moray_host.as_str())?;
-
-    // TODO: MANTA-4912
-    // We can have both _id and _idx, we don't have to have both, but we
-    // need at least 1.  This is an error that should be passed back to
-    // the caller via the handler as noted in MANTA-4912.
-    // See also MANTA-5360
-
-    // Create a thread for both _id and _idx in case we have both.
-    for id in ["_id", "_idx"].iter() {
-        pool.execute(start_iter_ids_thread(
-            id,
-            shard,
-            moray_ip.clone(),
-            obj_tx.clone(),
-            log.clone(),
-            conf.clone(),
-        ));
+impl<S: MatchSender> MatchSender for PoolSender<S> {
+    fn send_match(
+        &self,
+        msg: SharkspotterMessage,
+    ) -> Result<(), SharkspotterError> {
+        self.sent(self.tx.send_match(msg))
     }
 
-    Ok(())
+    fn send_record_error(
+        &self,
+        err: SharkspotterError,
+    ) -> Result<(), SharkspotterError> {
+        self.sent(self.tx.send_record_error(err))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst) || self.tx.is_closed()
+    }
 }
 
-fn run_direct_db_shard_thread(
-    pool: &ThreadPool,
-    shard: u32,
-    obj_tx: &crossbeam_channel::Sender<SharkspotterMessage>,
+impl MatchSender for crossbeam_channel::Sender<SharkspotterMessage> {
+    fn send_match(
+        &self,
+        msg: SharkspotterMessage,
+    ) -> Result<(), SharkspotterError> {
+        self.send(msg).map_err(|_| SharkspotterError::ChannelClosed)
+    }
+
+    // There is no way to pass the error back over this channel, so the
+    // record error stops the scan of the source.
+    fn send_record_error(
+        &self,
+        err: SharkspotterError,
+    ) -> Result<(), SharkspotterError> {
+        Err(err)
+    }
+}
+
+impl MatchSender
+    for crossbeam_channel::Sender<
+        Result<SharkspotterMessage, SharkspotterError>,
+    >
+{
+    fn send_match(
+        &self,
+        msg: SharkspotterMessage,
+    ) -> Result<(), SharkspotterError> {
+        self.send(Ok(msg))
+            .map_err(|_| SharkspotterError::ChannelClosed)
+    }
+
+    fn send_record_error(
+        &self,
+        err: SharkspotterError,
+    ) -> Result<(), SharkspotterError> {
+        self.send(Err(err))
+            .map_err(|_| SharkspotterError::ChannelClosed)
+    }
+}
+
+/// Scan units of work from the queue until there are none left.  Each
+/// source is split into a unit of `conf.work_unit_size` ids, with the rest
+/// going to the back of the queue for whichever worker is free next.
+fn scan_work_units<S: MatchSender>(
+    queue: &WorkQueue<Box<dyn ObjectSource>>,
+    obj_tx: &S,
+    on_report: &mut dyn FnMut(ShardReport),
     conf: &config::Config,
     log: &Logger,
 ) {
-    let th_obj_tx = obj_tx.clone();
-    let th_conf = conf.clone();
-    let th_log = log.clone();
-
-    pool.execute(move || {
-        // In test we noticed that the basic scheduler outperformed both the
-        // `threaded_scheduler()` with tuned thread counts and the default
-        // thread counts provided by `Runtime::new()` by 33%.  It also does not
-        // create any additional LWPs.
-        let mut rt = match tokio::runtime::Builder::new()
-            .enable_all()
-            .basic_scheduler()
-            .build()
-        {
-            Ok(r) => r,
+    let mut send_match = |record| {
+        obj_tx.send_match(record).map_err(|e| {
+            warn!(log, "Tx channel disconnected: {}", e);
+            e
+        })
+    };
+    let mut send_record_error = |err| obj_tx.send_record_error(err);
+
+    while let Some(mut source) = queue.next() {
+        if obj_tx.is_closed() || conf.cancel.is_cancelled() {
+            debug!(log, "scan stopped, not scanning {}", source.name());
+            break;
+        }
+
+        let report = match source.split_off(log, conf.work_unit_size) {
+            Ok(rest) => {
+                if let Some(rest) = rest {
+                    debug!(log, "split off {}", rest.name());
+                    queue.push(rest);
+                }
+
+                scan_source(
+                    source.as_mut(),
+                    log,
+                    conf,
+                    &mut send_match,
+                    &mut send_record_error,
+                    &|| obj_tx.is_closed(),
+                )
+            }
             Err(e) => {
-                error!(th_log, "could not create runtime: {}", e);
-                ERROR_LIST.lock().expect("ERROR_LIST lock").push(e);
-                return;
+                error!(log, "Could not split {} ({})", source.name(), e);
+                let mut report = ShardReport::new(source.shard());
+                report.status = ShardStatus::Failed;
+                report.errors.push(e);
+                report
             }
         };
 
-        if let Err(e) = rt.block_on(directdb::get_objects_from_shard(
-            shard,
-            th_conf,
-            th_log.clone(),
-            th_obj_tx,
-        )) {
-            // We use BrokenPipe in directdb::send_matching_object() to
-            // indicate that our receiver has shutdown.
-            // This is not an error in the context of lib sharkspotter.  The
-            // consumer of sharkspotter may encounter an error which causes
-            // it to stop receiving objects, but that error should be
-            // handled by the consumer not here.
-            if e.kind() != ErrorKind::BrokenPipe {
-                error!(th_log, "shard thread error: {}", e);
-            }
-            ERROR_LIST.lock().expect("ERROR_LIST lock").push(e);
-        }
-    });
+        on_report(report);
+    }
 }
 
-/// Same as the regular `run` method, but instead we spawn a new thread per
-/// shard and send the information back to the caller via a crossbeam
-/// mpmc channel.
-pub fn run_multithreaded(
-    config: &config::Config,
+fn run_sources_on_pool<S: MatchSender>(
+    sources: Vec<Box<dyn ObjectSource>>,
+    conf: &config::Config,
+    log: Logger,
+    obj_tx: S,
+) -> RunReport {
+    let start = Instant::now();
+    let pool = ThreadPool::with_name("shard_scanner".into(), conf.max_threads);
+    let (report_tx, report_rx) = crossbeam_channel::unbounded();
+    let queue = Arc::new(WorkQueue::new(sources));
+    let obj_tx = PoolSender::new(obj_tx);
+
+    for _ in 0..conf.max_threads {
+        let th_queue = Arc::clone(&queue);
+        let th_obj_tx = obj_tx.clone();
+        let th_report_tx = report_tx.clone();
+        let th_conf = conf.clone();
+        let th_log = log.clone();
+
+        pool.execute(move || {
+            // The receiver is held until the pool has been joined.
+            scan_work_units(
+                &th_queue,
+                &th_obj_tx,
+                &mut |report| {
+                    th_report_tx.send(report).expect("report channel")
+                },
+                &th_conf,
+                &th_log,
+            );
+        });
+    }
+
+    pool.join();
+    drop(report_tx);
+
+    let mut report = RunReport::default();
+    for shard_report in report_rx.iter() {
+        report.add(shard_report);
+    }
+
+    report.duration = start.elapsed();
+    report
+}
+
+/// Same as `run_sources`, but the sources are split into units of work that
+/// are scanned by `conf.max_threads` threads (see `scheduler`), and the
+/// matches are sent back to the caller via a crossbeam mpmc channel.  A
+/// record that can not be parsed stops the scan of the source it came from.
+pub fn run_sources_multithreaded(
+    sources: Vec<Box<dyn ObjectSource>>,
+    conf: &config::Config,
     log: Logger,
     obj_tx: crossbeam_channel::Sender<SharkspotterMessage>,
-) -> Result<(), Error> {
+) -> RunReport {
+    run_sources_on_pool(sources, conf, log, obj_tx)
+}
+
+/// Same as `run_sources_multithreaded`, but record errors are sent over the
+/// channel alongside the matches and the scan carries on.  Callers that want
+/// to stop scanning can drop the receiver.
+pub fn run_sources_multithreaded_with_errors(
+    sources: Vec<Box<dyn ObjectSource>>,
+    conf: &config::Config,
+    log: Logger,
+    obj_tx: crossbeam_channel::Sender<
+        Result<SharkspotterMessage, SharkspotterError>,
+    >,
+) -> RunReport {
+    run_sources_on_pool(sources, conf, log, obj_tx)
+}
+
+/// Get the config ready for a multithreaded run, and check that the sharks
+/// exist.
+fn prepare_run(
+    config: &config::Config,
+    log: &Logger,
+) -> Result<(config::Config, Checkpoint), SharkspotterError> {
     let mut conf = config.clone();
     config::normalize_config(&mut conf);
 
-    let pool = ThreadPool::with_name("shard_scanner".into(), conf.max_threads);
+    shark_fix_common(&mut conf, log);
+    validate_sharks(&conf, log)?;
 
-    shark_fix_common(&mut conf, &log);
-    validate_sharks(&conf, &log)?;
+    let checkpoint = Checkpoint::from_config(&conf)?;
+    Ok((conf, checkpoint))
+}
 
-    for shard in conf.min_shard..=conf.max_shard {
-        if conf.direct_db {
-            run_direct_db_shard_thread(&pool, shard, &obj_tx, &conf, &log);
-        } else {
-            run_moray_shard_thread(&pool, shard, &obj_tx, &conf, &log)?;
-        }
-    }
+fn run_multithreaded_common<S: MatchSender>(
+    config: &config::Config,
+    log: Logger,
+    obj_tx: S,
+) -> Result<RunReport, SharkspotterError> {
+    let (conf, checkpoint) = prepare_run(config, &log)?;
+    let sources = sources_from_config(&conf, &checkpoint)?;
 
-    pool.join();
+    let report = run_sources_on_pool(sources, &conf, log, obj_tx);
+    checkpoint.flush()?;
 
-    let mut error_strings = String::new();
-    let error_list = ERROR_LIST.lock().unwrap();
-    for error in error_list.iter() {
-        if error.kind() == ErrorKind::BrokenPipe {
-            continue;
-        }
-        error_strings = format!("{}{}\n", error_strings, error);
-    }
+    Ok(report)
+}
 
-    if !error_strings.is_empty() {
-        let msg = format!(
-            "Sharkspotter encountered the following errors:\n{}",
-            error_strings
-        );
-        return Err(Error::new(ErrorKind::Other, msg));
-    }
+/// Same as the regular `run` method, but the shards are scanned by up to
+/// `max_threads` threads that share out the work between them (see
+/// `scheduler`), and the information is sent back to the caller via a
+/// crossbeam mpmc channel.
+pub fn run_multithreaded(
+    config: &config::Config,
+    log: Logger,
+    obj_tx: crossbeam_channel::Sender<SharkspotterMessage>,
+) -> Result<RunReport, SharkspotterError> {
+    run_multithreaded_common(config, log, obj_tx)
+}
 
-    Ok(())
+/// Same as `run_multithreaded`, but record errors (with the shard and _id of
+/// the record) are sent over the channel alongside the matches, and the scan
+/// carries on.  Callers that want to stop scanning can drop the receiver.
+pub fn run_multithreaded_with_errors(
+    config: &config::Config,
+    log: Logger,
+    obj_tx: crossbeam_channel::Sender<
+        Result<SharkspotterMessage, SharkspotterError>,
+    >,
+) -> Result<RunReport, SharkspotterError> {
+    run_multithreaded_common(config, log, obj_tx)
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[test]
     fn _parse_max_id_value_test() {
@@ -793,4 +2028,754 @@ mod tests {
         }]);
         assert!(_parse_max_id_value(num_value_num, &log).is_ok());
     }
+
+    #[test]
+    fn lookup_ip_str_test() {
+        let mut dns = config::DnsConfig::default();
+        dns.hosts
+            .insert(String::from("1.moray.test"), IpAddr::from([127, 0, 0, 1]));
+
+        assert_eq!(lookup_ip_str("1.moray.test", &dns).unwrap(), "127.0.0.1");
+        assert_eq!(lookup_ip_str("10.0.0.1", &dns).unwrap(), "10.0.0.1");
+    }
+
+    #[test]
+    fn moray_sockets_test() {
+        let mut conf = config::Config {
+            domain: String::from("test"),
+            ..Default::default()
+        };
+        conf.dns
+            .hosts
+            .insert(String::from("1.moray.test"), "::1".parse().unwrap());
+        conf.dns
+            .hosts
+            .insert(String::from("2.moray.test"), IpAddr::from([10, 0, 0, 2]));
+
+        assert_eq!(moray_sockets(1, &conf).unwrap(), vec!["[::1]:2020"]);
+        assert_eq!(moray_sockets(2, &conf).unwrap(), vec!["10.0.0.2:2020"]);
+    }
+
+    #[test]
+    fn moray_query_error_test() {
+        let kind = |e| match moray_query_error(1, e) {
+            SharkspotterError::Query { .. } => "query",
+            SharkspotterError::Disconnected { .. } => "disconnected",
+            SharkspotterError::QueryTimeout { .. } => "timeout",
+            e => panic!("unexpected error {:?}", e),
+        };
+        let moray_error = |msg: &str| Error::new(ErrorKind::Other, msg);
+
+        assert_eq!(
+            kind(Error::new(ErrorKind::ConnectionReset, "reset")),
+            "disconnected"
+        );
+        assert_eq!(
+            kind(moray_error("NoDatabasePeersError: no peers")),
+            "disconnected"
+        );
+        assert_eq!(
+            kind(Error::new(ErrorKind::TimedOut, "timed out")),
+            "timeout"
+        );
+        assert_eq!(
+            kind(moray_error("QueryTimeoutError: query timeout")),
+            "timeout"
+        );
+
+        // Nothing is gained by running a bad query again.
+        assert_eq!(
+            kind(moray_error("InvalidQueryError: syntax error at \"SELCT\"")),
+            "query"
+        );
+        assert_eq!(
+            kind(moray_error("permission denied for relation manta")),
+            "query"
+        );
+    }
+
+    #[test]
+    fn chunk_query_test() {
+        assert_eq!(
+            chunk_query("_id", 1, 100, 100, &[]),
+            "SELECT * FROM manta WHERE _id >= 1 AND _id <= 100 AND \
+             type = 'object' ORDER BY _id LIMIT 100;"
+        );
+
+        // Rows of the _idx scan that the _id scan covers are skipped, and
+        // those are the rows that are counted as overlapping.
+        let covered = [("_id", IdRange { begin: 0, end: 50 })];
+        assert_eq!(
+            chunk_query("_idx", 1, 100, 100, &covered),
+            "SELECT * FROM manta WHERE _idx >= 1 AND _idx <= 100 AND \
+             type = 'object' AND (_id IS NULL OR _id < 0 OR _id > 50) \
+             ORDER BY _idx LIMIT 100;"
+        );
+        assert_eq!(
+            overlap_query("_idx", 0, 200, &covered),
+            "SELECT count(*) FROM manta WHERE _idx >= 0 AND _idx <= 200 AND \
+             type = 'object' AND ((_id >= 0 AND _id <= 50));"
+        );
+    }
+
+    /// Answers chunk queries from a fixed set of manta rows the way moray
+    /// would, and can be told to fail part way through a request.
+    struct FakeMoray {
+        rows: Vec<Value>,
+        fail_after: Option<usize>,
+        timeout_above: Option<u64>,
+    }
+
+    impl FakeMoray {
+        fn new(ids: &[u64], directories: &[u64]) -> Self {
+            let row = |id: u64, kind: &str| {
+                json!({
+                    "_id": id,
+                    "_etag": "E",
+                    "type": kind,
+                    "_value": json!({
+                        "objectId": id.to_string(),
+                        "type": kind,
+                    }).to_string(),
+                })
+            };
+            let mut rows: Vec<Value> =
+                ids.iter().map(|id| row(*id, "object")).collect();
+            rows.extend(directories.iter().map(|id| row(*id, "directory")));
+
+            FakeMoray {
+                rows,
+                fail_after: None,
+                timeout_above: None,
+            }
+        }
+    }
+
+    impl MoraySql for FakeMoray {
+        fn sql(
+            &mut self,
+            stmt: &str,
+            _opts: &str,
+            handler: &mut dyn FnMut(&Value) -> Result<(), Error>,
+        ) -> Result<(), Error> {
+            // Pull "_id >= N", "_id <= N" and "LIMIT N" out of the query.
+            let arg = |op: &str| -> u64 {
+                let start = stmt.find(op).expect("query arg") + op.len();
+                stmt[start..]
+                    .split(|c: char| !c.is_ascii_digit())
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("query number")
+            };
+            let (begin, end, limit) =
+                (arg("_id >= "), arg("_id <= "), arg("LIMIT "));
+            assert!(stmt.contains("ORDER BY _id"));
+
+            if let Some(max_width) = self.timeout_above {
+                if end - begin + 1 > max_width {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "QueryTimeoutError: query timeout",
+                    ));
+                }
+            }
+
+            let mut rows: Vec<&Value> = self
+                .rows
+                .iter()
+                .filter(|row| {
+                    let id = row["_id"].as_u64().unwrap();
+                    row["type"] == "object" && id >= begin && id <= end
+                })
+                .collect();
+            rows.sort_by_key(|row| row["_id"].as_u64());
+
+            for (i, row) in rows.into_iter().take(limit as usize).enumerate() {
+                if self.fail_after == Some(i) {
+                    self.fail_after = None;
+                    return Err(Error::new(
+                        ErrorKind::ConnectionReset,
+                        "connection reset by peer",
+                    ));
+                }
+                handler(&json!([row]))?;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// A source for the _id column of shard 1 of a fake moray.
+    fn fake_moray_source(conf: &config::Config) -> MorayObjectSource {
+        let mut source = MorayObjectSource::new(
+            1,
+            "_id",
+            vec![String::from("127.0.0.1:2020")],
+            conf,
+            &Checkpoint::from_config(conf).unwrap(),
+            &SharedProbe::default(),
+            &Throttle::new(conf).shard(1),
+            0,
+        );
+
+        // Don't keep the tests waiting on retries.
+        source.backoff.base_delay = Duration::from_millis(1);
+        source
+    }
+
+    /// Scan the range of the _id column of a fake moray that holds the given
+    /// objects and directories, with the first request that gets as far as
+    /// `fail_after` rows failing.
+    fn scan_fake_moray(
+        conf: &config::Config,
+        ids: &[u64],
+        directories: &[u64],
+        fail_after: Option<usize>,
+        range: IdRange,
+    ) -> Vec<u64> {
+        let log = slog_scope::logger();
+        let source = fake_moray_source(conf);
+        let mut mclient = FakeMoray::new(ids, directories);
+        mclient.fail_after = fail_after;
+        let mut found = vec![];
+
+        iter_ids(
+            &source,
+            &mut mclient,
+            &|_| Ok(FakeMoray::new(ids, directories)),
+            log,
+            range,
+            &[],
+            |obj| {
+                found.push(obj["_id"].as_u64().unwrap());
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        found
+    }
+
+    #[test]
+    fn iter_ids_test() {
+        let _guard = util::init_global_logger(None);
+        let conf = config::Config {
+            chunk_size: 10,
+            ..Default::default()
+        };
+        let range = |begin, end| IdRange { begin, end };
+
+        // Ids that are denser than the chunk size, with a run of every id in
+        // one chunk, take more than one page per chunk.  Directories are
+        // never visited.
+        let dense: Vec<u64> = (1..=100).chain(150..400).collect();
+        let directories: Vec<u64> = (400..450).collect();
+        let found =
+            scan_fake_moray(&conf, &dense, &directories, None, range(1, 500));
+        assert_eq!(found, dense);
+
+        // Sparse ids, including ones on the edges of chunks and pages.
+        let sparse = vec![1, 9, 10, 11, 20, 21, 99, 100, 1000, 1001];
+        let found = scan_fake_moray(&conf, &sparse, &[], None, range(1, 1001));
+        assert_eq!(found, sparse);
+
+        // Only the part of the range that was asked for is scanned.
+        let found = scan_fake_moray(&conf, &sparse, &[], None, range(10, 99));
+        assert_eq!(found, vec![10, 11, 20, 21, 99]);
+
+        // Chunks that grow to many pages wide, and a request that fails part
+        // way through a page and is retried on another moray.
+        let conf = config::Config {
+            chunk_size: 10,
+            min_chunk_size: 10,
+            max_chunk_size: 1000,
+            max_retries: 1,
+            ..Default::default()
+        };
+        let found = scan_fake_moray(
+            &conf,
+            &dense,
+            &directories,
+            Some(5),
+            range(1, 500),
+        );
+        assert_eq!(found, dense);
+    }
+
+    #[test]
+    fn read_chunk_test() {
+        let _guard = util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let ids: Vec<u64> = (1..=5).collect();
+        let mut mclient = FakeMoray::new(&ids, &[]);
+        let query = chunk_query("_id", 1, 5, 5, &[]);
+        let mut found = vec![];
+
+        // The time spent in the handler isn't counted as query time.
+        let query_time =
+            read_chunk(&log, &mut mclient, &query, "", 1, &mut |obj| {
+                found.push(obj["_id"].as_u64().unwrap());
+                std::thread::sleep(Duration::from_millis(20));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(found, ids);
+        assert!(query_time < Duration::from_millis(20), "{:?}", query_time);
+    }
+
+    #[test]
+    fn iter_ids_timeout_test() {
+        let _guard = util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let conf = config::Config {
+            chunk_size: 100,
+            min_chunk_size: 10,
+            max_chunk_size: 1000,
+            max_retries: 0,
+            ..Default::default()
+        };
+        let source = fake_moray_source(&conf);
+        let ids: Vec<u64> = (1..=1000).step_by(3).collect();
+        let fake_moray = || {
+            let mut mclient = FakeMoray::new(&ids, &[]);
+            mclient.timeout_above = Some(40);
+            mclient
+        };
+        let mut found = vec![];
+
+        // Queries over more than 40 ids time out, so the chunks are narrowed
+        // until they don't, without using up any retries.
+        iter_ids(
+            &source,
+            &mut fake_moray(),
+            &|_| Ok(fake_moray()),
+            log,
+            IdRange {
+                begin: 1,
+                end: 1000,
+            },
+            &[],
+            |obj| {
+                found.push(obj["_id"].as_u64().unwrap());
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(found, ids);
+    }
+
+    #[test]
+    fn iter_ids_cancel_test() {
+        let _guard = util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let conf = config::Config {
+            chunk_size: 10,
+            ..Default::default()
+        };
+        let source = fake_moray_source(&conf);
+        let ids: Vec<u64> = (1..=100).collect();
+        let mut mclient = FakeMoray::new(&ids, &[]);
+        let mut found = vec![];
+
+        // The chunk that was being read when the scan was cancelled is
+        // finished, but no more are read.
+        let ret = iter_ids(
+            &source,
+            &mut mclient,
+            &|_| Ok(FakeMoray::new(&ids, &[])),
+            log,
+            IdRange { begin: 1, end: 100 },
+            &[],
+            |obj| {
+                conf.cancel.cancel();
+                found.push(obj["_id"].as_u64().unwrap());
+                Ok(())
+            },
+        );
+        match ret {
+            Err(SharkspotterError::Cancelled) => (),
+            ret => panic!("unexpected result {:?}", ret),
+        }
+        assert_eq!(found, (1..=10).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn moray_split_off_test() {
+        let _guard = util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let conf = config::Config::default();
+        let mut source = fake_moray_source(&conf);
+
+        // Nothing listens on the source's moray, so the range has to come
+        // from the probe that another source for the shard already ran.
+        source
+            .probe
+            .get_or_probe(|| {
+                Ok(ShardProbe {
+                    shard: 1,
+                    id_columns: vec![IdColumn {
+                        name: String::from("_id"),
+                        data_type: String::from("integer"),
+                        range: Some(IdRange { begin: 5, end: 25 }),
+                    }],
+                })
+            })
+            .unwrap();
+
+        assert_eq!(
+            source.id_range(&log).unwrap(),
+            Some(IdRange { begin: 5, end: 25 })
+        );
+
+        let mut rest = source.split_off(&log, 10).unwrap().expect("split");
+        assert_eq!(
+            source.id_range(&log).unwrap(),
+            Some(IdRange { begin: 5, end: 9 })
+        );
+        assert_eq!(
+            rest.id_range(&log).unwrap(),
+            Some(IdRange { begin: 10, end: 25 })
+        );
+        assert!(rest.split_off(&log, 20).unwrap().is_some());
+        assert!(rest.split_off(&log, 20).unwrap().is_none());
+    }
+
+    /// A source that hands out the given records, and counts them in
+    /// `handed_out`, which is shared with any source split off from it.
+    pub(crate) struct FixtureSource {
+        shard: u32,
+        records: Vec<Value>,
+        handed_out: Arc<AtomicUsize>,
+    }
+
+    impl FixtureSource {
+        pub(crate) fn new(shard: u32, records: Vec<Value>) -> Self {
+            FixtureSource::counted(shard, records, &Arc::default())
+        }
+
+        pub(crate) fn counted(
+            shard: u32,
+            records: Vec<Value>,
+            handed_out: &Arc<AtomicUsize>,
+        ) -> Self {
+            FixtureSource {
+                shard,
+                records,
+                handed_out: Arc::clone(handed_out),
+            }
+        }
+    }
+
+    impl ObjectSource for FixtureSource {
+        fn shard(&self) -> u32 {
+            self.shard
+        }
+
+        fn name(&self) -> String {
+            format!("fixture {}", self.shard)
+        }
+
+        // Treat each record as a single id.
+        fn split_off(
+            &mut self,
+            _log: &Logger,
+            max_ids: u64,
+        ) -> Result<Option<Box<dyn ObjectSource>>, SharkspotterError> {
+            if self.records.len() as u64 <= max_ids {
+                return Ok(None);
+            }
+
+            Ok(Some(Box::new(FixtureSource::counted(
+                self.shard,
+                self.records.split_off(max_ids as usize),
+                &self.handed_out,
+            ))))
+        }
+
+        fn scan(
+            &mut self,
+            _log: &Logger,
+            handler: &mut dyn FnMut(&Value) -> Result<(), SharkspotterError>,
+        ) -> Result<(), SharkspotterError> {
+            for record in self.records.iter() {
+                self.handed_out.fetch_add(1, Ordering::SeqCst);
+                handler(record)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn fixture_sources() -> Vec<Box<dyn ObjectSource>> {
+        let record = |id: &str, obj_type: &str, sharks: &[&str], etag: &str| {
+            let sharks: Vec<Value> = sharks
+                .iter()
+                .map(|s| json!({"datacenter": "dc0", "manta_storage_id": s}))
+                .collect();
+            let manta_value =
+                json!({"objectId": id, "type": obj_type, "sharks": sharks});
+            json!({
+                "_etag": etag,
+                "_value": manta_value.to_string(),
+            })
+        };
+
+        vec![
+            Box::new(FixtureSource::new(
+                1,
+                vec![
+                    record("a", "object", &["1.stor", "2.stor"], "A"),
+                    record("b", "directory", &[], "B"),
+                ],
+            )),
+            Box::new(FixtureSource::new(
+                2,
+                vec![
+                    record("c", "object", &["2.stor"], "C"),
+                    record("d", "object", &["3.stor"], "D"),
+                ],
+            )),
+        ]
+    }
+
+    #[test]
+    fn run_sources_test() {
+        let _guard = util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let conf = config::Config {
+            sharks: vec![String::from("1.stor"), String::from("2.stor")],
+            ..Default::default()
+        };
+        let expected = vec!["a A 1.stor 1", "a A 2.stor 1", "c C 2.stor 2"];
+
+        let mut found = vec![];
+        let report = run_sources(
+            fixture_sources(),
+            &conf,
+            log.clone(),
+            |record| {
+                let obj_id = record.object.object_id.unwrap();
+                found.push(format!(
+                    "{} {} {} {}",
+                    obj_id, record.etag, record.shark, record.shard
+                ));
+                Ok(())
+            },
+            Err,
+        );
+        assert_eq!(found, expected);
+        assert!(report.is_complete());
+        assert_eq!(report.shards.len(), 2);
+        assert_eq!(report.scanned(), 4);
+        assert_eq!(report.matched(), 3);
+
+        // An object on both sharks is only passed along once.
+        let per_object_conf = config::Config {
+            per_object: true,
+            ..conf.clone()
+        };
+        let mut found = vec![];
+        let report = run_sources(
+            fixture_sources(),
+            &per_object_conf,
+            log.clone(),
+            |record| {
+                let obj_id = record.object.object_id.unwrap();
+                found.push(format!(
+                    "{} {}",
+                    obj_id,
+                    record.matching_sharks.join(",")
+                ));
+                Ok(())
+            },
+            Err,
+        );
+        assert_eq!(found, vec!["a 1.stor,2.stor", "c 2.stor"]);
+        assert_eq!(report.matched(), 2);
+
+        // Split every record off into its own unit of work.
+        let conf = config::Config {
+            max_threads: 3,
+            work_unit_size: 1,
+            ..conf
+        };
+        let (obj_tx, obj_rx) = crossbeam_channel::unbounded();
+        let report =
+            run_sources_multithreaded(fixture_sources(), &conf, log, obj_tx);
+        assert!(report.is_complete());
+        assert_eq!(report.shards.len(), 2);
+        assert_eq!(report.scanned(), 4);
+        assert_eq!(report.matched(), 3);
+
+        let mut found: Vec<String> = obj_rx
+            .iter()
+            .map(|msg| {
+                let obj_id =
+                    object_id_from_manta_obj(&msg.manta_value).unwrap();
+                format!("{} {} {} {}", obj_id, msg.etag, msg.shark, msg.shard)
+            })
+            .collect();
+        found.sort();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn closed_channel_test() {
+        let _guard = util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let conf = config::Config {
+            sharks: vec![String::from("1.stor")],
+            max_threads: 2,
+            work_unit_size: 1,
+            ..Default::default()
+        };
+        let manta_value = json!({
+            "objectId": "a",
+            "type": "object",
+            "sharks": [{"datacenter": "dc0", "manta_storage_id": "1.stor"}],
+        });
+        let handed_out = Arc::default();
+        let sources: Vec<Box<dyn ObjectSource>> = (1..=4)
+            .map(|shard| {
+                let records = (0..10)
+                    .map(|_| {
+                        json!({
+                            "_etag": "E",
+                            "_value": manta_value.to_string(),
+                        })
+                    })
+                    .collect();
+                Box::new(FixtureSource::counted(shard, records, &handed_out))
+                    as Box<dyn ObjectSource>
+            })
+            .collect();
+
+        // The crossbeam channel can't say that it is closed, but once a send
+        // to it fails the workers stop taking units off the queue.
+        let (obj_tx, obj_rx) = crossbeam_channel::unbounded();
+        drop(obj_rx);
+        let report = run_sources_multithreaded(sources, &conf, log, obj_tx);
+        assert!(!report.is_complete());
+        assert!(handed_out.load(Ordering::SeqCst) <= 2);
+    }
+
+    fn malformed_source() -> Box<dyn ObjectSource> {
+        let good = |id: &str| {
+            let manta_value = json!({
+                "objectId": id,
+                "type": "object",
+                "sharks": [{"datacenter": "dc0", "manta_storage_id": "1.stor"}],
+            });
+            json!({"_etag": "E", "_value": manta_value.to_string()})
+        };
+
+        Box::new(FixtureSource::new(
+            3,
+            vec![
+                good("a"),
+                json!({"_id": 5, "_etag": "E", "_value": "not json"}),
+                json!({
+                    "_id": "6",
+                    "_etag": "E",
+                    "_value": json!({"type": "object"}).to_string(),
+                }),
+                good("d"),
+            ],
+        ))
+    }
+
+    #[test]
+    fn record_error_test() {
+        let _guard = util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let conf = config::Config {
+            sharks: vec![String::from("1.stor")],
+            ..Default::default()
+        };
+        let error_ids = |errors: &[SharkspotterError]| -> Vec<String> {
+            errors
+                .iter()
+                .map(|e| match e {
+                    SharkspotterError::MalformedRecord {
+                        shard, id, ..
+                    } => {
+                        format!("malformed {} {:?}", shard, id)
+                    }
+                    SharkspotterError::MissingSharks { shard, id, .. } => {
+                        format!("missing {} {:?}", shard, id)
+                    }
+                    e => panic!("unexpected error {:?}", e),
+                })
+                .collect()
+        };
+        let expected_errors = vec!["malformed 3 Some(5)", "missing 3 Some(6)"];
+
+        // By default the first bad record stops the scan of the source.
+        let mut found = vec![];
+        let report = run_sources(
+            vec![malformed_source()],
+            &conf,
+            log.clone(),
+            |record| {
+                found.push(
+                    object_id_from_manta_obj(&record.manta_value).unwrap(),
+                );
+                Ok(())
+            },
+            Err,
+        );
+        assert_eq!(found, vec!["a"]);
+        assert_eq!(report.shards[0].status, ShardStatus::Failed);
+        assert_eq!(
+            error_ids(&report.shards[0].errors),
+            ["malformed 3 Some(5)"]
+        );
+        assert_eq!(report.scanned(), 2);
+
+        let mut found = vec![];
+        let mut errors = vec![];
+        let report = run_sources(
+            vec![malformed_source()],
+            &conf,
+            log.clone(),
+            |record| {
+                found.push(
+                    object_id_from_manta_obj(&record.manta_value).unwrap(),
+                );
+                Ok(())
+            },
+            |e| {
+                errors.push(e);
+                Ok(())
+            },
+        );
+        assert_eq!(found, vec!["a", "d"]);
+        assert_eq!(error_ids(&errors), expected_errors);
+        assert!(report.is_complete());
+        assert_eq!(report.scanned(), 4);
+        assert_eq!(report.skipped(), 2);
+
+        let (obj_tx, obj_rx) = crossbeam_channel::unbounded();
+        let report = run_sources_multithreaded_with_errors(
+            vec![malformed_source()],
+            &conf,
+            log,
+            obj_tx,
+        );
+        assert!(report.is_complete());
+        assert_eq!(report.skipped(), 2);
+
+        let (found, errors): (Vec<_>, Vec<_>) =
+            obj_rx.iter().partition(Result::is_ok);
+        let found: Vec<String> = found
+            .into_iter()
+            .map(|msg| {
+                let msg = msg.unwrap();
+                object_id_from_manta_obj(&msg.manta_value).unwrap()
+            })
+            .collect();
+        let errors: Vec<SharkspotterError> =
+            errors.into_iter().map(|e| e.unwrap_err()).collect();
+        assert_eq!(found, vec!["a", "d"]);
+        assert_eq!(error_ids(&errors), expected_errors);
+    }
 }
@@ -17,11 +17,13 @@
 /// This file can be parsed with the `json` tool which allows users to filter
 /// on certain fields.
 ///
-use crossbeam_channel::{self, Receiver, Sender};
+use crossbeam_channel::{self, Receiver, RecvTimeoutError, Sender};
 use serde_json::Value;
+use sharkspotter::cancel::CancelToken;
 use sharkspotter::config::Config;
+use sharkspotter::report::RunReport;
 use sharkspotter::{util, SharkspotterMessage};
-use slog::{trace, Logger};
+use slog::{trace, warn, Logger};
 use std::collections::HashMap;
 use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
@@ -29,10 +31,18 @@ use std::io::{BufWriter, Error, ErrorKind};
 use std::path::Path;
 use std::process;
 use std::thread;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+
+// The exit status when the scan is stopped by SIGINT or SIGTERM.
+const EXIT_CANCELLED: i32 = 130;
+
+// How often the output of a multithreaded run is flushed.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
 
 fn write_mobj_to_file<W>(
     mut writer: W,
-    manta_obj: Value,
+    manta_obj: &Value,
     conf: &Config,
 ) -> Result<(), Error>
 where
@@ -42,14 +52,14 @@ where
     let obj_id_only = conf.obj_id_only;
 
     if obj_id_only {
-        let obj_id = sharkspotter::object_id_from_manta_obj(&manta_obj)
+        let obj_id = sharkspotter::object_id_from_manta_obj(manta_obj)
             .map_err(|e| {
                 eprintln!("{}", e);
                 Error::new(ErrorKind::Other, e)
             })?;
         out_bytes = obj_id.as_bytes().to_owned();
     } else {
-        out_bytes = serde_json::to_vec(&manta_obj)?;
+        out_bytes = serde_json::to_vec(manta_obj)?;
     }
 
     writer.write_all(&out_bytes)?;
@@ -58,28 +68,62 @@ where
     Ok(())
 }
 
-fn run_multithreaded<F>(
+/// Run a multithreaded scan, passing each message along with `output` to
+/// `on_recv` on a thread of its own.  The output is handed back once the scan
+/// is done.
+///
+/// The matches are still on their way when the scanner threads update the
+/// checkpoint, so the checkpoint is held back until they have been written
+/// out.  The output is flushed with `flush` every FLUSH_INTERVAL, and the
+/// checkpoint is told how many matches have been written each time.
+fn run_multithreaded<O, F, FF>(
     conf: &Config,
     log: Logger,
+    mut output: O,
     mut on_recv: F,
-) -> Result<(), Error>
+    mut flush: FF,
+) -> Result<O, Error>
 where
+    O: 'static + std::marker::Send,
     F: 'static
         + std::marker::Send
-        + FnMut(SharkspotterMessage) -> Result<(), Error>,
+        + FnMut(&mut O, SharkspotterMessage) -> Result<(), Error>,
+    FF: 'static + std::marker::Send + FnMut(&mut O) -> Result<(), Error>,
 {
     let channel: (Sender<SharkspotterMessage>, Receiver<SharkspotterMessage>) =
         crossbeam_channel::bounded(100);
     let obj_tx = channel.0;
     let obj_rx = channel.1;
+    let ack = conf.output_ack.clone();
+    ack.enable();
+
     let handle = thread::spawn(move || {
-        while let Ok(msg) = obj_rx.recv() {
-            on_recv(msg)?;
+        let mut received = 0;
+        let mut last_flush = Instant::now();
+
+        loop {
+            if last_flush.elapsed() >= FLUSH_INTERVAL {
+                flush(&mut output)?;
+                ack.written(received);
+                last_flush = Instant::now();
+            }
+
+            match obj_rx.recv_timeout(FLUSH_INTERVAL) {
+                Ok(msg) => {
+                    on_recv(&mut output, msg)?;
+                    received += 1;
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
         }
-        Ok(())
+
+        flush(&mut output)?;
+        ack.written(received);
+        Ok(output)
     });
 
-    sharkspotter::run_multithreaded(conf, log, obj_tx)?;
+    sharkspotter::run_multithreaded(conf, log, obj_tx)?.into_result()?;
     handle.join().expect("sharkspotter reader join")
 }
 
@@ -91,14 +135,22 @@ fn run_with_file_map(conf: Config, log: Logger) -> Result<(), Error> {
 
     for shark in conf.sharks.iter() {
         let dirname = format!("./{}", shark);
-        fs::create_dir(dirname.as_str())?;
+
+        // When resuming an interrupted scan the output files from the
+        // previous run are left intact and appended to.
+        if conf.resume {
+            fs::create_dir_all(dirname.as_str())?;
+        } else {
+            fs::create_dir(dirname.as_str())?;
+        }
 
         for shard in conf.min_shard..=conf.max_shard {
             let fname = filename(shark, shard);
             let path = Path::new(fname.as_str());
             let file = match OpenOptions::new()
                 .append(true)
-                .create_new(true)
+                .create(conf.resume)
+                .create_new(!conf.resume)
                 .open(path)
             {
                 Err(e) => panic!(
@@ -113,37 +165,68 @@ fn run_with_file_map(conf: Config, log: Logger) -> Result<(), Error> {
         }
     }
 
-    if conf.multithreaded {
+    let mut file_map = if conf.multithreaded {
         let closure_conf = conf.clone();
-        run_multithreaded(&conf, log.clone(), move |msg| {
-            let shark = msg.shark.replace(&domain_prefix, "");
-            let shard = msg.shard;
-            trace!(&log, "shark: {}, shard: {}", shark, shard);
-
-            // Only sharks that are in the config.sharks vector should be
-            // passed to the callback.  If we see a shark that wasn't
-            // specified that represents a programmer error.
-            let file = file_map
-                .get_mut(&filename(shark.as_str(), shard))
-                .expect("unexpected shark");
-
-            write_mobj_to_file(file, msg.manta_value, &closure_conf)
-        })
-    } else {
-        sharkspotter::run(
+        run_multithreaded(
             &conf,
             log.clone(),
-            |manta_obj, _etag, shark, shard| {
+            file_map,
+            move |file_map, msg| {
+                for shark in msg.matching_sharks.iter() {
+                    let shark = shark.replace(&domain_prefix, "");
+                    let shard = msg.shard;
+                    trace!(&log, "shark: {}, shard: {}", shark, shard);
+
+                    // Only sharks that are in the config.sharks vector
+                    // should be passed to the callback.  If we see a shark
+                    // that wasn't specified that represents a programmer
+                    // error.
+                    let file = file_map
+                        .get_mut(&filename(shark.as_str(), shard))
+                        .expect("unexpected shark");
+
+                    write_mobj_to_file(file, &msg.manta_value, &closure_conf)?;
+                }
+                Ok(())
+            },
+            |file_map| {
+                for writer in file_map.values_mut() {
+                    writer.flush()?;
+                }
+                Ok(())
+            },
+        )?
+    } else {
+        sharkspotter::run(&conf, log.clone(), |record| {
+            for shark in record.matching_sharks.iter() {
                 let shark = shark.replace(&domain_prefix, "");
+                let shard = record.shard;
                 trace!(&log, "shark: {}, shard: {}", shark, shard);
 
                 let file =
                     file_map.get_mut(&filename(shark.as_str(), shard)).unwrap();
 
-                write_mobj_to_file(file, manta_obj, &conf)
-            },
-        )
+                write_mobj_to_file(&mut *file, &record.manta_value, &conf)?;
+
+                // The checkpoint is updated once the handler returns, by
+                // which time the match has to be in the file.
+                if conf.checkpoint_file.is_some() {
+                    file.flush()?;
+                }
+            }
+            Ok(())
+        })
+        .and_then(RunReport::into_result)?;
+        file_map
+    };
+
+    // Make sure everything that was found is written out, and that any
+    // error doing so is reported, even if the scan was cut short.
+    for writer in file_map.values_mut() {
+        writer.flush()?;
     }
+
+    Ok(())
 }
 
 fn run_with_user_file(
@@ -162,16 +245,63 @@ fn run_with_user_file(
 
     if conf.multithreaded {
         let closure_conf = conf.clone();
-        run_multithreaded(&conf, log, move |msg| {
-            write_mobj_to_file(&mut file, msg.manta_value, &closure_conf)
-        })
+        run_multithreaded(
+            &conf,
+            log,
+            file,
+            move |file, msg| {
+                write_mobj_to_file(file, &msg.manta_value, &closure_conf)
+            },
+            |file| file.flush(),
+        )
+        .map(|_| ())
     } else {
-        sharkspotter::run(&conf, log, |moray_obj, _etag, _shark, _shard| {
-            write_mobj_to_file(&mut file, moray_obj, &conf)
+        sharkspotter::run(&conf, log, |record| {
+            write_mobj_to_file(&mut file, &record.manta_value, &conf)
         })
+        .and_then(RunReport::into_result)
+        .map_err(Error::from)
     }
 }
 
+/// Cancel the scan on SIGINT or SIGTERM.  The scan stops once each thread
+/// has finished the chunk it is reading, so that what has been written out
+/// matches the checkpoint.  A second signal exits straight away.
+fn cancel_on_signal(cancel: CancelToken, log: Logger) -> Result<(), Error> {
+    let mut rt = tokio::runtime::Builder::new()
+        .enable_all()
+        .basic_scheduler()
+        .build()?;
+    let (mut sigint, mut sigterm) = rt.enter(|| -> Result<_, Error> {
+        Ok((
+            signal(SignalKind::interrupt())?,
+            signal(SignalKind::terminate())?,
+        ))
+    })?;
+
+    thread::Builder::new()
+        .name("signal_handler".into())
+        .spawn(move || {
+            rt.block_on(async {
+                tokio::select! {
+                    _ = sigint.recv() => (),
+                    _ = sigterm.recv() => (),
+                }
+                warn!(log, "caught signal, stopping scan");
+                eprintln!("Stopping scan, signal again to exit now");
+                cancel.cancel();
+
+                tokio::select! {
+                    _ = sigint.recv() => (),
+                    _ = sigterm.recv() => (),
+                }
+                process::exit(EXIT_CANCELLED);
+            })
+        })?;
+
+    Ok(())
+}
+
 fn main() -> Result<(), Error> {
     let conf = Config::from_args().unwrap_or_else(|err| {
         eprintln!("Error parsing args: {}", err);
@@ -181,10 +311,29 @@ fn main() -> Result<(), Error> {
     let _guard = util::init_global_logger(Some(conf.log_level));
     let log = slog_scope::logger();
 
+    let cancel = conf.cancel.clone();
+    cancel_on_signal(cancel.clone(), log.clone())?;
+
     let filename = conf.output_file.clone();
+    let checkpoint_file = conf.checkpoint_file.clone();
 
     match filename {
         Some(fname) => run_with_user_file(fname, conf, log),
         None => run_with_file_map(conf, log),
+    }?;
+
+    if cancel.is_cancelled() {
+        match checkpoint_file {
+            Some(file) => eprintln!(
+                "Scan stopped early, run again with '--resume {}' to finish it",
+                file
+            ),
+            None => eprintln!("Scan stopped early"),
+        }
+
+        drop(_guard);
+        process::exit(EXIT_CANCELLED);
     }
+
+    Ok(())
 }
@@ -0,0 +1,177 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+// Look up the password for a direct-DB connection in a libpq password file,
+// so that the same ~/.pgpass that psql uses works for sharkspotter too.  Each
+// line of the file is of the form:
+//
+//     hostname:port:database:username:password
+//
+// where any of the first four fields can be "*" to match anything, and ":" or
+// "\" in a field are escaped with a "\".  The first line that matches is
+// used.  As with libpq, a connection over a Unix socket matches a hostname of
+// "localhost", and the file is ignored if anyone but its owner can read it.
+
+use slog::{debug, warn, Logger};
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+/// The password file to use if none is configured: $PGPASSFILE, or
+/// ~/.pgpass.
+pub fn default_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("PGPASSFILE") {
+        return Some(PathBuf::from(path));
+    }
+
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".pgpass"))
+}
+
+// Split a line into its fields, undoing the escapes.
+fn split_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    fields.last_mut().unwrap().push(escaped);
+                }
+            }
+            ':' => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+
+    fields
+}
+
+/// Find the password for a connection in the contents of a password file.
+pub fn find_password(
+    contents: &str,
+    host: &str,
+    port: u16,
+    dbname: &str,
+    user: &str,
+) -> Option<String> {
+    let port = port.to_string();
+    let wanted = [host, port.as_str(), dbname, user];
+
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(split_line)
+        .filter(|fields| fields.len() == 5)
+        .find(|fields| {
+            fields
+                .iter()
+                .zip(wanted.iter())
+                .all(|(field, want)| field == "*" || field == want)
+        })
+        .map(|mut fields| fields.remove(4))
+}
+
+/// Look up the password for a connection in the given password file, or the
+/// default one.  Problems reading the file are logged and treated as if
+/// there was no password for the connection, as libpq does.
+pub fn lookup_password(
+    path: Option<&str>,
+    host: &str,
+    port: u16,
+    dbname: &str,
+    user: &str,
+    log: &Logger,
+) -> Option<String> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => default_path()?,
+    };
+
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!(log, "could not read {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    if metadata.permissions().mode() & 0o077 != 0 {
+        warn!(
+            log,
+            "ignoring password file {}, which others can read (it should \
+             be mode 0600 or less)",
+            path.display()
+        );
+        return None;
+    }
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            warn!(log, "could not read {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    let password = find_password(&contents, host, port, dbname, user);
+    if password.is_some() {
+        debug!(
+            log,
+            "using password for {}@{} from {}",
+            user,
+            host,
+            path.display()
+        );
+    }
+
+    password
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_password_test() {
+        let contents = "\
+            # comment\n\
+            \n\
+            db1.example.com:5432:moray:postgres:one\n\
+            *:5432:moray:reader:two\\:colon\n\
+            localhost:*:*:*:three\n\
+            *:*:*:*:fallback\n";
+
+        let find = |host, port, dbname, user| {
+            find_password(contents, host, port, dbname, user)
+        };
+
+        assert_eq!(
+            find("db1.example.com", 5432, "moray", "postgres"),
+            Some(String::from("one"))
+        );
+        assert_eq!(
+            find("db2.example.com", 5432, "moray", "reader"),
+            Some(String::from("two:colon"))
+        );
+        assert_eq!(
+            find("localhost", 15432, "test", "postgres"),
+            Some(String::from("three"))
+        );
+        assert_eq!(
+            find("db1.example.com", 5433, "moray", "postgres"),
+            Some(String::from("fallback"))
+        );
+        assert_eq!(find_password("a:1:b:c\n", "a", 1, "b", "c"), None);
+    }
+}
@@ -0,0 +1,281 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+// A shard's manta table can have an _id column, an _idx column or both.
+// Older shards only have _id (a 4 byte int in production), newer ones only
+// _idx, and shards that were migrated can have both with the same row
+// present in each.  Rather than trying to scan both and treating a failed
+// query as a missing column, each shard is probed up front for the columns
+// it has and the range of values in them, and the scan is planned from that.
+
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+use crate::error::SharkspotterError;
+use crate::source::IdRange;
+
+/// The id columns that a shard's manta table can have, in the order they are
+/// scanned.  A row is only emitted by the scan of the first column that
+/// covers it.
+pub const ID_COLUMNS: [&str; 2] = ["_id", "_idx"];
+
+/// Find which of the ID_COLUMNS exist, along with their types.
+pub const ID_COLUMNS_QUERY: &str = "SELECT column_name, data_type \
+     FROM information_schema.columns WHERE table_name = 'manta' AND \
+     column_name IN ('_id', '_idx');";
+
+/// Find the smallest and largest value of an id column.  Both are NULL if
+/// the table is empty.
+pub fn id_range_query(id_name: &str) -> String {
+    format!(
+        "SELECT MIN({})::bigint AS min, MAX({})::bigint AS max FROM manta;",
+        id_name, id_name
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdColumn {
+    pub name: String,
+
+    /// The postgres type of the column (e.g. "integer" or "bigint").
+    pub data_type: String,
+
+    /// The smallest and largest value in the column, or None if there are
+    /// no values.
+    pub range: Option<IdRange>,
+}
+
+/// The id columns that exist in a shard's manta table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShardProbe {
+    pub shard: u32,
+    pub id_columns: Vec<IdColumn>,
+}
+
+impl ShardProbe {
+    pub fn column(&self, id_name: &str) -> Option<&IdColumn> {
+        self.id_columns.iter().find(|c| c.name == id_name)
+    }
+
+    /// The first of the ID_COLUMNS that the table has.  Scans that walk the
+    /// table in a single pass are ordered by this one.
+    pub fn primary_column(&self) -> Option<&'static str> {
+        ID_COLUMNS
+            .iter()
+            .find(|id| self.column(id).is_some())
+            .copied()
+    }
+
+    /// The range of the given id column that a scan with this config covers,
+    /// or None if there is nothing in it to scan.
+    pub fn scan_range(&self, id_name: &str, conf: &Config) -> Option<IdRange> {
+        let range = self.column(id_name)?.range?;
+        let begin = range.begin.max(conf.begin);
+        let mut end = range.end;
+
+        // Only clamp the end if `-e` was given.
+        if conf.end > 0 && conf.end < end {
+            end = conf.end;
+        }
+
+        if begin > end {
+            return None;
+        }
+
+        Some(IdRange { begin, end })
+    }
+
+    /// The ranges covered by the scans of the columns before this one.  Rows
+    /// that fall in any of them have already been emitted.
+    pub fn covered_ranges(
+        &self,
+        id_name: &str,
+        conf: &Config,
+    ) -> Vec<(&'static str, IdRange)> {
+        ID_COLUMNS
+            .iter()
+            .take_while(|id| **id != id_name)
+            .filter_map(|id| Some((*id, self.scan_range(id, conf)?)))
+            .collect()
+    }
+
+    /// A one line description for the logs, e.g. "_id integer 1-1000, _idx
+    /// missing".
+    pub fn summary(&self) -> String {
+        let columns: Vec<String> = ID_COLUMNS
+            .iter()
+            .map(|id| match self.column(id) {
+                Some(IdColumn {
+                    data_type,
+                    range: Some(range),
+                    ..
+                }) => format!(
+                    "{} {} {}-{}",
+                    id, data_type, range.begin, range.end
+                ),
+                Some(IdColumn { data_type, .. }) => {
+                    format!("{} {} empty", id, data_type)
+                }
+                None => format!("{} missing", id),
+            })
+            .collect();
+
+        columns.join(", ")
+    }
+}
+
+/// Parse the value of a MIN() or MAX(), which may come back as either a
+/// number or a string.
+pub fn parse_id_value(val: &Value) -> Result<Option<u64>, String> {
+    match val {
+        Value::Null => Ok(None),
+        Value::Number(n) => n
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| format!("Could not convert {} to u64", n)),
+        Value::String(s) => s
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Could not parse '{}' as an id: {}", s, e)),
+        _ => Err(format!("Unexpected id value {:#?}", val)),
+    }
+}
+
+/// Turn the MIN() and MAX() of an id column into a range.
+pub fn id_range_from_values(
+    min: &Value,
+    max: &Value,
+) -> Result<Option<IdRange>, String> {
+    match (parse_id_value(min)?, parse_id_value(max)?) {
+        (Some(begin), Some(end)) => Ok(Some(IdRange { begin, end })),
+        _ => Ok(None),
+    }
+}
+
+/// A probe that is run once and shared by all of the sources for a shard,
+/// so that they agree on which rows each of them covers.
+#[derive(Clone, Default)]
+pub struct SharedProbe {
+    probe: Arc<Mutex<Option<ShardProbe>>>,
+}
+
+impl SharedProbe {
+    /// The probe, if one of the sources has already run it.
+    pub fn get(&self) -> Option<ShardProbe> {
+        self.probe.lock().expect("shard probe lock").clone()
+    }
+
+    pub fn get_or_probe<F>(
+        &self,
+        probe_fn: F,
+    ) -> Result<ShardProbe, SharkspotterError>
+    where
+        F: FnOnce() -> Result<ShardProbe, SharkspotterError>,
+    {
+        let mut probe = self.probe.lock().expect("shard probe lock");
+        if let Some(p) = probe.as_ref() {
+            return Ok(p.clone());
+        }
+
+        let p = probe_fn()?;
+        *probe = Some(p.clone());
+        Ok(p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn shard_probe_test() {
+        let probe = ShardProbe {
+            shard: 1,
+            id_columns: vec![
+                IdColumn {
+                    name: String::from("_id"),
+                    data_type: String::from("integer"),
+                    range: Some(IdRange {
+                        begin: 10,
+                        end: 100,
+                    }),
+                },
+                IdColumn {
+                    name: String::from("_idx"),
+                    data_type: String::from("bigint"),
+                    range: Some(IdRange {
+                        begin: 50,
+                        end: 500,
+                    }),
+                },
+            ],
+        };
+        let conf = Config::default();
+
+        assert_eq!(probe.summary(), "_id integer 10-100, _idx bigint 50-500");
+        assert_eq!(
+            probe.scan_range("_id", &conf),
+            Some(IdRange {
+                begin: 10,
+                end: 100
+            })
+        );
+        assert!(probe.covered_ranges("_id", &conf).is_empty());
+        assert_eq!(
+            probe.covered_ranges("_idx", &conf),
+            vec![(
+                "_id",
+                IdRange {
+                    begin: 10,
+                    end: 100
+                }
+            )]
+        );
+
+        let conf = Config {
+            begin: 200,
+            end: 300,
+            ..Default::default()
+        };
+        assert_eq!(probe.scan_range("_id", &conf), None);
+        assert_eq!(
+            probe.scan_range("_idx", &conf),
+            Some(IdRange {
+                begin: 200,
+                end: 300
+            })
+        );
+        assert!(probe.covered_ranges("_idx", &conf).is_empty());
+
+        let probe = ShardProbe {
+            shard: 2,
+            id_columns: vec![IdColumn {
+                name: String::from("_idx"),
+                data_type: String::from("bigint"),
+                range: None,
+            }],
+        };
+        assert_eq!(probe.summary(), "_id missing, _idx bigint empty");
+        assert_eq!(probe.primary_column(), Some("_idx"));
+        assert_eq!(probe.scan_range("_idx", &conf), None);
+
+        assert_eq!(
+            id_range_from_values(&json!("1"), &json!(20)).expect("range"),
+            Some(IdRange { begin: 1, end: 20 })
+        );
+        assert_eq!(
+            id_range_from_values(&Value::Null, &Value::Null).expect("range"),
+            None
+        );
+        assert!(id_range_from_values(&json!(false), &json!(1)).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+// Everything we know about an object that was found on one of the requested
+// sharks.  Alongside the manta object metadata itself (`manta_value`) a
+// record carries the moray columns that identify the row it came from, and a
+// typed view of the parts of the metadata that most callers need, so that
+// they don't have to parse the json again.  Moray columns that a source can't
+// provide (e.g. `_vnode` in a dump that doesn't include it) are left empty.
+
+use libmanta::moray::MantaObjectShark;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::moray_obj_column;
+
+/// The parts of a manta object's metadata that are the same in every
+/// version of the schema.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MantaObject {
+    pub owner: Option<String>,
+    pub object_id: Option<String>,
+    pub content_length: Option<u64>,
+    pub sharks: Vec<MantaObjectShark>,
+}
+
+impl MantaObject {
+    pub fn new(manta_value: &Value, sharks: Vec<MantaObjectShark>) -> Self {
+        let string = |name| {
+            manta_value
+                .get(name)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        };
+
+        MantaObject {
+            owner: string("owner"),
+            object_id: string("objectId"),
+            content_length: manta_value
+                .get("contentLength")
+                .and_then(Value::as_u64),
+            sharks,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchRecord {
+    /// The requested shark that the object is on.  This is the first of
+    /// `matching_sharks`.
+    pub shark: String,
+
+    /// The requested sharks that this record is for.  This is just `shark`,
+    /// unless `Config::per_object` is set, in which case there is only one
+    /// record for each object, listing every requested shark that it is on.
+    pub matching_sharks: Vec<String>,
+
+    pub shard: u32,
+    pub etag: String,
+
+    /// The id column that the row was found by, for sources that scan by
+    /// one (see `probe::ID_COLUMNS`).
+    pub id_column: Option<String>,
+
+    /// The moray `_id`, `_idx`, `_key`, `_mtime` and `_vnode` of the row.
+    pub id: Option<u64>,
+    pub idx: Option<u64>,
+    pub key: Option<String>,
+    pub mtime: Option<u64>,
+    pub vnode: Option<u64>,
+
+    pub object: MantaObject,
+
+    /// The whole of the manta object metadata.
+    pub manta_value: Value,
+}
+
+impl MatchRecord {
+    /// A record of a moray row whose object is on each of `matching_sharks`.
+    pub fn new(
+        moray_value: &Value,
+        manta_value: Value,
+        object: MantaObject,
+        etag: &str,
+        matching_sharks: Vec<String>,
+        shard: u32,
+        id_column: Option<&str>,
+    ) -> Self {
+        MatchRecord {
+            shark: matching_sharks.first().cloned().unwrap_or_default(),
+            matching_sharks,
+            shard,
+            etag: etag.to_string(),
+            id_column: id_column.map(str::to_string),
+            id: moray_obj_column(moray_value, "_id"),
+            idx: moray_obj_column(moray_value, "_idx"),
+            key: moray_value
+                .get("_key")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            mtime: moray_obj_column(moray_value, "_mtime"),
+            vnode: moray_obj_column(moray_value, "_vnode"),
+            object,
+            manta_value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn match_record_test() {
+        let manta_value = json!({
+            "owner": "2e2d6d1c-7d8d-11ea-a2a1-d3bc7ec2bb8e",
+            "objectId": "a",
+            "contentLength": 1024,
+            "type": "object",
+            "sharks": [{"datacenter": "dc0", "manta_storage_id": "1.stor"}],
+        });
+        let moray_value = json!({
+            "_id": 12,
+            "_idx": "34",
+            "_key": "/2e2d6d1c-7d8d-11ea-a2a1-d3bc7ec2bb8e/stor/a",
+            "_mtime": "1586900000000",
+            "_etag": "E",
+            "_value": manta_value.to_string(),
+        });
+        let sharks = vec![MantaObjectShark {
+            datacenter: String::from("dc0"),
+            manta_storage_id: String::from("1.stor"),
+        }];
+
+        let record = MatchRecord::new(
+            &moray_value,
+            manta_value.clone(),
+            MantaObject::new(&manta_value, sharks),
+            "E",
+            vec![String::from("1.stor")],
+            3,
+            Some("_idx"),
+        );
+        assert_eq!(record.shark, "1.stor");
+        assert_eq!(record.id, Some(12));
+        assert_eq!(record.idx, Some(34));
+        assert_eq!(record.mtime, Some(1_586_900_000_000));
+        assert_eq!(record.vnode, None);
+        assert_eq!(
+            record.key.as_ref().map(String::as_str),
+            Some("/2e2d6d1c-7d8d-11ea-a2a1-d3bc7ec2bb8e/stor/a")
+        );
+        assert_eq!(record.object.object_id.as_ref().unwrap(), "a");
+        assert_eq!(record.object.content_length, Some(1024));
+        assert_eq!(record.object.sharks.len(), 1);
+
+        // Records can be passed on as json without losing anything.
+        let json = serde_json::to_value(&record).expect("serialize");
+        assert_eq!(json["object"]["objectId"], "a");
+        assert_eq!(json["id_column"], "_idx");
+        let back: MatchRecord =
+            serde_json::from_value(json).expect("deserialize");
+        assert_eq!(back.idx, Some(34));
+        assert_eq!(back.manta_value, manta_value);
+    }
+}
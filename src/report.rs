@@ -0,0 +1,189 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::time::Duration;
+
+use crate::error::SharkspotterError;
+
+/// How the scan of a shard ended.  When a shard has more than one source
+/// (e.g. a moray scan of both _id and _idx) the worst of them is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShardStatus {
+    /// Every record was scanned.
+    Complete,
+
+    /// The scan was cancelled before it finished (see `cancel`).
+    Cancelled,
+
+    /// The receiving end of the channel was dropped before the scan
+    /// finished.
+    Stopped,
+
+    /// The scan ended early because of an error.
+    Failed,
+}
+
+#[derive(Debug)]
+pub struct ShardReport {
+    pub shard: u32,
+    pub status: ShardStatus,
+
+    /// Records read from the shard, including directories.
+    pub scanned: u64,
+
+    /// Objects passed to the caller.  An object that is on more than one of
+    /// the requested sharks is counted once for each shark, unless
+    /// `Config::per_object` is set.
+    pub matched: u64,
+
+    /// Records that had an error which the caller chose to skip.
+    pub skipped: u64,
+
+    /// Objects that are in more than one of the shard's id columns, and so
+    /// were only scanned once.  Only counted when `Config::report_overlap`
+    /// is set.
+    pub overlap: Option<u64>,
+
+    /// The errors that stopped the scan of this shard.
+    pub errors: Vec<SharkspotterError>,
+
+    /// Time spent scanning the shard, summed over its sources.
+    pub duration: Duration,
+}
+
+impl ShardReport {
+    pub fn new(shard: u32) -> Self {
+        ShardReport {
+            shard,
+            status: ShardStatus::Complete,
+            scanned: 0,
+            matched: 0,
+            skipped: 0,
+            overlap: None,
+            errors: vec![],
+            duration: Duration::from_secs(0),
+        }
+    }
+
+    fn merge(&mut self, other: ShardReport) {
+        self.status = self.status.max(other.status);
+        self.scanned += other.scanned;
+        self.matched += other.matched;
+        self.skipped += other.skipped;
+        self.overlap = match (self.overlap, other.overlap) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+        };
+        self.errors.extend(other.errors);
+        self.duration += other.duration;
+    }
+}
+
+/// The outcome of a single call to one of the `run` functions.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    /// One entry per shard, in shard order.
+    pub shards: Vec<ShardReport>,
+
+    /// Wall clock time of the whole run.
+    pub duration: Duration,
+}
+
+impl RunReport {
+    /// Add the report for a single source, merging it with any other
+    /// sources for the same shard.
+    pub fn add(&mut self, report: ShardReport) {
+        match self.shards.binary_search_by_key(&report.shard, |s| s.shard) {
+            Ok(i) => self.shards[i].merge(report),
+            Err(i) => self.shards.insert(i, report),
+        }
+    }
+
+    pub fn scanned(&self) -> u64 {
+        self.shards.iter().map(|s| s.scanned).sum()
+    }
+
+    pub fn matched(&self) -> u64 {
+        self.shards.iter().map(|s| s.matched).sum()
+    }
+
+    pub fn skipped(&self) -> u64 {
+        self.shards.iter().map(|s| s.skipped).sum()
+    }
+
+    /// True if the scan of any shard was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.shards
+            .iter()
+            .any(|s| s.status == ShardStatus::Cancelled)
+    }
+
+    /// True if every shard was scanned to the end.
+    pub fn is_complete(&self) -> bool {
+        self.shards
+            .iter()
+            .all(|s| s.status == ShardStatus::Complete)
+    }
+
+    /// For callers that only care whether the run failed.  Returns every
+    /// shard error as a single `SharkspotterError::Scan`.
+    pub fn into_result(self) -> Result<(), SharkspotterError> {
+        let errors: Vec<SharkspotterError> =
+            self.shards.into_iter().flat_map(|s| s.errors).collect();
+
+        if !errors.is_empty() {
+            return Err(SharkspotterError::Scan(errors));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_report_merge() {
+        let shard_report = |shard, status, scanned, matched| ShardReport {
+            status,
+            scanned,
+            matched,
+            duration: Duration::from_secs(1),
+            ..ShardReport::new(shard)
+        };
+
+        let mut report = RunReport::default();
+        report.add(shard_report(2, ShardStatus::Complete, 10, 1));
+        report.add(shard_report(1, ShardStatus::Complete, 5, 0));
+        report.add(shard_report(2, ShardStatus::Stopped, 3, 2));
+
+        let shards: Vec<u32> = report.shards.iter().map(|s| s.shard).collect();
+        assert_eq!(shards, vec![1, 2]);
+        assert_eq!(report.shards[1].status, ShardStatus::Stopped);
+        assert_eq!(report.shards[1].duration, Duration::from_secs(2));
+        assert_eq!(report.scanned(), 18);
+        assert_eq!(report.matched(), 3);
+        assert!(!report.is_complete());
+        assert!(report.into_result().is_ok());
+
+        let mut failed = shard_report(3, ShardStatus::Failed, 0, 0);
+        failed.errors.push(SharkspotterError::Query {
+            shard: 3,
+            cause: String::from("timeout"),
+        });
+        let mut report = RunReport::default();
+        report.add(failed);
+        match report.into_result() {
+            Err(SharkspotterError::Scan(errors)) => assert_eq!(errors.len(), 1),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,146 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use slog::{warn, Logger};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::error::SharkspotterError;
+
+/// How often, and how patiently, to retry an operation that failed with a
+/// transient error (see `SharkspotterError::is_transient()`).  The delay
+/// doubles after each attempt, up to `max_delay`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Backoff {
+    pub fn from_config(conf: &Config) -> Self {
+        Backoff {
+            max_retries: conf.max_retries,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(32),
+        }
+    }
+
+    /// The delay before the given retry, starting at 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry.saturating_sub(1)).unwrap_or(0);
+        match self.base_delay.checked_mul(factor) {
+            Some(d) if factor > 0 && d < self.max_delay => d,
+            _ => self.max_delay,
+        }
+    }
+
+    /// Run `op` until it succeeds, fails with an error that is not
+    /// transient, or runs out of retries.  `op` is given the number of the
+    /// attempt, starting at 0, so that it can e.g. try a different server
+    /// each time.
+    pub fn retry<T, F>(
+        &self,
+        log: &Logger,
+        mut op: F,
+    ) -> Result<T, SharkspotterError>
+    where
+        F: FnMut(u32) -> Result<T, SharkspotterError>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            match op(attempt) {
+                Err(e) if e.is_transient() && attempt < self.max_retries => {
+                    attempt += 1;
+                    let delay = self.delay(attempt);
+                    warn!(
+                        log,
+                        "retrying after error";
+                        "error" => e.to_string(),
+                        "retry" => attempt,
+                        "max_retries" => self.max_retries,
+                        "delay_ms" => delay.as_millis() as u64
+                    );
+                    thread::sleep(delay);
+                }
+                ret => return ret,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_test() {
+        let _guard = crate::util::init_global_logger(None);
+        let log = slog_scope::logger();
+
+        let backoff = Backoff {
+            max_retries: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+        };
+        let delays: Vec<u128> =
+            (1..=5).map(|r| backoff.delay(r).as_millis()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 4, 4]);
+        assert_eq!(backoff.delay(100), Duration::from_millis(4));
+
+        let transient = || SharkspotterError::Disconnected {
+            shard: 1,
+            cause: String::from("connection reset"),
+        };
+
+        // Succeeds on the last retry.
+        let mut attempts = vec![];
+        let ret = backoff.retry(&log, |attempt| {
+            attempts.push(attempt);
+            if attempt < 3 {
+                return Err(transient());
+            }
+            Ok(attempt)
+        });
+        assert_eq!(ret.expect("retry"), 3);
+        assert_eq!(attempts, vec![0, 1, 2, 3]);
+
+        // Runs out of retries.
+        let mut count = 0;
+        let ret: Result<(), _> = backoff.retry(&log, |_| {
+            count += 1;
+            Err(transient())
+        });
+        assert!(ret.is_err());
+        assert_eq!(count, 4);
+
+        // Other errors are not retried.
+        let mut count = 0;
+        let ret: Result<(), _> = backoff.retry(&log, |_| {
+            count += 1;
+            Err(SharkspotterError::ChannelClosed)
+        });
+        assert!(ret.is_err());
+        assert_eq!(count, 1);
+
+        let mut count = 0;
+        let ret: Result<(), _> = backoff.retry(&log, |_| {
+            count += 1;
+            Err(SharkspotterError::Query {
+                shard: 1,
+                cause: String::from("syntax error"),
+            })
+        });
+        assert!(ret.is_err());
+        assert_eq!(count, 1);
+    }
+}
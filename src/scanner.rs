@@ -0,0 +1,278 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+// A blocking iterator over the matches of a scan, for callers that want
+// neither a callback nor a channel of their own:
+//
+//     for msg in Scanner::new(&conf, log)?.iter() {
+//         let msg = msg?;
+//         ...
+//     }
+//
+// The shards are scanned by `max_threads` threads in the same way as
+// `run_multithreaded`, but they can only get a chunk's worth of matches ahead
+// of the caller before they wait for it to catch up.  Dropping the iterator
+// (e.g. by breaking out of the loop) stops the threads before their next
+// record, waits for them to finish and saves the checkpoint, if any.
+
+use slog::{debug, warn, Logger};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use threadpool::ThreadPool;
+
+use crate::checkpoint::Checkpoint;
+use crate::config::Config;
+use crate::error::SharkspotterError;
+use crate::scheduler::WorkQueue;
+use crate::source::ObjectSource;
+use crate::{MatchSender, SharkspotterMessage};
+
+type ScanItem = Result<SharkspotterMessage, SharkspotterError>;
+
+/// A scan that is ready to run.  Creating one checks the sharks and looks up
+/// the shards, but nothing is scanned until the iterator is used.
+pub struct Scanner {
+    conf: Config,
+    checkpoint: Checkpoint,
+    sources: Vec<Box<dyn ObjectSource>>,
+    log: Logger,
+}
+
+impl Scanner {
+    pub fn new(
+        config: &Config,
+        log: Logger,
+    ) -> Result<Self, SharkspotterError> {
+        let (conf, checkpoint) = crate::prepare_run(config, &log)?;
+        let sources = crate::sources_from_config(&conf, &checkpoint)?;
+
+        Ok(Scanner::from_sources(sources, &conf, checkpoint, log))
+    }
+
+    /// A scan of the given sources.  The shark names in the config are used
+    /// exactly as given.
+    pub fn from_sources(
+        sources: Vec<Box<dyn ObjectSource>>,
+        conf: &Config,
+        checkpoint: Checkpoint,
+        log: Logger,
+    ) -> Self {
+        Scanner {
+            conf: conf.clone(),
+            checkpoint,
+            sources,
+            log,
+        }
+    }
+
+    /// Start the scan.  Every match is returned in turn, along with any
+    /// record errors and errors that stopped the scan of a shard.
+    pub fn iter(self) -> ScanIter {
+        let (obj_tx, obj_rx) =
+            crossbeam_channel::bounded(self.conf.chunk_size.max(1) as usize);
+        let closed = Arc::new(AtomicBool::new(false));
+        let sender = IterSender {
+            tx: obj_tx,
+            closed: Arc::clone(&closed),
+        };
+        let max_threads = self.conf.max_threads.max(1);
+        let pool = ThreadPool::with_name("shard_scanner".into(), max_threads);
+        let queue = Arc::new(WorkQueue::new(self.sources));
+
+        for _ in 0..max_threads {
+            let th_queue = Arc::clone(&queue);
+            let th_sender = sender.clone();
+            let th_conf = self.conf.clone();
+            let th_log = self.log.clone();
+
+            pool.execute(move || {
+                crate::scan_work_units(
+                    &th_queue,
+                    &th_sender,
+                    &mut |report| {
+                        for e in report.errors {
+                            if th_sender.tx.send(Err(e)).is_err() {
+                                break;
+                            }
+                        }
+                    },
+                    &th_conf,
+                    &th_log,
+                );
+            });
+        }
+
+        ScanIter {
+            rx: Some(obj_rx),
+            closed,
+            pool,
+            checkpoint: self.checkpoint,
+            log: self.log,
+        }
+    }
+}
+
+impl IntoIterator for Scanner {
+    type Item = ScanItem;
+    type IntoIter = ScanIter;
+
+    fn into_iter(self) -> ScanIter {
+        self.iter()
+    }
+}
+
+/// Where the scanner threads send their matches, and how they find out that
+/// the iterator has been dropped.
+#[derive(Clone)]
+struct IterSender {
+    tx: crossbeam_channel::Sender<ScanItem>,
+    closed: Arc<AtomicBool>,
+}
+
+impl MatchSender for IterSender {
+    fn send_match(
+        &self,
+        msg: SharkspotterMessage,
+    ) -> Result<(), SharkspotterError> {
+        self.tx
+            .send(Ok(msg))
+            .map_err(|_| SharkspotterError::ChannelClosed)
+    }
+
+    fn send_record_error(
+        &self,
+        err: SharkspotterError,
+    ) -> Result<(), SharkspotterError> {
+        self.tx
+            .send(Err(err))
+            .map_err(|_| SharkspotterError::ChannelClosed)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+/// The matches of a running scan (see `Scanner`).
+pub struct ScanIter {
+    rx: Option<crossbeam_channel::Receiver<ScanItem>>,
+    closed: Arc<AtomicBool>,
+    pool: ThreadPool,
+    checkpoint: Checkpoint,
+    log: Logger,
+}
+
+impl ScanIter {
+    /// Wait for the scanner threads, and save the final checkpoint.  Only
+    /// the first call does anything.
+    fn finish(&mut self) -> Result<(), SharkspotterError> {
+        if self.rx.take().is_none() {
+            return Ok(());
+        }
+
+        self.pool.join();
+        self.checkpoint.flush()?;
+        Ok(())
+    }
+}
+
+impl Iterator for ScanIter {
+    type Item = ScanItem;
+
+    fn next(&mut self) -> Option<ScanItem> {
+        if let Ok(item) = self.rx.as_ref()?.recv() {
+            return Some(item);
+        }
+
+        // Every scanner thread has finished.
+        self.finish().err().map(Err)
+    }
+}
+
+impl Drop for ScanIter {
+    fn drop(&mut self) {
+        if self.rx.is_none() {
+            return;
+        }
+
+        debug!(self.log, "scan iterator dropped, stopping scanner threads");
+        self.closed.store(true, Ordering::SeqCst);
+
+        // Dropping the receiver unblocks any thread waiting to send.
+        drop(self.rx.take());
+        self.pool.join();
+
+        if let Err(e) = self.checkpoint.flush() {
+            warn!(self.log, "could not save checkpoint: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::tests::{counted_sources, sources};
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn scanner_test() {
+        let _guard = crate::util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let conf = Config {
+            sharks: vec![String::from("1.stor")],
+            chunk_size: 2,
+            max_threads: 2,
+            ..Default::default()
+        };
+        let checkpoint = || Checkpoint::from_config(&conf).expect("checkpoint");
+
+        let scanner = Scanner::from_sources(
+            sources(3, 10),
+            &conf,
+            checkpoint(),
+            log.clone(),
+        );
+        let mut found: Vec<String> = scanner
+            .iter()
+            .map(|msg| {
+                let msg = msg.expect("match");
+                crate::object_id_from_manta_obj(&msg.manta_value)
+                    .expect("object id")
+            })
+            .collect();
+        found.sort();
+        let mut expected: Vec<String> = (1..=3)
+            .flat_map(|shard| (0..10).map(move |i| format!("{}-{}", shard, i)))
+            .collect();
+        expected.sort();
+        assert_eq!(found, expected);
+
+        // Breaking out of the loop stops the scanner threads.  Dropping the
+        // iterator waits for them to exit, after which no source should have
+        // got anywhere near the end of its objects.
+        let handed_out = Arc::new(AtomicUsize::new(0));
+        let scanner = Scanner::from_sources(
+            counted_sources(3, 10_000, &handed_out),
+            &conf,
+            checkpoint(),
+            log,
+        );
+        let mut count = 0;
+        for msg in scanner {
+            msg.expect("match");
+            count += 1;
+            if count == 5 {
+                break;
+            }
+        }
+        assert_eq!(count, 5);
+        assert!(handed_out.load(Ordering::SeqCst) < 10_000);
+    }
+}
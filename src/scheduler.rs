@@ -0,0 +1,189 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+// The multithreaded runs don't hand each shard to its own thread.  Instead
+// every source starts out on a single shared queue, and a fixed number of
+// workers take turns pulling from it.  When a worker picks up a source it
+// splits off everything past the first `Config::work_unit_size` ids and puts
+// that back at the end of the queue before scanning the rest.  The shards
+// are worked through a unit at a time, and a worker that finishes early
+// takes over the remaining units of whichever shards are still going, so the
+// length of a run tracks the total number of rows rather than the size of
+// the largest shard.
+
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, PoisonError};
+
+struct QueueState<T> {
+    units: VecDeque<T>,
+
+    /// Units that have been handed out but not yet marked done.  A worker
+    /// can add more units until then.
+    active: usize,
+}
+
+/// A queue of work units shared by a set of workers.  Each worker calls
+/// `next` until it returns None.
+pub struct WorkQueue<T> {
+    state: Mutex<QueueState<T>>,
+    ready: Condvar,
+}
+
+impl<T> WorkQueue<T> {
+    pub fn new(units: Vec<T>) -> Self {
+        WorkQueue {
+            state: Mutex::new(QueueState {
+                units: units.into_iter().collect(),
+                active: 0,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Add a unit to the back of the queue.
+    pub fn push(&self, unit: T) {
+        let mut state = self.state.lock().expect("work queue lock");
+        state.units.push_back(unit);
+        self.ready.notify_one();
+    }
+
+    /// Take the next unit off the queue.  If the queue is empty but other
+    /// workers could still add to it, wait.  Returns None once all of the
+    /// work is done.
+    pub fn next(&self) -> Option<WorkUnit<'_, T>> {
+        let mut state = self.state.lock().expect("work queue lock");
+        loop {
+            if let Some(unit) = state.units.pop_front() {
+                state.active += 1;
+                return Some(WorkUnit { queue: self, unit });
+            }
+
+            if state.active == 0 {
+                return None;
+            }
+
+            state = self.ready.wait(state).expect("work queue lock");
+        }
+    }
+
+    // Mark a unit returned by `next` as finished.  This is called while
+    // unwinding if a worker panics, so a poisoned lock is not a reason to
+    // panic again.
+    fn done(&self) {
+        let mut state =
+            self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.active -= 1;
+
+        if state.active == 0 && state.units.is_empty() {
+            self.ready.notify_all();
+        }
+    }
+}
+
+/// A unit of work handed out by `WorkQueue::next`.  The unit is marked done
+/// when this is dropped, including when the worker panics, so that the other
+/// workers don't wait for it forever.
+pub struct WorkUnit<'a, T> {
+    queue: &'a WorkQueue<T>,
+    unit: T,
+}
+
+impl<'a, T> Deref for WorkUnit<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.unit
+    }
+}
+
+impl<'a, T> DerefMut for WorkUnit<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.unit
+    }
+}
+
+impl<'a, T> Drop for WorkUnit<'a, T> {
+    fn drop(&mut self) {
+        self.queue.done();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn work_queue_test() {
+        // Each unit is a number of ids, which the workers split in half
+        // until they are down to a single id.
+        let queue = Arc::new(WorkQueue::new(vec![64u64, 1, 7]));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || {
+                    let mut scanned = 0;
+                    while let Some(unit) = queue.next() {
+                        let mut ids = *unit;
+                        if ids > 1 {
+                            queue.push(ids / 2);
+                            ids -= ids / 2;
+                        }
+                        if ids == 1 {
+                            scanned += 1;
+                        } else {
+                            queue.push(ids);
+                        }
+                    }
+                    scanned
+                })
+            })
+            .collect();
+
+        let scanned: u64 =
+            workers.into_iter().map(|w| w.join().expect("join")).sum();
+        assert_eq!(scanned, 72);
+        assert!(queue.next().is_none());
+    }
+
+    #[test]
+    fn work_queue_panic_test() {
+        // A worker that panics part way through a unit must not leave the
+        // others waiting for it to finish.
+        let queue = Arc::new(WorkQueue::new(vec![0u64, 1, 2, 3, 4, 5]));
+        let scanned = Arc::new(AtomicU64::new(0));
+        let workers: Vec<_> = (0..3)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let scanned = Arc::clone(&scanned);
+                thread::spawn(move || {
+                    while let Some(unit) = queue.next() {
+                        if *unit == 2 {
+                            panic!("unit {} failed", *unit);
+                        }
+                        scanned.fetch_add(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+
+        let failed = workers
+            .into_iter()
+            .map(|w| w.join())
+            .filter(Result::is_err)
+            .count();
+        assert_eq!(failed, 1);
+        assert_eq!(scanned.load(Ordering::SeqCst), 5);
+        assert!(queue.next().is_none());
+    }
+}
@@ -0,0 +1,177 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::error::SharkspotterError;
+use serde_json::Value;
+use slog::Logger;
+
+/// An inclusive range of moray ids (_id or _idx).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdRange {
+    pub begin: u64,
+    pub end: u64,
+}
+
+impl IdRange {
+    /// The first id of each of `parts` equally sized pieces of the range.  If
+    /// the range has fewer ids than there are parts, the last pieces start
+    /// after the end of the range and are empty.
+    pub fn split_points(&self, parts: u32) -> Vec<u64> {
+        let parts = u64::from(parts.max(1));
+        let len = self.end - self.begin + 1;
+        let size = (len + parts - 1) / parts;
+
+        (0..parts).map(|i| self.begin + i * size).collect()
+    }
+
+    /// Split the range after the first multiple of `max_ids` in it, into a
+    /// unit of work and the rest of the range.  Units end on a multiple of
+    /// `max_ids` so that a resumed run splits a column in the same places.
+    /// Returns None if the range doesn't reach past that point.
+    pub fn split_unit(&self, max_ids: u64) -> Option<(IdRange, IdRange)> {
+        let split_at = (self.begin / max_ids + 1) * max_ids;
+        if split_at > self.end {
+            return None;
+        }
+
+        Some((
+            IdRange {
+                begin: self.begin,
+                end: split_at - 1,
+            },
+            IdRange {
+                begin: split_at,
+                end: self.end,
+            },
+        ))
+    }
+}
+
+/// A record handed over by `ObjectSource::scan_records`.
+pub enum SourceRecord<'a> {
+    /// A moray object, as passed to the `scan` handler.
+    Moray(&'a Value),
+
+    /// A moray object whose "_value" the source has already parsed, so that
+    /// it doesn't have to be parsed again.  `moray_value` has every other
+    /// field of the moray object, and an error parsing the manta object
+    /// metadata is a record error.
+    Parsed {
+        moray_value: Value,
+        manta_value: Result<Value, SharkspotterError>,
+    },
+
+    /// A record that the source has found cannot be on any of the requested
+    /// sharks without fully parsing it.  It is only counted.
+    Skipped,
+}
+
+/// A source of moray `manta` bucket entries for a single shard.
+///
+/// Each record passed to the scan handler is a moray object in the form that
+/// the moray `sql` endpoint returns it (see the sample entry at the top of
+/// lib.rs).  At a minimum it must have the manta object metadata as a json
+/// string under "_value" and the etag under "_etag".  Sharkspotter takes care
+/// of parsing the metadata, filtering on the requested sharks and handing the
+/// matches to the caller, so a source only needs to produce records.
+///
+/// See `run_sources()` and `run_sources_multithreaded()`.
+pub trait ObjectSource: Send {
+    /// The shard that the records belong to.
+    fn shard(&self) -> u32;
+
+    /// A short description of the source for use in logs and errors.
+    fn name(&self) -> String;
+
+    /// The range of ids that will be scanned, if the source knows it.
+    fn id_range(
+        &mut self,
+        _log: &Logger,
+    ) -> Result<Option<IdRange>, SharkspotterError> {
+        Ok(None)
+    }
+
+    /// The id column that the source scans by, if it scans by one.
+    fn id_column(&self) -> Option<String> {
+        None
+    }
+
+    /// The number of records this source skipped because another source for
+    /// the same shard already covers them.  Only counted when
+    /// `Config::report_overlap` is set.
+    fn overlap(&self) -> Option<u64> {
+        None
+    }
+
+    /// Split off everything past the first `max_ids` ids that are left to
+    /// scan as a new source for the same shard, and narrow this source down
+    /// to the ids before that.  Returns None if the source is already small
+    /// enough or can not be split.  See `scheduler`.
+    fn split_off(
+        &mut self,
+        _log: &Logger,
+        _max_ids: u64,
+    ) -> Result<Option<Box<dyn ObjectSource>>, SharkspotterError> {
+        Ok(None)
+    }
+
+    /// Call the handler for every record in the source.  If the handler
+    /// returns an error the scan stops and that error is returned.
+    fn scan(
+        &mut self,
+        log: &Logger,
+        handler: &mut dyn FnMut(&Value) -> Result<(), SharkspotterError>,
+    ) -> Result<(), SharkspotterError>;
+
+    /// Same as `scan`, but a source that can check its records more cheaply
+    /// than by handing over each one as a moray object may do so (see
+    /// `SourceRecord`).  This is what sharkspotter itself scans sources with.
+    fn scan_records(
+        &mut self,
+        log: &Logger,
+        handler: &mut dyn FnMut(SourceRecord) -> Result<(), SharkspotterError>,
+    ) -> Result<(), SharkspotterError> {
+        self.scan(log, &mut |moray_value| {
+            handler(SourceRecord::Moray(moray_value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_points_test() {
+        let range = IdRange { begin: 0, end: 99 };
+        assert_eq!(range.split_points(1), vec![0]);
+        assert_eq!(range.split_points(4), vec![0, 25, 50, 75]);
+        assert_eq!(range.split_points(3), vec![0, 34, 68]);
+
+        let range = IdRange { begin: 10, end: 11 };
+        assert_eq!(range.split_points(4), vec![10, 11, 12, 13]);
+    }
+
+    #[test]
+    fn split_unit_test() {
+        let range = IdRange { begin: 5, end: 25 };
+        assert_eq!(
+            range.split_unit(10),
+            Some((
+                IdRange { begin: 5, end: 9 },
+                IdRange { begin: 10, end: 25 }
+            ))
+        );
+        assert_eq!(range.split_unit(30), None);
+
+        let range = IdRange { begin: 20, end: 29 };
+        assert_eq!(range.split_unit(10), None);
+    }
+}
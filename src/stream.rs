@@ -0,0 +1,439 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+// An async interface to a scan for callers that already run on tokio.  The
+// scan runs on the caller's runtime: direct-DB shards are read as tasks of
+// their own, while moray and dump file sources, whose clients block, are
+// scanned on tokio's blocking thread pool, sharing out units of work the
+// same way as `run_multithreaded` does.
+//
+// Matches (and errors) are passed back over a bounded channel, so a scan
+// only ever gets a chunk's worth of matches ahead of the caller.  Dropping
+// the stream stops the scan: each scanner stops before its next record, and
+// no more units of work are started.
+
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use slog::{debug, warn, Logger};
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+use crate::checkpoint::Checkpoint;
+use crate::config::Config;
+use crate::directdb;
+use crate::error::SharkspotterError;
+use crate::report::ShardReport;
+use crate::scheduler::WorkQueue;
+use crate::source::ObjectSource;
+use crate::throttle::{ShardThrottle, Throttle};
+use crate::{MatchSender, SharkspotterMessage};
+
+type StreamItem = Result<SharkspotterMessage, SharkspotterError>;
+
+/// The matches of a scan started by `scan_stream`.  Record errors, and
+/// errors that stopped the scan of a shard, are passed along with the
+/// matches, and the stream ends once every shard has been scanned.
+pub struct ScanStream {
+    rx: mpsc::Receiver<StreamItem>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Stream for ScanStream {
+    type Item = StreamItem;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.get_mut().rx.poll_recv(cx)
+    }
+}
+
+impl Drop for ScanStream {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The sending half of a `ScanStream`, for both the async direct-DB
+/// scanners and the blocking scanner threads.
+#[derive(Clone)]
+struct StreamSender {
+    tx: mpsc::Sender<StreamItem>,
+    closed: Arc<AtomicBool>,
+}
+
+impl StreamSender {
+    async fn send(&self, item: StreamItem) -> Result<(), SharkspotterError> {
+        self.tx
+            .clone()
+            .send(item)
+            .await
+            .map_err(|_| SharkspotterError::ChannelClosed)
+    }
+
+    fn send_blocking(&self, item: StreamItem) -> Result<(), SharkspotterError> {
+        futures::executor::block_on(self.send(item))
+    }
+
+    /// Pass along the errors that stopped the scan of a source.  The record
+    /// errors have already been sent.
+    fn send_report(&self, report: ShardReport) {
+        for e in report.errors {
+            if self.send_blocking(Err(e)).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl MatchSender for StreamSender {
+    fn send_match(
+        &self,
+        msg: SharkspotterMessage,
+    ) -> Result<(), SharkspotterError> {
+        self.send_blocking(Ok(msg))
+    }
+
+    fn send_record_error(
+        &self,
+        err: SharkspotterError,
+    ) -> Result<(), SharkspotterError> {
+        self.send_blocking(Err(err))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+fn new_stream(conf: &Config) -> (StreamSender, ScanStream) {
+    let (tx, rx) = mpsc::channel(conf.chunk_size.max(1) as usize);
+    let closed = Arc::new(AtomicBool::new(false));
+    let sender = StreamSender {
+        tx,
+        closed: Arc::clone(&closed),
+    };
+
+    (sender, ScanStream { rx, closed })
+}
+
+fn join_error(e: tokio::task::JoinError) -> SharkspotterError {
+    SharkspotterError::Io(Error::new(ErrorKind::Other, e.to_string()))
+}
+
+/// Scan the shards in the config, and stream back every object that
+/// resides on one of `config.sharks`.  This must be called from within a
+/// tokio runtime, which the scan is run on.
+///
+/// An error setting up the scan (e.g. a shark that does not exist) is the
+/// only item in the stream.
+pub fn scan_stream(
+    config: &Config,
+    log: Logger,
+) -> impl Stream<Item = StreamItem> {
+    let (sender, scan) = new_stream(config);
+    let config = config.clone();
+
+    tokio::spawn(async move {
+        if let Err(e) = run_stream(config, log, &sender).await {
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+
+    scan
+}
+
+/// Same as `scan_stream`, but for the given sources, which are scanned on
+/// the blocking thread pool.  The shark names in the config are used
+/// exactly as given.
+pub fn scan_sources_stream(
+    sources: Vec<Box<dyn ObjectSource>>,
+    conf: &Config,
+    log: Logger,
+) -> impl Stream<Item = StreamItem> {
+    let (sender, scan) = new_stream(conf);
+    let conf = conf.clone();
+
+    tokio::spawn(async move {
+        scan_blocking_sources(sources, conf, log, sender).await;
+    });
+
+    scan
+}
+
+async fn run_stream(
+    config: Config,
+    log: Logger,
+    sender: &StreamSender,
+) -> Result<(), SharkspotterError> {
+    // Checking the sharks and looking up the morays both block.
+    let th_log = log.clone();
+    let (conf, checkpoint) = tokio::task::spawn_blocking(move || {
+        crate::prepare_run(&config, &th_log)
+    })
+    .await
+    .map_err(join_error)??;
+
+    if conf.direct_db && conf.dump_files.is_empty() {
+        scan_direct_db(&conf, &log, &checkpoint, sender).await;
+    } else {
+        let th_conf = conf.clone();
+        let th_checkpoint = checkpoint.clone();
+        let sources = tokio::task::spawn_blocking(move || {
+            crate::sources_from_config(&th_conf, &th_checkpoint)
+        })
+        .await
+        .map_err(join_error)??;
+
+        scan_blocking_sources(sources, conf, log, sender.clone()).await;
+    }
+
+    checkpoint.flush()?;
+    Ok(())
+}
+
+/// Scan the sources on up to `conf.max_threads` blocking threads, sharing out
+/// the units of work between them.
+async fn scan_blocking_sources(
+    sources: Vec<Box<dyn ObjectSource>>,
+    conf: Config,
+    log: Logger,
+    sender: StreamSender,
+) {
+    let queue = Arc::new(WorkQueue::new(sources));
+
+    let workers = (0..conf.max_threads.max(1)).map(|_| {
+        let th_queue = Arc::clone(&queue);
+        let th_sender = sender.clone();
+        let th_conf = conf.clone();
+        let th_log = log.clone();
+
+        tokio::task::spawn_blocking(move || {
+            crate::scan_work_units(
+                &th_queue,
+                &th_sender,
+                &mut |report| th_sender.send_report(report),
+                &th_conf,
+                &th_log,
+            );
+        })
+    });
+
+    for ret in future::join_all(workers).await {
+        if let Err(e) = ret {
+            warn!(log, "scanner thread failed: {}", e);
+            let _ = sender.send(Err(join_error(e))).await;
+        }
+    }
+}
+
+/// Scan each shard's database as a task of its own, up to
+/// `conf.max_threads` of them at a time.
+async fn scan_direct_db(
+    conf: &Config,
+    log: &Logger,
+    checkpoint: &Checkpoint,
+    sender: &StreamSender,
+) {
+    let throttle = Throttle::new(conf);
+
+    stream::iter(conf.min_shard..=conf.max_shard)
+        .for_each_concurrent(conf.max_threads.max(1), |shard| {
+            let task = tokio::spawn(scan_direct_db_shard(
+                shard,
+                conf.clone(),
+                log.clone(),
+                checkpoint.clone(),
+                throttle.shard(shard),
+                sender.clone(),
+            ));
+
+            async move {
+                match task.await.map_err(join_error).and_then(|ret| ret) {
+                    Ok(())
+                    | Err(SharkspotterError::ChannelClosed)
+                    | Err(SharkspotterError::Cancelled) => (),
+                    Err(e) => {
+                        warn!(log, "error scanning shard {}: {}", shard, e);
+                        let _ = sender.send(Err(e)).await;
+                    }
+                }
+            }
+        })
+        .await;
+}
+
+async fn scan_direct_db_shard(
+    shard: u32,
+    conf: Config,
+    log: Logger,
+    checkpoint: Checkpoint,
+    throttle: ShardThrottle,
+    sender: StreamSender,
+) -> Result<(), SharkspotterError> {
+    // The record handler, and the futures it returns, borrow these.
+    let (conf, log, sender) = (&conf, &log, &sender);
+
+    if sender.is_closed() {
+        debug!(log, "receiver closed, not scanning shard {}", shard);
+        return Err(SharkspotterError::ChannelClosed);
+    }
+    conf.cancel.check()?;
+
+    directdb::visit_objects_in_shard(
+        shard,
+        conf.clone(),
+        log.clone(),
+        checkpoint,
+        throttle,
+        |record| {
+            // Sort out which requested sharks the object is on first,
+            // and then wait for room in the channel to send them.
+            let mut items = vec![];
+            let mut errors = vec![];
+            let ret = crate::handle_record(
+                log,
+                record,
+                shard,
+                None,
+                conf,
+                &mut |record| {
+                    items.push(Ok(record));
+                    Ok(())
+                },
+                &mut |e| {
+                    errors.push(Err(e));
+                    Ok(())
+                },
+            );
+
+            let closed = sender.is_closed();
+
+            async move {
+                if closed {
+                    return Err(SharkspotterError::ChannelClosed);
+                }
+
+                ret?;
+                for item in items.into_iter().chain(errors) {
+                    sender.send(item).await?;
+                }
+                Ok(())
+            }
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::tests::FixtureSource;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Sources for the given number of shards, each with `count` objects
+    /// that are all on "1.stor".
+    pub(crate) fn sources(
+        shards: u32,
+        count: u32,
+    ) -> Vec<Box<dyn ObjectSource>> {
+        counted_sources(shards, count, &Arc::default())
+    }
+
+    /// Same as `sources`, with the number of objects that the sources have
+    /// handed out kept in `handed_out`.
+    pub(crate) fn counted_sources(
+        shards: u32,
+        count: u32,
+        handed_out: &Arc<AtomicUsize>,
+    ) -> Vec<Box<dyn ObjectSource>> {
+        (1..=shards)
+            .map(|shard| {
+                let records = (0..count)
+                    .map(|i| {
+                        let manta_value = json!({
+                            "objectId": format!("{}-{}", shard, i),
+                            "type": "object",
+                            "sharks": [{
+                                "datacenter": "dc0",
+                                "manta_storage_id": "1.stor",
+                            }],
+                        });
+                        json!({
+                            "_etag": "E",
+                            "_value": manta_value.to_string(),
+                        })
+                    })
+                    .collect();
+
+                Box::new(FixtureSource::counted(shard, records, handed_out))
+                    as Box<dyn ObjectSource>
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scan_sources_stream_test() {
+        let _guard = crate::util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let conf = Config {
+            sharks: vec![String::from("1.stor")],
+            chunk_size: 2,
+            max_threads: 2,
+            ..Default::default()
+        };
+        let mut rt = tokio::runtime::Builder::new()
+            .enable_all()
+            .basic_scheduler()
+            .build()
+            .expect("runtime");
+
+        let mut found: Vec<String> = rt.block_on(async {
+            scan_sources_stream(sources(3, 10), &conf, log.clone())
+                .map(|msg| {
+                    let msg = msg.expect("match");
+                    crate::object_id_from_manta_obj(&msg.manta_value)
+                        .expect("object id")
+                })
+                .collect()
+                .await
+        });
+        found.sort();
+        let mut expected: Vec<String> = (1..=3)
+            .flat_map(|shard| (0..10).map(move |i| format!("{}-{}", shard, i)))
+            .collect();
+        expected.sort();
+        assert_eq!(found, expected);
+
+        // Dropping the stream part way through stops the scan.  Dropping the
+        // runtime waits for the scanning threads to exit, after which no
+        // source should have got anywhere near the end of its objects.
+        let handed_out = Arc::new(AtomicUsize::new(0));
+        let found = rt.block_on(async {
+            scan_sources_stream(
+                counted_sources(3, 1000, &handed_out),
+                &conf,
+                log.clone(),
+            )
+            .take(5)
+            .collect::<Vec<_>>()
+            .await
+        });
+        drop(rt);
+        assert_eq!(found.len(), 5);
+        assert!(handed_out.load(Ordering::SeqCst) < 1000);
+    }
+}
@@ -0,0 +1,236 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+// Scanning a live shard as fast as possible competes with customer requests
+// for the same database.  A scan can be slowed down with any combination of:
+//
+//  - limits on the rows and chunks read per second, both across the whole run
+//    and for each shard,
+//  - a fixed delay after every chunk, and
+//  - an extra delay that grows while moray is slow to answer a chunk query
+//    and shrinks again once it recovers.
+//
+// Moray scans are throttled after each chunk.  Direct-DB scans read a single
+// stream of rows, so every `chunk_size` rows are treated as a chunk, and
+// since there is no per-chunk query to time there the slow query backoff
+// does not apply.
+
+use slog::{info, Logger};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ThrottleConfig};
+
+// The most the slow query backoff will wait after a single chunk.
+const MAX_SLOW_DELAY: Duration = Duration::from_secs(30);
+
+// Once the slow query backoff shrinks below this it is dropped altogether.
+const MIN_SLOW_DELAY: Duration = Duration::from_millis(10);
+
+/// Spaces out units (rows or chunks) so that no more than `rate` of them are
+/// let through per second.  Shared by every scanner that the limit applies
+/// to.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Account for `units` more units, and return how long the caller
+    /// should wait before carrying on.
+    pub fn acquire(&self, units: u64) -> Duration {
+        let mut next = self.next.lock().expect("rate limiter lock");
+        let now = Instant::now();
+        let start = if *next > now { *next } else { now };
+
+        *next = start + Duration::from_secs_f64(units as f64 / self.rate);
+        start - now
+    }
+}
+
+fn limiter(rate: u64) -> Option<Arc<RateLimiter>> {
+    if rate == 0 {
+        return None;
+    }
+
+    Some(Arc::new(RateLimiter::new(rate)))
+}
+
+/// The throttle for a whole run.  Hand each shard its own `ShardThrottle`
+/// with `shard()`.
+#[derive(Clone)]
+pub struct Throttle {
+    conf: ThrottleConfig,
+    chunk_size: u64,
+    rows: Option<Arc<RateLimiter>>,
+    chunks: Option<Arc<RateLimiter>>,
+}
+
+impl Throttle {
+    pub fn new(conf: &Config) -> Self {
+        let throttle = &conf.throttle;
+
+        Throttle {
+            conf: throttle.clone(),
+            chunk_size: conf.chunk_size,
+            rows: limiter(throttle.rows_per_sec),
+            chunks: limiter(throttle.chunks_per_sec),
+        }
+    }
+
+    /// The throttle for a single shard.  Every source for the shard should
+    /// use a clone of the same one so that they share its limits.
+    pub fn shard(&self, shard: u32) -> ShardThrottle {
+        let mut rows: Vec<Arc<RateLimiter>> = vec![];
+        let mut chunks: Vec<Arc<RateLimiter>> = vec![];
+
+        rows.extend(self.rows.clone());
+        rows.extend(limiter(self.conf.shard_rows_per_sec));
+        chunks.extend(self.chunks.clone());
+        chunks.extend(limiter(self.conf.shard_chunks_per_sec));
+
+        ShardThrottle {
+            shard,
+            chunk_size: self.chunk_size,
+            chunk_delay: Duration::from_millis(self.conf.chunk_delay_ms),
+            slow_query: match self.conf.slow_query_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            rows,
+            chunks,
+            slow_delay: Duration::from_secs(0),
+            pending_rows: 0,
+        }
+    }
+}
+
+/// Works out how long a single scanner should pause between chunks.
+#[derive(Clone)]
+pub struct ShardThrottle {
+    shard: u32,
+    chunk_size: u64,
+    chunk_delay: Duration,
+    slow_query: Option<Duration>,
+    rows: Vec<Arc<RateLimiter>>,
+    chunks: Vec<Arc<RateLimiter>>,
+    slow_delay: Duration,
+    pending_rows: u64,
+}
+
+impl ShardThrottle {
+    /// How long to wait after a chunk of `rows` rows that took `latency` to
+    /// read, if known.
+    pub fn after_chunk(
+        &mut self,
+        rows: u64,
+        latency: Option<Duration>,
+        log: &Logger,
+    ) -> Duration {
+        let mut wait = self.chunk_delay;
+
+        for limiter in self.rows.iter() {
+            wait = wait.max(limiter.acquire(rows));
+        }
+
+        for limiter in self.chunks.iter() {
+            wait = wait.max(limiter.acquire(1));
+        }
+
+        if let (Some(threshold), Some(latency)) = (self.slow_query, latency) {
+            if latency > threshold {
+                self.slow_delay =
+                    (self.slow_delay * 2).max(latency).min(MAX_SLOW_DELAY);
+                info!(
+                    log,
+                    "slow chunk query, backing off";
+                    "shard" => self.shard,
+                    "latency_ms" => latency.as_millis() as u64,
+                    "delay_ms" => self.slow_delay.as_millis() as u64
+                );
+            } else if self.slow_delay > Duration::from_secs(0) {
+                self.slow_delay /= 2;
+                if self.slow_delay < MIN_SLOW_DELAY {
+                    self.slow_delay = Duration::from_secs(0);
+                }
+            }
+        }
+
+        wait + self.slow_delay
+    }
+
+    /// How long to wait after a single row of a scan that is not read in
+    /// chunks.  Every `chunk_size` rows are throttled as a chunk.
+    pub fn after_row(&mut self, log: &Logger) -> Duration {
+        self.pending_rows += 1;
+        if self.pending_rows < self.chunk_size {
+            return Duration::from_secs(0);
+        }
+
+        self.pending_rows = 0;
+        self.after_chunk(self.chunk_size, None, log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_test() {
+        let _guard = crate::util::init_global_logger(None);
+        let log = slog_scope::logger();
+        let ms = Duration::from_millis;
+
+        // 10 rows per second lets the first 5 rows through straight away and
+        // then makes the next caller wait half a second.
+        let limiter = RateLimiter::new(10);
+        assert!(limiter.acquire(5) < ms(5));
+        let wait = limiter.acquire(1);
+        assert!(wait > ms(450) && wait <= ms(500), "{:?}", wait);
+
+        let conf = Config {
+            chunk_size: 2,
+            throttle: ThrottleConfig {
+                chunk_delay_ms: 5,
+                slow_query_ms: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut throttle = Throttle::new(&conf).shard(1);
+
+        // The slow query backoff doubles while queries are slow, and halves
+        // once they are fast again.
+        assert_eq!(throttle.after_chunk(10, Some(ms(20)), &log), ms(5));
+        assert_eq!(throttle.after_chunk(10, Some(ms(200)), &log), ms(205));
+        assert_eq!(throttle.after_chunk(10, Some(ms(150)), &log), ms(405));
+        assert_eq!(throttle.after_chunk(10, Some(ms(20)), &log), ms(205));
+        assert_eq!(throttle.after_chunk(10, None, &log), ms(205));
+
+        // Rows are only throttled once per chunk.
+        let mut throttle = Throttle::new(&conf).shard(2);
+        assert_eq!(throttle.after_row(&log), ms(0));
+        assert_eq!(throttle.after_row(&log), ms(5));
+        assert_eq!(throttle.after_row(&log), ms(0));
+
+        // With the defaults nothing is throttled.
+        let mut throttle = Throttle::new(&Config::default()).shard(3);
+        assert_eq!(throttle.after_chunk(1000, Some(ms(500)), &log), ms(0));
+    }
+}
@@ -23,24 +23,96 @@ USAGE:
     sharkspotter [FLAGS] [OPTIONS] --domain <MORAY_DOMAIN> --shark <STORAGE_ID>...
 
 FLAGS:
-    -D, --direct_db         use direct DB access instead of moray
-    -h, --help              Prints help information
-    -T, --multithreaded     Run with multiple threads, one per shard
-    -O, --object_id_only    Output only the object ID
-    -x                      Skip shark validation. Useful if shark is in readonly mode.
-    -V, --version           Prints version information
+        --db_shark_filter    Have the database skip the rows that are not on the sharks (--direct_db only)
+    -D, --direct_db          use direct DB access instead of moray
+    -h, --help               Prints help information
+    -T, --multithreaded      Run with multiple threads that share out the shards
+    -O, --object_id_only     Output only the object ID
+        --per_object         Write each object to the output file once, even if it is on more than one of the sharks
+        --report_overlap     Count the objects that are in both the _id and _idx columns of a shard (slow)
+    -x                       Skip shark validation. Useful if shark is in readonly mode.
+    -V, --version            Prints version information
 
 OPTIONS:
-    -b, --begin <INDEX>                index to being scanning at (default: 0)
-    -c, --chunk-size <NUM_RECORDS>     number of records to scan per call to moray (default: 100)
-    -d, --domain <MORAY_DOMAIN>        Domain that the moray zones are in
-    -e, --end <INDEX>                  index to stop scanning at (default: 0)
-    -l, --log_level <log_level>        Set log level
-    -M, --max_shard <MAX_SHARD>        Ending shard number (default: 1)
-    -t, --max_threads <max_threads>    maximum number of threads to run with
-    -m, --min_shard <MIN_SHARD>        Beginning shard number (default: 1)
-    -f, --file <FILE_NAME>             output filename (default <shark>/shard_<shard_num>.objs
-    -s, --shark <STORAGE_ID>...        Find objects that belong to this shark
+    -b, --begin <INDEX>                                index to being scanning at (default: 0)
+        --checkpoint <STATE_FILE>                      Periodically save scan progress to this file
+    -c, --chunk-size <NUM_RECORDS>                     number of records to scan per call to moray (default: 100)
+        --chunk_delay <MILLISECONDS>                   Time to wait after reading each chunk
+        --config <CONFIG_FILE>
+            Read the moray, postgres, dns and throttle settings from this json file
+
+    -d, --domain <MORAY_DOMAIN>                        Domain that the moray zones are in
+        --dump <DUMP_FILE>...
+            Scan a local manatee backup of the manta table instead of moray.  The shard is taken from the
+            '<shard>.moray.<domain>' component of the path, and the shard range defaults to the shards of the dump
+            files.
+    -e, --end <INDEX>                                  index to stop scanning at (default: 0)
+        --host_map <HOSTS_FILE>
+            Use the addresses in this file, which is in the same format as /etc/hosts, instead of looking the hosts up
+
+    -l, --log_level <log_level>                        Set log level
+        --max_chunk_size <NUM_IDS>
+            Let the number of ids scanned per call to moray grow to this if the ids are sparse
+
+        --max_chunks_per_sec <NUM_CHUNKS>              Most chunks to read per second across all shards
+        --max_retries <NUM_RETRIES>
+            Number of times to retry a failed moray request, waiting twice as long after each one (default: 5)
+
+        --max_rows_per_sec <NUM_ROWS>                  Most rows to read per second across all shards
+    -M, --max_shard <MAX_SHARD>                        Ending shard number (default: 1)
+    -t, --max_threads <max_threads>                    maximum number of threads to run with
+        --min_chunk_size <NUM_IDS>
+            Let the number of ids scanned per call to moray shrink to this if the ids are densely packed or moray is
+            slow
+    -m, --min_shard <MIN_SHARD>                        Beginning shard number (default: 1)
+        --moray_host <HOST_TEMPLATE>
+            Host name of each shard's moray, where {{shard}} and {{domain}} are filled in (default: {{shard}}.moray.{{domain}})
+
+        --moray_port <PORT>                            Port that moray listens on (default: 2020)
+        --nameserver <IP_ADDR[:PORT]>...
+            Look up hosts using this nameserver instead of the ones in resolv.conf (default port: 53)
+
+    -f, --file <FILE_NAME>                             output filename (default <shark>/shard_<shard_num>.objs
+        --postgres_application_name <NAME>
+            Name the --direct_db connections show up as in pg_stat_activity (default: sharkspotter)
+
+        --postgres_db <DB_NAME>                        Database to connect to for --direct_db (default: moray)
+        --postgres_host <HOST_TEMPLATE>
+            Host name of each shard's database for --direct_db, where {{shard}} and {{domain}} are filled in, or the
+            directory of its Unix socket (default: {{shard}}.rebalancer-postgres.{{domain}})
+        --postgres_lock_timeout <MILLISECONDS>
+            Give up on the --direct_db scan of a shard if it has to wait this long for a lock (default: 0, no limit)
+
+        --postgres_passfile <PASSFILE>
+            Look up the --direct_db password in this file instead of $PGPASSFILE or ~/.pgpass
+
+        --postgres_port <PORT>                         Port that postgres listens on (default: 5432)
+        --postgres_sslmode <SSL_MODE>
+            Whether to use TLS for --direct_db, and how to check the server's certificate (default: disable) [possible
+            values: disable, prefer, require, verify-ca, verify-full]
+        --postgres_sslrootcert <CA_FILE>
+            Trust the certificate authorities in this file instead of the system's for --direct_db
+
+        --postgres_statement_timeout <MILLISECONDS>
+            Give up on the --direct_db scan of a shard after this long (default: 0, no limit)
+
+        --postgres_url <CONNECTION_STRING>
+            libpq connection string or postgresql:// URL of each shard's database for --direct_db, where {{shard}} and
+            {{domain}} are filled in.  Takes the place of the other --postgres_* connection options.
+        --postgres_user <USER>                         User to connect as for --direct_db (default: postgres)
+        --resume <STATE_FILE>                          Resume an interrupted scan from this checkpoint file
+        --shard_max_chunks_per_sec <NUM_CHUNKS>        Most chunks to read per second from each shard
+        --shard_max_rows_per_sec <NUM_ROWS>            Most rows to read per second from each shard
+        --shard_splits <NUM_SPLITS>
+            Split the id range of each shard into this many parts that are scanned concurrently, with -T or --direct_db
+            (default: 1)
+    -s, --shark <STORAGE_ID>...                        Find objects that belong to this shark
+        --slow_query <MILLISECONDS>
+            Back off from a shard while its chunk queries take longer than this
+
+        --sql_timeout <MILLISECONDS>                   Timeout for each query made through moray (default: 10000)
+        --work_unit_size <NUM_IDS>
+            Number of ids in each unit of work shared out between the threads of a multithreaded scan (default: 1000000)
 ", env!("CARGO_PKG_VERSION"));
 
         assert_cli::Assert::main_binary()
@@ -113,6 +185,7 @@ OPTIONS:
 }
 
 mod direct_db {
+    use sharkspotter::report::RunReport;
     use sharkspotter::{
         config, object_id_from_manta_obj, run_multithreaded, util,
         SharkspotterMessage,
@@ -153,8 +226,12 @@ mod direct_db {
             }
         }
 
-        if let Err(e) = handle.join().expect("thread join") {
-            return Err(e);
+        if let Err(e) = handle
+            .join()
+            .expect("thread join")
+            .and_then(RunReport::into_result)
+        {
+            return Err(e.into());
         }
 
         Ok(obj_ids)